@@ -0,0 +1,12 @@
+#![no_main]
+use kadht::krpc::KrpcMessage;
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    // Anything we manage to parse should survive being written back out
+    if let Ok(message) = KrpcMessage::try_from(data) {
+        let bytes = message.to_bytes();
+        assert_eq!(Ok(message), KrpcMessage::try_from(&bytes[..]));
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+use kadht::messages::{Message, NetworkKey};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let key = NetworkKey::new([0; 32]);
+    let _ = Message::parse(data, Some(&key));
+    // Anything we manage to parse should survive being written back out
+    if let Ok(message) = Message::parse(data, None) {
+        let mut buf = vec![0; 0x10000];
+        let count = message.clone().write(&mut buf).unwrap();
+        assert_eq!(Ok(message), Message::parse(&buf[..count], None));
+    }
+});
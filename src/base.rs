@@ -1,6 +1,8 @@
 use crate::rand::distributions::{Distribution, Standard};
 use crate::rand::Rng;
 use crate::sha1::Sha1;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryInto;
 use std::net::SocketAddr;
 
@@ -24,7 +26,7 @@ pub const KEY_SIZE: usize = 128;
 /// e.g. the distance metric we mentioned before, but has no semantic
 /// meaning by itself, since it can be used to mean one of these 2 things
 /// depending on the situation.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct BitKey(pub u128);
 
 impl BitKey {
@@ -67,6 +69,28 @@ impl BitKey {
     }
 }
 
+// Keys are written out as 32 hex digits, which is easier to read than a huge number
+#[cfg(feature = "serde")]
+impl Serialize for BitKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:032x}", self.0))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for BitKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() != 32 {
+            return Err(D::Error::invalid_length(hex.len(), &"32 hex digits"));
+        }
+        u128::from_str_radix(&hex, 16)
+            .map(BitKey)
+            .map_err(|_| D::Error::custom("invalid hex digit in key"))
+    }
+}
+
 impl Distribution<BitKey> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> BitKey {
         BitKey(rng.gen())
@@ -79,6 +103,7 @@ impl Distribution<BitKey> for Standard {
 /// These elements are inserted into our routing table,
 /// allowing us to inform other nodes of their existence,
 /// as well as contact them as part of the protocol.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Node {
     /// A unique identifier for this node.
     ///
@@ -138,7 +163,19 @@ mod tests {
     #[test]
     fn bitkey_hash() {
         let s = "Hello World";
-        let i = u128::from_be_bytes([215,120,229,2,47,171,112,25,119,197,216,64,187,196,134,208]);
+        let i = u128::from_be_bytes([
+            215, 120, 229, 2, 47, 171, 112, 25, 119, 197, 216, 64, 187, 196, 134, 208,
+        ]);
         assert_eq!(BitKey(i), BitKey::from_hash(s));
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn bitkey_serializes_as_hex() {
+        let key = BitKey(0x102030405060708090A0B0C0D0E0F);
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!("\"000102030405060708090a0b0c0d0e0f\"", json);
+        assert_eq!(key, serde_json::from_str(&json).unwrap());
+        assert!(serde_json::from_str::<BitKey>("\"0f\"").is_err());
+    }
 }
@@ -0,0 +1,506 @@
+use crate::erasure::{self, from_hex, to_hex, ErasureError, ErasureOptions};
+use crate::sha1::Sha1;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::mem;
+use std::str;
+
+/// The most bytes of a blob a single chunk holds.
+///
+/// Chunks are stored as they are, and need to fit in a single value.
+pub const CHUNK_BYTES: usize = 255;
+/// The most chunk keys a manifest lists.
+///
+/// Blobs with more chunks than this list the keys of their chunks in other chunks.
+pub const MANIFEST_KEYS: usize = 5;
+// How many levels of chunks listing other chunks we're willing to follow
+const MAX_DEPTH: usize = 8;
+// Chunks listing other chunks hold the raw SHA-1 of each of them
+const DIGEST_BYTES: usize = 20;
+// Lets a manifest be told apart from any other value stored under the name of a blob
+const MANIFEST_TAG: &str = "blob";
+
+/// Represents the reasons we might fail to split a blob into chunks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlobError {
+    /// The blob needs more levels of chunks than a reader is willing to follow.
+    TooLarge,
+}
+
+fn digest(data: &[u8]) -> [u8; DIGEST_BYTES] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.digest().bytes()
+}
+
+/// The key a chunk is stored under, which is the SHA-1 of its content, in hex.
+pub fn chunk_key(chunk: &[u8]) -> String {
+    to_hex(&digest(chunk))
+}
+
+/// Represents the value published under the name of a blob.
+///
+/// The manifest lists the keys of the chunks holding the blob, in order. If the blob
+/// has too many chunks, the chunks it lists hold the keys of the next level of chunks
+/// instead, with `depth` such levels before reaching the data itself.
+///
+/// The data is cut into stripes of `data_shards` chunks, each followed by
+/// `parity_shards` chunks of Reed-Solomon parity, so that any `data_shards`
+/// chunks of a stripe are enough to rebuild it. Plain blobs have stripes of a
+/// single chunk, without parity. Chunks listing other chunks are never coded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    /// How many bytes the blob holds.
+    pub len: usize,
+    /// How many levels of chunks list other chunks.
+    pub depth: usize,
+    /// How many chunks of data each stripe holds.
+    pub data_shards: usize,
+    /// How many chunks of parity follow the data of each stripe.
+    pub parity_shards: usize,
+    /// The keys of the chunks at the first level.
+    pub keys: Vec<String>,
+}
+
+impl Manifest {
+    /// Write the manifest as a value.
+    ///
+    /// Plain blobs are written as `blob:depth:len:key,key,...`, and coded blobs as
+    /// `blob:depth:len:data_shards:parity_shards:key,key,...`.
+    pub fn to_value(&self) -> Vec<u8> {
+        let keys = self.keys.join(",");
+        let value = if self.parity_shards == 0 {
+            format!("{}:{}:{}:{}", MANIFEST_TAG, self.depth, self.len, keys)
+        } else {
+            format!(
+                "{}:{}:{}:{}:{}:{}",
+                MANIFEST_TAG, self.depth, self.len, self.data_shards, self.parity_shards, keys
+            )
+        };
+        value.into_bytes()
+    }
+
+    /// Read a manifest from a value, if that value is one.
+    pub fn from_value(value: &[u8]) -> Option<Self> {
+        let parts: Vec<&str> = str::from_utf8(value).ok()?.split(':').collect();
+        if parts.first() != Some(&MANIFEST_TAG) {
+            return None;
+        }
+        let (data_shards, parity_shards, keys) = match parts.len() {
+            4 => (1, 0, parts[3]),
+            6 => (parts[3].parse().ok()?, parts[4].parse().ok()?, parts[5]),
+            _ => return None,
+        };
+        // Bounding the shard count keeps a bogus manifest from making us allocate too much
+        let coded = ErasureOptions {
+            data_shards,
+            parity_shards,
+            replicas: 0,
+        };
+        if parts.len() == 6 && coded.check().is_err() {
+            return None;
+        }
+        let depth = parts[1].parse().ok().filter(|d| *d <= MAX_DEPTH)?;
+        let len = parts[2].parse().ok()?;
+        let keys: Vec<String> = match keys {
+            "" => Vec::new(),
+            keys => keys.split(',').map(String::from).collect(),
+        };
+        let valid_key = |key: &String| from_hex(key).is_some_and(|d| d.len() == DIGEST_BYTES);
+        if keys.len() > MANIFEST_KEYS || !keys.iter().all(valid_key) {
+            return None;
+        }
+        Some(Manifest {
+            len,
+            depth,
+            data_shards,
+            parity_shards,
+            keys,
+        })
+    }
+}
+
+// The key and content of each chunk a blob was split into
+type Chunks = Vec<(String, Vec<u8>)>;
+
+/// Split a blob into chunks, returning its manifest, along with the key and value of each chunk.
+///
+/// Chunks with the same content are only returned once.
+pub fn split(data: &[u8]) -> Result<(Manifest, Chunks), BlobError> {
+    split_striped(data, 1, 0)
+}
+
+/// Split a blob into chunks like `split`, adding parity chunks to each stripe of its data.
+///
+/// Each chunk of a stripe is stored under its own key, like any other chunk, so the
+/// stripes of a blob are spread over the whole network.
+pub fn split_coded(
+    data: &[u8],
+    options: ErasureOptions,
+) -> Result<(Manifest, Chunks), ErasureError> {
+    options.check()?;
+    split_striped(data, options.data_shards, options.parity_shards)
+        .map_err(|_| ErasureError::TooLarge)
+}
+
+fn split_striped(
+    data: &[u8],
+    data_shards: usize,
+    parity_shards: usize,
+) -> Result<(Manifest, Chunks), BlobError> {
+    let mut chunks = Vec::new();
+    let mut seen = HashSet::new();
+    let mut level = Cow::Borrowed(data);
+    let mut depth = 0;
+    loop {
+        // Only the data itself is coded, since every chunk listing it is needed anyway
+        let (stripe_shards, stripe_parity) = match depth {
+            0 => (data_shards, parity_shards),
+            _ => (1, 0),
+        };
+        let mut digests = Vec::new();
+        for stripe in level.chunks(stripe_shards * CHUNK_BYTES) {
+            // The options were checked already, so coding can't fail
+            for chunk in erasure::encode(stripe, stripe_shards, stripe_parity).unwrap() {
+                let digest = digest(&chunk);
+                let key = to_hex(&digest);
+                if seen.insert(key.clone()) {
+                    chunks.push((key, chunk));
+                }
+                digests.push(digest);
+            }
+        }
+        if digests.len() <= MANIFEST_KEYS {
+            let keys = digests.iter().map(|d| to_hex(d)).collect();
+            let manifest = Manifest {
+                len: data.len(),
+                depth,
+                data_shards,
+                parity_shards,
+                keys,
+            };
+            return Ok((manifest, chunks));
+        }
+        if depth == MAX_DEPTH {
+            return Err(BlobError::TooLarge);
+        }
+        level = Cow::Owned(digests.concat());
+        depth += 1;
+    }
+}
+
+/// Represents a blob being read, one level of chunks at a time.
+///
+/// Chunks may arrive in any order, and are only kept if they match their key.
+/// The data of the blob is handed out in order, as soon as it's available,
+/// each stripe being rebuilt once enough of its chunks have been found.
+#[derive(Debug)]
+pub struct BlobReader {
+    len: usize,
+    depth: usize,
+    data_shards: usize,
+    parity_shards: usize,
+    keys: Vec<String>,
+    chunks: Vec<Option<Vec<u8>>>,
+    // How many stripes, and how many bytes, were already handed out
+    streamed: usize,
+    sent: usize,
+}
+
+impl BlobReader {
+    /// Start reading a blob, returning nothing if the manifest can't describe a valid blob.
+    pub fn new(manifest: Manifest) -> Option<Self> {
+        let reader = BlobReader {
+            len: manifest.len,
+            depth: manifest.depth,
+            data_shards: manifest.data_shards,
+            parity_shards: manifest.parity_shards,
+            chunks: vec![None; manifest.keys.len()],
+            keys: manifest.keys,
+            streamed: 0,
+            sent: 0,
+        };
+        Some(reader).filter(BlobReader::has_valid_level)
+    }
+
+    // The data shards and parity shards of each stripe at the current level
+    fn coding(&self) -> (usize, usize) {
+        match self.depth {
+            0 => (self.data_shards, self.parity_shards),
+            _ => (1, 0),
+        }
+    }
+
+    // How many chunks each stripe at the current level is made of
+    fn stripe_width(&self) -> usize {
+        let (data_shards, parity_shards) = self.coding();
+        data_shards + parity_shards
+    }
+
+    // How many bytes of the blob a stripe holds, which we only know once we reach the data
+    fn stripe_len(&self, stripe: usize) -> Option<usize> {
+        if self.depth > 0 {
+            return None;
+        }
+        let stripe_bytes = self.data_shards * CHUNK_BYTES;
+        Some(stripe_bytes.min(self.len - stripe * stripe_bytes))
+    }
+
+    // Chunks holding data need to be just numerous enough to hold the whole blob
+    fn has_valid_level(&self) -> bool {
+        if self.depth > 0 {
+            return true;
+        }
+        let stripes = self.len.div_ceil(self.data_shards * CHUNK_BYTES);
+        self.keys.len() == stripes * self.stripe_width()
+    }
+
+    // Whether a chunk has the size the chunk at some position should have
+    fn fits(&self, position: usize, chunk: &[u8]) -> bool {
+        match self.stripe_len(position / self.stripe_width()) {
+            Some(len) => chunk.len() == erasure::shard_len(len, self.data_shards),
+            // Every chunk but the last of a level is full
+            None if position + 1 == self.keys.len() => {
+                !chunk.is_empty() && chunk.len() <= CHUNK_BYTES
+            }
+            None => chunk.len() == CHUNK_BYTES,
+        }
+    }
+
+    // Whether enough chunks of a stripe were found to rebuild it
+    fn has_stripe(&self, stripe: usize) -> bool {
+        let width = self.stripe_width();
+        let (data_shards, _) = self.coding();
+        let found = self.chunks[stripe * width..(stripe + 1) * width]
+            .iter()
+            .filter(|chunk| chunk.is_some())
+            .count();
+        found >= data_shards
+    }
+
+    /// How many bytes the blob holds.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether or not the blob is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many levels of chunks listing other chunks are left before the data.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The keys of the chunks still missing at the current level, without duplicates.
+    ///
+    /// Stripes that can already be rebuilt don't need any more of their chunks.
+    pub fn missing(&self) -> Vec<String> {
+        let width = self.stripe_width();
+        let mut seen = HashSet::new();
+        self.keys
+            .iter()
+            .zip(&self.chunks)
+            .enumerate()
+            .filter(|(i, (_, chunk))| chunk.is_none() && !self.has_stripe(i / width))
+            .filter(|(_, (key, _))| seen.insert(*key))
+            .map(|(_, (key, _))| key.clone())
+            .collect()
+    }
+
+    /// Offer a value found under the key of a chunk, returning true if it filled a missing chunk.
+    ///
+    /// The value is ignored unless its content hashes to the key, and it has the size
+    /// of a chunk at its position.
+    pub fn offer(&mut self, key: &str, chunk: &[u8]) -> bool {
+        if chunk_key(chunk) != key {
+            return false;
+        }
+        let mut filled = false;
+        for i in 0..self.keys.len() {
+            if self.chunks[i].is_none() && self.keys[i] == key && self.fits(i, chunk) {
+                self.chunks[i] = Some(chunk.to_vec());
+                filled = true;
+            }
+        }
+        filled
+    }
+
+    /// Whether enough chunks of the current level have been found to rebuild all of it.
+    pub fn is_complete(&self) -> bool {
+        let stripes = self.keys.len() / self.stripe_width();
+        (0..stripes).all(|stripe| self.has_stripe(stripe))
+    }
+
+    /// Take the data that's ready to be handed out, following what was handed out before.
+    pub fn take_ready(&mut self) -> Vec<u8> {
+        let mut ready = Vec::new();
+        let width = self.stripe_width();
+        while self.depth == 0 && self.streamed < self.keys.len() / width {
+            let stripe = self.streamed;
+            if !self.has_stripe(stripe) {
+                break;
+            }
+            // We only need the chunks until the stripe is rebuilt, so we keep their place
+            // without holding on to their content
+            let shards = self.chunks[stripe * width..(stripe + 1) * width]
+                .iter_mut()
+                .map(|chunk| chunk.as_mut().map(mem::take))
+                .collect();
+            let len = self.stripe_len(stripe).unwrap_or(0);
+            match erasure::decode(shards, self.data_shards, len) {
+                Some(data) => ready.extend(data),
+                None => break,
+            }
+            self.streamed += 1;
+        }
+        ready.truncate(self.len - self.sent);
+        self.sent += ready.len();
+        ready
+    }
+
+    /// Move on to the next level of chunks, once the current level is complete.
+    ///
+    /// Returns false if the current level doesn't list the chunks of a valid next level.
+    pub fn descend(&mut self) -> bool {
+        if self.depth == 0 || !self.is_complete() {
+            return false;
+        }
+        let listing: Vec<u8> = self.chunks.drain(..).flatten().flatten().collect();
+        let digests = listing.chunks_exact(DIGEST_BYTES);
+        if !digests.remainder().is_empty() {
+            return false;
+        }
+        self.keys = digests.map(to_hex).collect();
+        self.chunks = vec![None; self.keys.len()];
+        self.depth -= 1;
+        self.has_valid_level()
+    }
+
+    /// Whether the whole blob has been handed out.
+    pub fn is_finished(&self) -> bool {
+        let stripes = self.keys.len() / self.stripe_width();
+        self.depth == 0 && self.streamed == stripes && self.sent == self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Read a blob by offering the chunks of each level in reverse, to mix up their order
+    fn read(manifest: Manifest, chunks: &HashMap<String, Vec<u8>>) -> Option<Vec<u8>> {
+        let mut reader = BlobReader::new(manifest)?;
+        let mut data = Vec::new();
+        loop {
+            for key in reader.missing().iter().rev() {
+                if let Some(chunk) = chunks.get(key) {
+                    reader.offer(key, chunk);
+                }
+                data.extend(reader.take_ready());
+            }
+            if reader.depth() == 0 {
+                return Some(data).filter(|_| reader.is_finished());
+            }
+            if !reader.descend() {
+                return None;
+            }
+        }
+    }
+
+    #[test]
+    fn blobs_are_read_back_through_every_level() {
+        let data: Vec<u8> = (0..20000).map(|x| (x * 7 % 251) as u8).collect();
+        let (manifest, chunks) = split(&data).unwrap();
+        assert_eq!(2, manifest.depth);
+        assert!(chunks.iter().all(|(k, v)| k.len() <= 255 && v.len() <= 255));
+        let value = manifest.to_value();
+        assert!(value.len() <= 255);
+        assert_eq!(Some(manifest.clone()), Manifest::from_value(&value));
+        let chunks: HashMap<String, Vec<u8>> = chunks.into_iter().collect();
+        assert_eq!(Some(data), read(manifest, &chunks));
+        // Repeated content is only stored once, and empty blobs need no chunks at all
+        let (manifest, chunks) = split(&[1; 3 * CHUNK_BYTES]).unwrap();
+        assert_eq!(3, manifest.keys.len());
+        assert_eq!(1, chunks.len());
+        let (manifest, chunks) = split(b"").unwrap();
+        assert!(chunks.is_empty());
+        assert_eq!(Some(Vec::new()), read(manifest, &HashMap::new()));
+    }
+
+    #[test]
+    fn chunks_must_match_their_key() {
+        let data = [3; CHUNK_BYTES + 10];
+        let (manifest, chunks) = split(&data).unwrap();
+        let mut reader = BlobReader::new(manifest.clone()).unwrap();
+        let (first, second) = (&chunks[0], &chunks[1]);
+        // A chunk can't stand in for another, nor be altered
+        assert!(!reader.offer(&first.0, &second.1));
+        let mut altered = first.1.clone();
+        altered[0] ^= 1;
+        assert!(!reader.offer(&first.0, &altered));
+        // Data is only handed out once everything before it has been
+        assert!(reader.offer(&second.0, &second.1));
+        assert!(reader.take_ready().is_empty());
+        assert!(reader.offer(&first.0, &first.1));
+        assert_eq!(data.to_vec(), reader.take_ready());
+        assert!(reader.is_finished());
+        // A manifest claiming a different length doesn't describe a valid blob
+        let wrong_len = Manifest {
+            len: 1000,
+            ..manifest
+        };
+        assert!(BlobReader::new(wrong_len).is_none());
+        assert_eq!(None, Manifest::from_value(b"blob:0:3:nothex"));
+        assert_eq!(None, Manifest::from_value(b"blob:0:3:4:0:"));
+        assert_eq!(None, Manifest::from_value(b"hello"));
+    }
+
+    #[test]
+    fn coded_blobs_survive_losing_parity_chunks() {
+        let options = ErasureOptions::default();
+        let data: Vec<u8> = (0..6000).map(|x| (x * 13 % 256) as u8).collect();
+        let (manifest, chunks) = split_coded(&data, options).unwrap();
+        assert_eq!(
+            Some(manifest.clone()),
+            Manifest::from_value(&manifest.to_value())
+        );
+        assert!(chunks.iter().all(|(_, v)| v.len() <= CHUNK_BYTES));
+        let mut chunks: HashMap<String, Vec<u8>> = chunks.into_iter().collect();
+        assert_eq!(Some(data.clone()), read(manifest.clone(), &chunks));
+        // Losing up to two chunks of every stripe still leaves enough to rebuild it
+        let mut reader = BlobReader::new(manifest.clone()).unwrap();
+        while reader.depth() > 0 {
+            for key in reader.missing() {
+                reader.offer(&key, &chunks[&key]);
+            }
+            assert!(reader.descend());
+        }
+        let width = options.total_shards();
+        let lost: Vec<String> = reader
+            .keys
+            .chunks(width)
+            .flat_map(|stripe| vec![stripe[0].clone(), stripe[width - 1].clone()])
+            .collect();
+        for key in &lost {
+            chunks.remove(key);
+        }
+        assert_eq!(Some(data.clone()), read(manifest.clone(), &chunks));
+        // But not three
+        let third = reader.keys[1].clone();
+        chunks.remove(&third);
+        assert_eq!(None, read(manifest, &chunks));
+        assert_eq!(
+            Err(ErasureError::InvalidShardCount),
+            split_coded(
+                &data,
+                ErasureOptions {
+                    parity_shards: 0,
+                    ..options
+                }
+            )
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use crate::reed_solomon_erasure::galois_8::ReedSolomon;
+use std::fmt::Write;
+use std::str;
+
+/// The most shards Reed-Solomon codes over bytes can produce.
+pub const MAX_SHARDS: usize = 256;
+
+/// Represents how the data of a blob gets split into shards.
+///
+/// The data is cut into stripes, each split into `data_shards` pieces, along
+/// with `parity_shards` extra pieces, and any `data_shards` of the pieces of a
+/// stripe are enough to reconstruct it.
+#[derive(Clone, Copy, Debug)]
+pub struct ErasureOptions {
+    /// How many shards of a stripe are needed to reconstruct it.
+    pub data_shards: usize,
+    /// How many shards of a stripe can be lost, without losing the stripe.
+    pub parity_shards: usize,
+    /// How many of the nodes closest to each shard store a copy of it.
+    pub replicas: usize,
+}
+
+impl ErasureOptions {
+    /// The total number of shards each stripe gets split into.
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// Check that these options describe a code we can use.
+    pub fn check(&self) -> Result<(), ErasureError> {
+        if self.parity_shards == 0 || self.total_shards() > MAX_SHARDS {
+            return Err(ErasureError::InvalidShardCount);
+        }
+        ReedSolomon::new(self.data_shards, self.parity_shards)
+            .map(|_| ())
+            .map_err(|_| ErasureError::InvalidShardCount)
+    }
+}
+
+impl Default for ErasureOptions {
+    fn default() -> Self {
+        ErasureOptions {
+            data_shards: 4,
+            parity_shards: 2,
+            replicas: 3,
+        }
+    }
+}
+
+/// Represents the reasons we might fail to split data into shards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErasureError {
+    /// The data needs more levels of chunks than a reader is willing to follow.
+    TooLarge,
+    /// There needs to be at least one data and parity shard, and at most 256 shards.
+    InvalidShardCount,
+}
+
+pub(crate) fn to_hex(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * data.len());
+    for byte in data {
+        // Writing to a string never fails
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let pairs = hex.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() || !hex.is_ascii() {
+        return None;
+    }
+    pairs
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// How long each shard of a stripe is, with the last data shard padded to match.
+pub fn shard_len(stripe_len: usize, data_shards: usize) -> usize {
+    stripe_len.div_ceil(data_shards.max(1)).max(1)
+}
+
+/// Split a stripe into its data shards, followed by its parity shards.
+///
+/// Without parity shards, the stripe is only split, which is how plain blobs are stored.
+pub fn encode(
+    stripe: &[u8],
+    data_shards: usize,
+    parity_shards: usize,
+) -> Result<Vec<Vec<u8>>, ErasureError> {
+    let len = shard_len(stripe.len(), data_shards);
+    let mut shards = vec![vec![0; len]; data_shards + parity_shards];
+    for (shard, piece) in shards.iter_mut().zip(stripe.chunks(len)) {
+        shard[..piece.len()].copy_from_slice(piece);
+    }
+    if parity_shards > 0 {
+        let codec = ReedSolomon::new(data_shards, parity_shards)
+            .map_err(|_| ErasureError::InvalidShardCount)?;
+        // The shards all have the same, non zero, length
+        codec.encode(&mut shards).unwrap();
+    }
+    Ok(shards)
+}
+
+/// Rebuild a stripe of `len` bytes from the shards found so far, if there are enough of them.
+///
+/// Shards are given in order, with the missing ones left out. The caller checks
+/// each shard before handing it over, since a bad shard goes unnoticed here.
+pub fn decode(mut shards: Vec<Option<Vec<u8>>>, data_shards: usize, len: usize) -> Option<Vec<u8>> {
+    let parity_shards = shards.len().checked_sub(data_shards)?;
+    if parity_shards > 0 {
+        let codec = ReedSolomon::new(data_shards, parity_shards).ok()?;
+        codec.reconstruct_data(&mut shards).ok()?;
+    }
+    let mut data = Vec::with_capacity(len);
+    for shard in shards.into_iter().take(data_shards) {
+        data.extend(shard?);
+    }
+    data.truncate(len);
+    Some(data).filter(|data| data.len() == len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_data_shards_reconstruct_the_stripe() {
+        let stripe: Vec<u8> = (0..=255).chain(0..100).map(|x| x as u8).collect();
+        let shards = encode(&stripe, 4, 2).unwrap();
+        assert_eq!(6, shards.len());
+        assert!(shards.iter().all(|shard| shard.len() == 89));
+        let mut found: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        found[1] = None;
+        found[4] = None;
+        assert_eq!(Some(stripe.clone()), decode(found.clone(), 4, stripe.len()));
+        found[0] = None;
+        assert_eq!(None, decode(found, 4, stripe.len()));
+    }
+
+    #[test]
+    fn stripes_without_parity_are_only_split() {
+        let shards = encode(b"hello world", 1, 0).unwrap();
+        assert_eq!(vec![b"hello world".to_vec()], shards);
+        let found = shards.into_iter().map(Some).collect();
+        assert_eq!(Some(b"hello world".to_vec()), decode(found, 1, 11));
+        assert_eq!(None, decode(vec![None], 1, 11));
+    }
+
+    #[test]
+    fn options_need_parity() {
+        let options = ErasureOptions {
+            data_shards: 2,
+            parity_shards: 0,
+            replicas: 1,
+        };
+        assert_eq!(Err(ErasureError::InvalidShardCount), options.check());
+        let too_many = ErasureOptions {
+            data_shards: 255,
+            parity_shards: 2,
+            replicas: 1,
+        };
+        assert_eq!(Err(ErasureError::InvalidShardCount), too_many.check());
+        assert_eq!(Ok(()), ErasureOptions::default().check());
+    }
+}
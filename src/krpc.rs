@@ -0,0 +1,806 @@
+use crate::base::BitKey;
+use crate::erasure;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, SocketAddr};
+
+/// How many bytes are in a node ID on the mainline DHT.
+pub const NODE_ID_BYTES: usize = 20;
+// Compact peer info is an IP address followed by a port
+const COMPACT_V4_BYTES: usize = 4 + 2;
+const COMPACT_V6_BYTES: usize = 16 + 2;
+// Lists and dictionaries nested deeper than this are rejected, to avoid overflowing the stack
+const MAX_DEPTH: usize = 32;
+
+/// Represents an error when parsing out a KRPC message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KrpcError {
+    /// There were not enough bytes to parse the message
+    InsufficientLength,
+    /// The message wasn't valid bencode
+    InvalidBencode,
+    /// Lists or dictionaries were nested too deeply
+    TooDeep,
+    /// A required field was missing, or had the wrong type
+    MissingField(&'static str),
+    /// The message type, or query method, was unrecognized
+    UnknownMessageType,
+    /// Compact node or peer info had an invalid length
+    InvalidCompactInfo,
+}
+
+/// Represents a bencoded value.
+///
+/// This is the format used by every message on the mainline DHT. Dictionaries
+/// are kept sorted by key, which is the order bencode requires when encoding.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    /// Append the encoding of this value to a buffer.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(i) => {
+                buf.push(b'i');
+                buf.extend_from_slice(i.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            Bencode::Bytes(bytes) => encode_bytes(bytes, buf),
+            Bencode::List(items) => {
+                buf.push(b'l');
+                for item in items {
+                    item.encode(buf);
+                }
+                buf.push(b'e');
+            }
+            Bencode::Dict(entries) => {
+                buf.push(b'd');
+                for (key, value) in entries {
+                    encode_bytes(key, buf);
+                    value.encode(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+
+    /// Parse a single value, which must take up the entire input.
+    pub fn decode(data: &[u8]) -> Result<Self, KrpcError> {
+        let (value, read_count) = decode_prefix(data, 0)?;
+        if read_count != data.len() {
+            return Err(KrpcError::InvalidBencode);
+        }
+        Ok(value)
+    }
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(bytes.len().to_string().as_bytes());
+    buf.push(b':');
+    buf.extend_from_slice(bytes);
+}
+
+// This parses an unsigned number in ASCII, without leading zeros
+fn parse_digits(digits: &[u8]) -> Result<u64, KrpcError> {
+    let valid = !digits.is_empty()
+        && digits.iter().all(u8::is_ascii_digit)
+        && (digits[0] != b'0' || digits.len() == 1);
+    if !valid {
+        return Err(KrpcError::InvalidBencode);
+    }
+    // The digits are all ASCII, so this is valid UTF8
+    std::str::from_utf8(digits)
+        .unwrap()
+        .parse()
+        .map_err(|_| KrpcError::InvalidBencode)
+}
+
+// This returns the value, and the total amount of bytes consumed
+fn decode_prefix(data: &[u8], depth: usize) -> Result<(Bencode, usize), KrpcError> {
+    if depth > MAX_DEPTH {
+        return Err(KrpcError::TooDeep);
+    }
+    let (head, rest) = data.split_first().ok_or(KrpcError::InsufficientLength)?;
+    match head {
+        b'i' => {
+            let end = rest
+                .iter()
+                .position(|&b| b == b'e')
+                .ok_or(KrpcError::InsufficientLength)?;
+            let (negative, digits) = match rest[..end].split_first() {
+                Some((b'-', digits)) => (true, digits),
+                _ => (false, &rest[..end]),
+            };
+            let magnitude = parse_digits(digits)?;
+            let int = if negative {
+                if magnitude == 0 {
+                    return Err(KrpcError::InvalidBencode);
+                }
+                0i64.checked_sub_unsigned(magnitude)
+            } else {
+                i64::try_from(magnitude).ok()
+            };
+            let int = int.ok_or(KrpcError::InvalidBencode)?;
+            Ok((Bencode::Int(int), end + 2))
+        }
+        b'l' => {
+            let mut items = Vec::new();
+            let mut read_count = 1;
+            loop {
+                match data.get(read_count) {
+                    None => return Err(KrpcError::InsufficientLength),
+                    Some(b'e') => return Ok((Bencode::List(items), read_count + 1)),
+                    Some(_) => {
+                        let (item, read) = decode_prefix(&data[read_count..], depth + 1)?;
+                        items.push(item);
+                        read_count += read;
+                    }
+                }
+            }
+        }
+        b'd' => {
+            let mut entries = BTreeMap::new();
+            let mut read_count = 1;
+            loop {
+                match data.get(read_count) {
+                    None => return Err(KrpcError::InsufficientLength),
+                    Some(b'e') => return Ok((Bencode::Dict(entries), read_count + 1)),
+                    Some(_) => {
+                        let key = match decode_prefix(&data[read_count..], depth + 1)? {
+                            (Bencode::Bytes(key), read) => {
+                                read_count += read;
+                                key
+                            }
+                            _ => return Err(KrpcError::InvalidBencode),
+                        };
+                        let (value, read) = decode_prefix(&data[read_count..], depth + 1)?;
+                        entries.insert(key, value);
+                        read_count += read;
+                    }
+                }
+            }
+        }
+        b'0'..=b'9' => {
+            let colon = data
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or(KrpcError::InsufficientLength)?;
+            let len = parse_digits(&data[..colon])?;
+            let start = colon + 1;
+            let end = usize::try_from(len)
+                .ok()
+                .and_then(|len| start.checked_add(len))
+                .ok_or(KrpcError::InsufficientLength)?;
+            let bytes = data.get(start..end).ok_or(KrpcError::InsufficientLength)?;
+            Ok((Bencode::Bytes(bytes.into()), end))
+        }
+        _ => Err(KrpcError::InvalidBencode),
+    }
+}
+
+type Dict = BTreeMap<Vec<u8>, Bencode>;
+
+fn get<'a>(dict: &'a Dict, key: &'static str) -> Option<&'a Bencode> {
+    dict.get(key.as_bytes())
+}
+
+fn get_bytes<'a>(dict: &'a Dict, key: &'static str) -> Result<&'a [u8], KrpcError> {
+    match get(dict, key) {
+        Some(Bencode::Bytes(bytes)) => Ok(bytes),
+        _ => Err(KrpcError::MissingField(key)),
+    }
+}
+
+fn get_int(dict: &Dict, key: &'static str) -> Result<i64, KrpcError> {
+    match get(dict, key) {
+        Some(Bencode::Int(i)) => Ok(*i),
+        _ => Err(KrpcError::MissingField(key)),
+    }
+}
+
+fn get_dict<'a>(dict: &'a Dict, key: &'static str) -> Result<&'a Dict, KrpcError> {
+    match get(dict, key) {
+        Some(Bencode::Dict(inner)) => Ok(inner),
+        _ => Err(KrpcError::MissingField(key)),
+    }
+}
+
+fn get_id(dict: &Dict, key: &'static str) -> Result<NodeId, KrpcError> {
+    let bytes = get_bytes(dict, key)?;
+    let id = bytes.try_into().map_err(|_| KrpcError::MissingField(key))?;
+    Ok(NodeId(id))
+}
+
+fn insert(dict: &mut Dict, key: &str, value: Bencode) {
+    dict.insert(key.as_bytes().into(), value);
+}
+
+/// Represents the 160 bit identifier of a node or torrent on the mainline DHT.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct NodeId(pub [u8; NODE_ID_BYTES]);
+
+impl NodeId {
+    /// Parse an identifier from 40 hex digits, like info hashes are usually written.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let bytes = erasure::from_hex(hex)?;
+        bytes.try_into().ok().map(NodeId)
+    }
+
+    /// Convert this identifier into a key for our own routing table.
+    ///
+    /// This keeps the most significant 128 bits, so nodes close to each other
+    /// on the mainline DHT stay close to each other in our routing table.
+    pub fn to_bitkey(self) -> BitKey {
+        // The unwrapping is fine, since 16 bytes always fit
+        BitKey(u128::from_be_bytes(self.0[..16].try_into().unwrap()))
+    }
+}
+
+/// Represents a node on the mainline DHT, along with its address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MainlineNode {
+    pub id: NodeId,
+    pub udp_addr: SocketAddr,
+}
+
+fn write_compact_addr(addr: SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(v4) => buf.extend_from_slice(&v4.octets()),
+        IpAddr::V6(v6) => buf.extend_from_slice(&v6.octets()),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+// This reads an address in compact peer info format, guessing the IP type from the length
+fn read_compact_addr(data: &[u8]) -> Result<SocketAddr, KrpcError> {
+    let (ip, port) = match data.len() {
+        COMPACT_V4_BYTES => {
+            let ip4_bytes: [u8; 4] = data[..4].try_into().unwrap();
+            (IpAddr::V4(ip4_bytes.into()), &data[4..])
+        }
+        COMPACT_V6_BYTES => {
+            let ip16_bytes: [u8; 16] = data[..16].try_into().unwrap();
+            (IpAddr::V6(ip16_bytes.into()), &data[16..])
+        }
+        _ => return Err(KrpcError::InvalidCompactInfo),
+    };
+    let port = u16::from_be_bytes(port.try_into().unwrap());
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Compact node info is a concatenation of entries, each an ID followed by compact peer info
+fn read_compact_nodes(data: &[u8], addr_len: usize) -> Result<Vec<MainlineNode>, KrpcError> {
+    let entry_len = NODE_ID_BYTES + addr_len;
+    let entries = data.chunks_exact(entry_len);
+    if !entries.remainder().is_empty() {
+        return Err(KrpcError::InvalidCompactInfo);
+    }
+    entries
+        .map(|entry| {
+            let (id, addr) = entry.split_at(NODE_ID_BYTES);
+            Ok(MainlineNode {
+                id: NodeId(id.try_into().unwrap()),
+                udp_addr: read_compact_addr(addr)?,
+            })
+        })
+        .collect()
+}
+
+/// Represents a query sent to a node on the mainline DHT.
+///
+/// These line up with the RPC calls we already use: `Ping` with `Ping`,
+/// `FindNode` with `FindNode`, `GetPeers` with `FindValue`, and `AnnouncePeer`
+/// with `Store`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    /// Check whether or not a node is still alive
+    Ping,
+    /// Try and find the closest nodes to a given ID
+    FindNode(NodeId),
+    /// Ask for the peers of a torrent, or the closest nodes to it
+    GetPeers(NodeId),
+    /// Announce that we're a peer for a torrent, echoing the token the node gave us
+    ///
+    /// If the port is implied, the node should use the source port of this message instead.
+    AnnouncePeer {
+        info_hash: NodeId,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+/// Represents the response to any query on the mainline DHT.
+///
+/// Responses don't say which query they're answering, so every field
+/// besides the ID of the sender is optional.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Response {
+    /// The closest nodes the responder knows of, over both IPV4 and IPV6
+    pub nodes: Vec<MainlineNode>,
+    /// The peers for a torrent, in response to `GetPeers`
+    pub values: Vec<SocketAddr>,
+    /// A token for announcing ourselves to the responder
+    pub token: Option<Vec<u8>>,
+}
+
+/// Represents the data differing between KRPC messages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KrpcBody {
+    /// A query, along with the ID of the sender
+    Query(NodeId, Query),
+    /// A response, along with the ID of the sender
+    Response(NodeId, Response),
+    /// An error, with a code and a description
+    Error(i64, String),
+}
+
+/// Represents a message on the mainline DHT, as described in BEP-5.
+///
+/// Every message is a bencoded dictionary, identified by a transaction ID
+/// chosen by the sender of the query, and echoed in the response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KrpcMessage {
+    /// An opaque transaction ID, usually only a couple bytes long
+    pub transaction_id: Vec<u8>,
+    /// The client version of the sender, if it included one
+    pub version: Option<Vec<u8>>,
+    /// The contents of this message
+    pub body: KrpcBody,
+}
+
+impl KrpcMessage {
+    /// Serialize this message into bencode.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = Dict::new();
+        insert(&mut dict, "t", Bencode::Bytes(self.transaction_id.clone()));
+        if let Some(version) = &self.version {
+            insert(&mut dict, "v", Bencode::Bytes(version.clone()));
+        }
+        match &self.body {
+            KrpcBody::Query(id, query) => {
+                let mut args = Dict::new();
+                insert(&mut args, "id", Bencode::Bytes(id.0.to_vec()));
+                let method = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode(target) => {
+                        insert(&mut args, "target", Bencode::Bytes(target.0.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers(info_hash) => {
+                        insert(&mut args, "info_hash", Bencode::Bytes(info_hash.0.to_vec()));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        insert(&mut args, "info_hash", Bencode::Bytes(info_hash.0.to_vec()));
+                        insert(&mut args, "port", Bencode::Int(i64::from(*port)));
+                        insert(&mut args, "token", Bencode::Bytes(token.clone()));
+                        if *implied_port {
+                            insert(&mut args, "implied_port", Bencode::Int(1));
+                        }
+                        "announce_peer"
+                    }
+                };
+                insert(&mut dict, "y", Bencode::Bytes(b"q".to_vec()));
+                insert(&mut dict, "q", Bencode::Bytes(method.as_bytes().into()));
+                insert(&mut dict, "a", Bencode::Dict(args));
+            }
+            KrpcBody::Response(id, response) => {
+                let mut values = Dict::new();
+                insert(&mut values, "id", Bencode::Bytes(id.0.to_vec()));
+                let mut nodes = Vec::new();
+                let mut nodes6 = Vec::new();
+                for node in &response.nodes {
+                    let buf = if node.udp_addr.is_ipv4() {
+                        &mut nodes
+                    } else {
+                        &mut nodes6
+                    };
+                    buf.extend_from_slice(&node.id.0);
+                    write_compact_addr(node.udp_addr, buf);
+                }
+                if !nodes.is_empty() {
+                    insert(&mut values, "nodes", Bencode::Bytes(nodes));
+                }
+                if !nodes6.is_empty() {
+                    insert(&mut values, "nodes6", Bencode::Bytes(nodes6));
+                }
+                if !response.values.is_empty() {
+                    let peers = response
+                        .values
+                        .iter()
+                        .map(|&addr| {
+                            let mut buf = Vec::with_capacity(COMPACT_V6_BYTES);
+                            write_compact_addr(addr, &mut buf);
+                            Bencode::Bytes(buf)
+                        })
+                        .collect();
+                    insert(&mut values, "values", Bencode::List(peers));
+                }
+                if let Some(token) = &response.token {
+                    insert(&mut values, "token", Bencode::Bytes(token.clone()));
+                }
+                insert(&mut dict, "y", Bencode::Bytes(b"r".to_vec()));
+                insert(&mut dict, "r", Bencode::Dict(values));
+            }
+            KrpcBody::Error(code, message) => {
+                let error = vec![
+                    Bencode::Int(*code),
+                    Bencode::Bytes(message.as_bytes().into()),
+                ];
+                insert(&mut dict, "y", Bencode::Bytes(b"e".to_vec()));
+                insert(&mut dict, "e", Bencode::List(error));
+            }
+        }
+        let mut buf = Vec::new();
+        Bencode::Dict(dict).encode(&mut buf);
+        buf
+    }
+}
+
+fn try_query_from(dict: &Dict) -> Result<KrpcBody, KrpcError> {
+    let args = get_dict(dict, "a")?;
+    let id = get_id(args, "id")?;
+    let query = match get_bytes(dict, "q")? {
+        b"ping" => Query::Ping,
+        b"find_node" => Query::FindNode(get_id(args, "target")?),
+        b"get_peers" => Query::GetPeers(get_id(args, "info_hash")?),
+        b"announce_peer" => {
+            let port = get_int(args, "port")?;
+            Query::AnnouncePeer {
+                info_hash: get_id(args, "info_hash")?,
+                port: u16::try_from(port).map_err(|_| KrpcError::MissingField("port"))?,
+                implied_port: get_int(args, "implied_port").is_ok_and(|i| i != 0),
+                token: get_bytes(args, "token")?.into(),
+            }
+        }
+        _ => return Err(KrpcError::UnknownMessageType),
+    };
+    Ok(KrpcBody::Query(id, query))
+}
+
+fn try_response_from(dict: &Dict) -> Result<KrpcBody, KrpcError> {
+    let values = get_dict(dict, "r")?;
+    let id = get_id(values, "id")?;
+    let mut nodes = Vec::new();
+    if let Ok(compact) = get_bytes(values, "nodes") {
+        nodes.extend(read_compact_nodes(compact, COMPACT_V4_BYTES)?);
+    }
+    if let Ok(compact) = get_bytes(values, "nodes6") {
+        nodes.extend(read_compact_nodes(compact, COMPACT_V6_BYTES)?);
+    }
+    let peers = match get(values, "values") {
+        None => Vec::new(),
+        Some(Bencode::List(peers)) => peers
+            .iter()
+            .map(|peer| match peer {
+                Bencode::Bytes(compact) => read_compact_addr(compact),
+                _ => Err(KrpcError::InvalidCompactInfo),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err(KrpcError::MissingField("values")),
+    };
+    let token = get_bytes(values, "token").ok().map(Vec::from);
+    let response = Response {
+        nodes,
+        values: peers,
+        token,
+    };
+    Ok(KrpcBody::Response(id, response))
+}
+
+fn try_error_from(dict: &Dict) -> Result<KrpcBody, KrpcError> {
+    match get(dict, "e") {
+        Some(Bencode::List(error)) => match error.as_slice() {
+            [Bencode::Int(code), Bencode::Bytes(message)] => {
+                let message = String::from_utf8_lossy(message).into_owned();
+                Ok(KrpcBody::Error(*code, message))
+            }
+            _ => Err(KrpcError::MissingField("e")),
+        },
+        _ => Err(KrpcError::MissingField("e")),
+    }
+}
+
+impl TryFrom<&[u8]> for KrpcMessage {
+    type Error = KrpcError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let dict = match Bencode::decode(data)? {
+            Bencode::Dict(dict) => dict,
+            _ => return Err(KrpcError::InvalidBencode),
+        };
+        let transaction_id = get_bytes(&dict, "t")?.into();
+        let version = get_bytes(&dict, "v").ok().map(Vec::from);
+        let body = match get_bytes(&dict, "y")? {
+            b"q" => try_query_from(&dict)?,
+            b"r" => try_response_from(&dict)?,
+            b"e" => try_error_from(&dict)?,
+            _ => return Err(KrpcError::UnknownMessageType),
+        };
+        Ok(KrpcMessage {
+            transaction_id,
+            version,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These packets are the examples given in BEP-5
+    const PING_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    const PING_RESPONSE: &[u8] = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+    const FIND_NODE_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
+    const GET_PEERS_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+    const GET_PEERS_VALUES: &[u8] = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+    const ANNOUNCE_PEER_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+    const ERROR: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+    // These packets were captured over loopback from the mainline crate, version 8.0.1, which
+    // sends "RS" followed by its version in every message. One node bootstrapped off another,
+    // looked itself up, then found, announced and found the peers of a random info hash.
+    // The error answers an announce_peer we sent with a made up token.
+    const MAINLINE_PING_QUERY: &[u8] = b"\
+        d1:ad2:id20:\x09\xae8\xe9>)\x1c@\xe30\x8f\x1eB\xcd\x96U\x85\x94\xc1\xf2e1:q4:ping2:roi\
+        0e1:t4:\x00\x00\x00\x011:v4:RS\x00\x051:y1:qe";
+    const MAINLINE_PING_RESPONSE: &[u8] = b"\
+        d2:ip6:\x7f\x00\x00\x01\xb7\x221:rd2:id20:\xe4\xeeW\xb4Z\x1eWw\x92\x1a\x1e\x9d*\xfb_a\
+        \x06\x20\xee\x89e2:roi0e1:t4:\x00\x00\x00\x011:v4:RS\x00\x051:y1:re";
+    const MAINLINE_FIND_NODE_QUERY: &[u8] = b"\
+        d1:ad2:id20:\x0e\xc7\xe6[\xdd\xf5\x0cI\xbe\xc0\xb9h\xbb\x85*\xc9va^\x8a6:target20:\x0e\
+        \xc7\xe6[\xdd\xf5\x0cI\xbe\xc0\xb9h\xbb\x85*\xc9va^\x8ae1:q9:find_node2:roi0e1:t4:\x00\
+        \x00\x00\x001:v4:RS\x00\x051:y1:qe";
+    const MAINLINE_FIND_NODE_RESPONSE: &[u8] = b"\
+        d2:ip6:\x7f\x00\x00\x01\xb7\x221:rd2:id20:\xe4\xeeW\xb4Z\x1eWw\x92\x1a\x1e\x9d*\xfb_a\
+        \x06\x20\xee\x895:nodes26:\x0e\xc7\xe6[\xdd\xf5\x0cI\xbe\xc0\xb9h\xbb\x85*\xc9va^\x8a\
+        \x7f\x00\x00\x01\xb7\x22e2:roi0e1:t4:\x00\x00\x00\x001:v4:RS\x00\x051:y1:re";
+    const MAINLINE_GET_PEERS_QUERY: &[u8] = b"\
+        d1:ad2:id20:\x0e\xc7\xe6[\xdd\xf5\x0cI\xbe\xc0\xb9h\xbb\x85*\xc9va^\x8a9:info_hash20:\
+        \xecN\xcb\x08H\x93\xdf\x84-\xdc\xd1\x96\xf7_\x1a\xa0\x9d}\x88ae1:q9:get_peers2:roi0e1:\
+        t4:\x00\x00\x00\x051:v4:RS\x00\x051:y1:qe";
+    const MAINLINE_GET_PEERS_NODES: &[u8] = b"\
+        d2:ip6:\x7f\x00\x00\x01\xb7\x221:rd2:id20:\xe4\xeeW\xb4Z\x1eWw\x92\x1a\x1e\x9d*\xfb_a\
+        \x06\x20\xee\x895:nodes52:\x974\x05\x9bIN.l\xb0\xe3\x1f\xb0\xdd\xab\x96\x8a\x12\x93\
+        \x15d\x7f\x00\x00\x01\xb7\x22\x0e\xc7\xe6[\xdd\xf5\x0cI\xbe\xc0\xb9h\xbb\x85*\xc9va^\
+        \x8a\x7f\x00\x00\x01\xb7\x225:token4:6jS\xcde2:roi0e1:t4:\x00\x00\x00\x051:v4:RS\x00\
+        \x051:y1:re";
+    const MAINLINE_ANNOUNCE_PEER_QUERY: &[u8] = b"\
+        d1:ad2:id20:\xc6^%N\x0a\xe7MA\x92\xf5\xf1\x0e\xc3\xcd\xac(x\xfd\x8f\x0512:implied_port\
+        i0e9:info_hash20:\xecN\xcb\x08H\x93\xdf\x84-\xdc\xd1\x96\xf7_\x1a\xa0\x9d}\x88a4:porti\
+        6881e5:token4:6jS\xcde1:q13:announce_peer2:roi0e1:t4:\x00\x00\x00\x071:v4:RS\x00\x051:\
+        y1:qe";
+    const MAINLINE_ANNOUNCE_PEER_RESPONSE: &[u8] = b"\
+        d2:ip6:\x7f\x00\x00\x01\xb7\x221:rd2:id20:\xe4\xeeW\xb4Z\x1eWw\x92\x1a\x1e\x9d*\xfb_a\
+        \x06\x20\xee\x89e2:roi0e1:t4:\x00\x00\x00\x071:v4:RS\x00\x051:y1:re";
+    const MAINLINE_GET_PEERS_VALUES: &[u8] = b"\
+        d2:ip6:\x7f\x00\x00\x01\xb7\x221:rd2:id20:\xe4\xeeW\xb4Z\x1eWw\x92\x1a\x1e\x9d*\xfb_a\
+        \x06\x20\xee\x895:nodes52:\x974\x05\x9bIN.l\xb0\xe3\x1f\xb0\xdd\xab\x96\x8a\x12\x93\
+        \x15d\x7f\x00\x00\x01\xb7\x22\x0e\xc7\xe6[\xdd\xf5\x0cI\xbe\xc0\xb9h\xbb\x85*\xc9va^\
+        \x8a\x7f\x00\x00\x01\xb7\x225:token4:6jS\xcd6:valuesl6:\x7f\x00\x00\x01\x1a\xe1ee2:roi\
+        0e1:t4:\x00\x00\x00\x081:v4:RS\x00\x051:y1:re";
+    const MAINLINE_BAD_TOKEN: &[u8] = b"\
+        d1:eli203e9:Bad\x20tokene2:ip6:\x7f\x00\x00\x01\xd0g2:roi0e1:t4:\x00\x00\x00*1:v4:RS\
+        \x00\x051:y1:ee";
+
+    const ID_A: NodeId = NodeId(*b"abcdefghij0123456789");
+    const ID_M: NodeId = NodeId(*b"mnopqrstuvwxyz123456");
+
+    fn message(body: KrpcBody) -> KrpcMessage {
+        KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            body,
+        }
+    }
+
+    fn assert_round_trip(bytes: &[u8], expected: KrpcMessage) {
+        assert_eq!(Ok(expected.clone()), KrpcMessage::try_from(bytes));
+        assert_eq!(bytes, &expected.to_bytes()[..]);
+    }
+
+    #[test]
+    fn ping_round_trip() {
+        assert_round_trip(PING_QUERY, message(KrpcBody::Query(ID_A, Query::Ping)));
+        let response = KrpcBody::Response(ID_M, Response::default());
+        assert_round_trip(PING_RESPONSE, message(response));
+    }
+
+    #[test]
+    fn find_node_round_trip() {
+        let query = KrpcBody::Query(ID_A, Query::FindNode(ID_M));
+        assert_round_trip(FIND_NODE_QUERY, message(query));
+    }
+
+    #[test]
+    fn get_peers_round_trip() {
+        let query = KrpcBody::Query(ID_A, Query::GetPeers(ID_M));
+        assert_round_trip(GET_PEERS_QUERY, message(query));
+        let response = Response {
+            nodes: Vec::new(),
+            values: vec![
+                "97.120.106.101:11893".parse().unwrap(),
+                "105.100.104.116:28269".parse().unwrap(),
+            ],
+            token: Some(b"aoeusnth".to_vec()),
+        };
+        assert_round_trip(
+            GET_PEERS_VALUES,
+            message(KrpcBody::Response(ID_A, response)),
+        );
+    }
+
+    #[test]
+    fn announce_peer_round_trip() {
+        let query = Query::AnnouncePeer {
+            info_hash: ID_M,
+            port: 6881,
+            implied_port: true,
+            token: b"aoeusnth".to_vec(),
+        };
+        assert_round_trip(ANNOUNCE_PEER_QUERY, message(KrpcBody::Query(ID_A, query)));
+    }
+
+    #[test]
+    fn error_round_trip() {
+        let error = KrpcBody::Error(201, String::from("A Generic Error Ocurred"));
+        assert_round_trip(ERROR, message(error));
+    }
+
+    fn id(hex: &str) -> NodeId {
+        NodeId::from_hex(hex).unwrap()
+    }
+
+    fn response_from(bytes: &[u8], transaction_id: &[u8]) -> Response {
+        let message = KrpcMessage::try_from(bytes).unwrap();
+        assert_eq!(transaction_id, &message.transaction_id[..]);
+        assert_eq!(Some(b"RS\x00\x05".to_vec()), message.version);
+        match message.body {
+            KrpcBody::Response(responder, response) => {
+                assert_eq!(id("e4ee57b45a1e5777921a1e9d2afb5f610620ee89"), responder);
+                response
+            }
+            other => panic!("expected a response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mainline_queries_parse() {
+        let node = id("0ec7e65bddf50c49bec0b968bb852ac976615e8a");
+        let info_hash = id("ec4ecb084893df842ddcd196f75f1aa09d7d8861");
+        let queries = [
+            (
+                MAINLINE_PING_QUERY,
+                id("09ae38e93e291c40e3308f1e42cd96558594c1f2"),
+                Query::Ping,
+            ),
+            (MAINLINE_FIND_NODE_QUERY, node, Query::FindNode(node)),
+            (MAINLINE_GET_PEERS_QUERY, node, Query::GetPeers(info_hash)),
+            (
+                MAINLINE_ANNOUNCE_PEER_QUERY,
+                id("c65e254e0ae74d4192f5f10ec3cdac2878fd8f05"),
+                Query::AnnouncePeer {
+                    info_hash,
+                    port: 6881,
+                    implied_port: false,
+                    token: b"6jS\xcd".to_vec(),
+                },
+            ),
+        ];
+        for (bytes, sender, query) in queries.iter().cloned() {
+            let message = KrpcMessage::try_from(bytes).unwrap();
+            assert_eq!(Some(b"RS\x00\x05".to_vec()), message.version);
+            assert_eq!(KrpcBody::Query(sender, query), message.body);
+        }
+    }
+
+    #[test]
+    fn mainline_responses_parse() {
+        let ping = response_from(MAINLINE_PING_RESPONSE, b"\x00\x00\x00\x01");
+        assert_eq!(Response::default(), ping);
+        let announce = response_from(MAINLINE_ANNOUNCE_PEER_RESPONSE, b"\x00\x00\x00\x07");
+        assert_eq!(Response::default(), announce);
+
+        let querier = MainlineNode {
+            id: id("0ec7e65bddf50c49bec0b968bb852ac976615e8a"),
+            udp_addr: "127.0.0.1:46882".parse().unwrap(),
+        };
+        let find_node = response_from(MAINLINE_FIND_NODE_RESPONSE, b"\x00\x00\x00\x00");
+        assert_eq!(vec![querier], find_node.nodes);
+        assert_eq!(None, find_node.token);
+
+        let other = MainlineNode {
+            id: id("9734059b494e2e6cb0e31fb0ddab968a12931564"),
+            udp_addr: "127.0.0.1:46882".parse().unwrap(),
+        };
+        let no_peers = response_from(MAINLINE_GET_PEERS_NODES, b"\x00\x00\x00\x05");
+        assert_eq!(vec![other, querier], no_peers.nodes);
+        assert!(no_peers.values.is_empty());
+        assert_eq!(Some(b"6jS\xcd".to_vec()), no_peers.token);
+
+        let peers = response_from(MAINLINE_GET_PEERS_VALUES, b"\x00\x00\x00\x08");
+        assert_eq!(no_peers.nodes, peers.nodes);
+        let announced: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        assert_eq!(vec![announced], peers.values);
+        assert_eq!(no_peers.token, peers.token);
+    }
+
+    #[test]
+    fn mainline_errors_parse() {
+        let message = KrpcMessage::try_from(MAINLINE_BAD_TOKEN).unwrap();
+        assert_eq!(b"\x00\x00\x00*".to_vec(), message.transaction_id);
+        assert_eq!(Some(b"RS\x00\x05".to_vec()), message.version);
+        let error = KrpcBody::Error(203, String::from("Bad token"));
+        assert_eq!(error, message.body);
+    }
+
+    #[test]
+    fn compact_nodes_round_trip() {
+        let response = Response {
+            nodes: vec![
+                MainlineNode {
+                    id: ID_M,
+                    udp_addr: "127.0.0.1:6881".parse().unwrap(),
+                },
+                MainlineNode {
+                    id: ID_A,
+                    udp_addr: "[::1]:6881".parse().unwrap(),
+                },
+            ],
+            values: Vec::new(),
+            token: None,
+        };
+        let expected = message(KrpcBody::Response(ID_A, response));
+        let bytes = expected.to_bytes();
+        let nodes = [&ID_M.0[..], &[127, 0, 0, 1, 0x1A, 0xE1]].concat();
+        assert!(bytes.windows(nodes.len()).any(|w| w == &nodes[..]));
+        assert_eq!(Ok(expected), KrpcMessage::try_from(&bytes[..]));
+    }
+
+    #[test]
+    fn invalid_packets_are_rejected() {
+        // Compact node info has to hold a whole number of 26 byte entries
+        let short_nodes = [
+            &b"d1:rd2:id20:abcdefghij01234567895:nodes25:"[..],
+            &[0; 25],
+            b"e1:t2:aa1:y1:re",
+        ]
+        .concat();
+        assert_eq!(
+            Err(KrpcError::InvalidCompactInfo),
+            KrpcMessage::try_from(&short_nodes[..])
+        );
+        let truncated = &PING_QUERY[..PING_QUERY.len() - 1];
+        assert_eq!(
+            Err(KrpcError::InsufficientLength),
+            KrpcMessage::try_from(truncated)
+        );
+        let deep = [&[b'l'; 64][..], &[b'e'; 64][..]].concat();
+        assert_eq!(Err(KrpcError::TooDeep), Bencode::decode(&deep));
+        assert_eq!(Err(KrpcError::InvalidBencode), Bencode::decode(b"i-0e"));
+        assert_eq!(Err(KrpcError::InvalidBencode), Bencode::decode(b"i03e"));
+        assert_eq!(
+            Ok(Bencode::Int(i64::MIN)),
+            Bencode::decode(b"i-9223372036854775808e")
+        );
+    }
+
+    #[test]
+    fn node_ids_keep_their_high_bits() {
+        let mut id = [0; NODE_ID_BYTES];
+        id[0] = 0x80;
+        id[19] = 0xFF;
+        assert_eq!(BitKey(1 << 127), NodeId(id).to_bitkey());
+    }
+}
@@ -0,0 +1,24 @@
+extern crate argon2;
+extern crate chacha20poly1305;
+extern crate ed25519_dalek;
+extern crate hmac;
+extern crate rand;
+extern crate reed_solomon_erasure;
+extern crate sha1;
+extern crate sha2;
+pub mod base;
+pub mod blob;
+pub mod erasure;
+pub mod krpc;
+pub mod limits;
+pub mod mainline;
+pub mod messages;
+pub mod providers;
+pub mod quorum;
+pub mod record;
+pub mod routing;
+pub mod server;
+pub mod store;
+pub mod sync;
+pub mod token;
+pub mod transport;
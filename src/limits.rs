@@ -0,0 +1,325 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How often a limiter sweeps its buckets for sources that have gone quiet.
+const PRUNE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Represents a token bucket used to limit the rate of some action.
+///
+/// The bucket starts out full, and every action takes a token out of
+/// the bucket. Tokens flow back into the bucket at a steady rate, until the bucket
+/// is full again. This allows short bursts of activity, while still limiting
+/// the rate of activity over longer periods of time.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new, full, bucket.
+    pub fn new(capacity: f64, per_second: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity,
+            per_second,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + secs * self.per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Try and take a token from the bucket, returning whether or not we could.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// Represents the parameters of a token bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    /// How many actions can happen in a single burst
+    pub burst: u32,
+    /// How many actions per second can happen over a long period of time
+    pub per_second: u32,
+}
+
+/// Represents a collection of token buckets, one for each key.
+///
+/// This is used to limit the rate of traffic from each source,
+/// whether that source be an IP address, or a node ID.
+pub struct RateLimiter<K> {
+    rate: Rate,
+    buckets: HashMap<K, TokenBucket>,
+    pruned_at: Instant,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Create a new limiter, where every key gets the same rate.
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+            pruned_at: now,
+        }
+    }
+
+    /// Check whether or not the source with this key can do something right now.
+    pub fn allow(&mut self, key: K, now: Instant) -> bool {
+        let rate = self.rate;
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| {
+                TokenBucket::new(f64::from(rate.burst), f64::from(rate.per_second), now)
+            })
+            .try_take(now)
+    }
+
+    /// Forget about all sources that haven't been active recently, if we haven't done so recently.
+    ///
+    /// A full bucket behaves the same as a fresh one, so we can drop it without
+    /// changing anything, and avoid keeping around a bucket for every source we've
+    /// ever seen.
+    pub fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned_at) < PRUNE_PERIOD {
+            return;
+        }
+        self.pruned_at = now;
+        self.buckets.retain(|_, bucket| !bucket.is_full(now));
+    }
+}
+
+/// How long it takes for a single strike against an address to be forgiven.
+pub const FORGIVE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// Represents how much we've distrusted each address lately.
+///
+/// Strikes are things like responses that don't match the node we queried.
+/// Anyone can spoof an address to earn it strikes, so strikes are forgiven one
+/// at a time as time passes, and a genuine response from the address clears them.
+pub struct Suspicion {
+    threshold: u32,
+    max_tracked: usize,
+    strikes: HashMap<IpAddr, (u32, Instant)>,
+    pruned_at: Instant,
+}
+
+impl Suspicion {
+    /// Create a tracker distrusting addresses with `threshold` strikes, tracking at most `max_tracked`.
+    pub fn new(threshold: u32, max_tracked: usize, now: Instant) -> Self {
+        Suspicion {
+            threshold,
+            max_tracked,
+            strikes: HashMap::new(),
+            pruned_at: now,
+        }
+    }
+
+    // The strikes left against an address, once the ones old enough are forgiven
+    fn remaining(strikes: u32, since: Instant, now: Instant) -> u32 {
+        let forgiven = now.duration_since(since).as_secs() / FORGIVE_PERIOD.as_secs();
+        strikes.saturating_sub(u32::try_from(forgiven).unwrap_or(u32::MAX))
+    }
+
+    /// Record a strike against an address.
+    ///
+    /// Once we track as many addresses as we can, new addresses are ignored.
+    pub fn strike(&mut self, ip: IpAddr, now: Instant) {
+        if self.strikes.len() >= self.max_tracked && !self.strikes.contains_key(&ip) {
+            return;
+        }
+        let entry = self.strikes.entry(ip).or_insert((0, now));
+        let remaining = Self::remaining(entry.0, entry.1, now);
+        *entry = (remaining.saturating_add(1), now);
+    }
+
+    /// Forget every strike against an address, after it proved itself genuine.
+    pub fn clear(&mut self, ip: IpAddr) {
+        self.strikes.remove(&ip);
+    }
+
+    /// Check whether an address has fewer strikes against it than the threshold.
+    pub fn is_trusted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.strikes
+            .get(&ip)
+            .is_none_or(|&(strikes, since)| Self::remaining(strikes, since, now) < self.threshold)
+    }
+
+    /// Forget about the addresses whose strikes were all forgiven, if we haven't done so recently.
+    pub fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned_at) < PRUNE_PERIOD {
+            return;
+        }
+        self.pruned_at = now;
+        self.strikes
+            .retain(|_, &mut (strikes, since)| Self::remaining(strikes, since, now) > 0);
+    }
+}
+
+/// Represents the limits a server places on the traffic it handles.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// How many messages we accept from each IP address
+    pub per_ip: Rate,
+    /// How many messages we accept from each node ID
+    pub per_node: Rate,
+    /// How many bytes of keys and values we're willing to store for other nodes
+    pub max_storage_bytes: usize,
+    /// How many bytes a single value we store for another node can hold
+    pub max_value_bytes: usize,
+    /// How many pings we can be waiting for at any given time
+    pub max_pings_in_flight: usize,
+    /// How many copies we can be waiting on replicas to acknowledge, when repairing them
+    pub max_repairs_in_flight: usize,
+    /// How many keys a query can be looking up at once, when it looks up several keys
+    pub max_paths_in_flight: usize,
+    /// How many provider announcements we're willing to hold for other nodes
+    pub max_providers: usize,
+    /// How many providers a single key can hold
+    pub max_providers_per_key: usize,
+    /// How many values, from different publishers, a single key can hold
+    pub max_values_per_key: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            per_ip: Rate {
+                burst: 200,
+                per_second: 50,
+            },
+            per_node: Rate {
+                burst: 100,
+                per_second: 25,
+            },
+            max_storage_bytes: 64 * 1024 * 1024,
+            max_value_bytes: 255,
+            max_pings_in_flight: 64,
+            max_repairs_in_flight: 64,
+            max_paths_in_flight: 16,
+            max_providers: 64 * 1024,
+            max_providers_per_key: 20,
+            max_values_per_key: 16,
+        }
+    }
+}
+
+/// Represents counters for all the traffic a server decided to drop.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DropCounters {
+    /// Messages dropped because their IP address sent too many messages
+    pub ip_rate_limited: u64,
+    /// Messages dropped because their node ID sent too many messages
+    pub node_rate_limited: u64,
+    /// Store requests and provider announcements dropped because we're out of room
+    pub storage_full: u64,
+    /// Pings we didn't send because too many were already in flight
+    pub pings_skipped: u64,
+    /// Copies we didn't send to stale replicas because too many were already in flight
+    pub repairs_skipped: u64,
+    /// Responses that didn't come from the node we sent the request to
+    pub mismatched_responses: u64,
+    /// Datagrams that failed to open with our secure channel
+    pub unauthenticated: u64,
+    /// Datagrams that opened with our secure channel, but were too old, or seen before
+    pub replayed: u64,
+    /// Datagrams that couldn't be parsed
+    pub malformed: u64,
+    /// Errors other nodes sent in answer to our requests
+    pub errors_received: u64,
+    /// Pages of values that didn't start where we asked them to
+    pub unexpected_pages: u64,
+    /// Messages of ours that couldn't be encoded, and were never sent
+    pub unencodable: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket_allows_bursts() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(3.0, 1.0, now);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1.0, 2.0, now);
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+        assert!(bucket.try_take(now + Duration::from_millis(500)));
+        assert!(bucket.is_full(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn rate_limiter_separates_keys() {
+        let now = Instant::now();
+        let rate = Rate {
+            burst: 1,
+            per_second: 1,
+        };
+        let mut limiter = RateLimiter::new(rate, now);
+        assert!(limiter.allow(1, now));
+        assert!(!limiter.allow(1, now));
+        assert!(limiter.allow(2, now));
+        // Sweeping only happens every so often
+        limiter.prune(now + Duration::from_secs(1));
+        assert_eq!(2, limiter.buckets.len());
+        limiter.prune(now + PRUNE_PERIOD);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn suspicion_is_forgiven() {
+        let now = Instant::now();
+        let mut suspicion = Suspicion::new(2, 2, now);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        suspicion.strike(ip, now);
+        assert!(suspicion.is_trusted(ip, now));
+        suspicion.strike(ip, now);
+        assert!(!suspicion.is_trusted(ip, now));
+        assert!(suspicion.is_trusted(ip, now + FORGIVE_PERIOD));
+        suspicion.prune(now + 2 * FORGIVE_PERIOD);
+        assert!(suspicion.strikes.is_empty());
+        // A genuine response clears every strike, and we only track so many addresses
+        suspicion.strike(ip, now);
+        suspicion.strike(ip, now);
+        suspicion.clear(ip);
+        assert!(suspicion.is_trusted(ip, now));
+        for last in 1..=3 {
+            suspicion.strike(IpAddr::from([10, 0, 1, last]), now);
+        }
+        assert_eq!(2, suspicion.strikes.len());
+    }
+}
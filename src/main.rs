@@ -1,17 +1,48 @@
-extern crate rand;
-extern crate sha1;
-pub mod base;
-pub mod messages;
-pub mod routing;
-pub mod server;
-use server::{make_server_comms, run_server, ToServerMsg};
+use kadht::base::BitKey;
+use kadht::erasure::ErasureOptions;
+use kadht::krpc::NodeId;
+use kadht::mainline::MainlineConfig;
+use kadht::messages::NetworkKey;
+use kadht::quorum::{ReadQuorum, Resolver};
+use kadht::server::{
+    make_server_comms, run_server, FromServerMsg, GetOptions, ServerConfig, StoreOptions,
+    ToServerMsg,
+};
+use kadht::transport::SecureChannel;
+use std::env;
 use std::io;
+use std::net::ToSocketAddrs;
 use std::thread;
 
 fn main() {
     let (sender, receiver) = make_server_comms();
+    // Nodes sharing a network identifier form a private network,
+    // and nodes sharing a passphrase additionally encrypt their traffic
+    let config = ServerConfig {
+        network_key: env::var("KADHT_NETWORK")
+            .ok()
+            .map(|network| NetworkKey::from_passphrase(&network)),
+        channel: env::var("KADHT_PASSPHRASE")
+            .ok()
+            .map(|passphrase| SecureChannel::from_passphrase(&passphrase)),
+        // Bootstrap nodes are separated by commas, like router.bittorrent.com:6881
+        mainline: env::var("KADHT_MAINLINE").ok().map(|nodes| MainlineConfig {
+            bootstrap: nodes
+                .split(',')
+                .filter_map(|node| node.to_socket_addrs().ok())
+                .flatten()
+                .collect(),
+        }),
+        ..ServerConfig::default()
+    };
+    // The mainline DHT is reached over the internet, rather than locally
+    let address = if config.mainline.is_some() {
+        "0.0.0.0:6881"
+    } else {
+        "127.0.0.1:8080"
+    };
     thread::spawn(move || {
-        if let Err(e) = run_server(receiver, "127.0.0.1:8080") {
+        if let Err(e) = run_server(receiver, address, config) {
             println!("Server died: {}", e);
         }
     });
@@ -21,29 +52,139 @@ fn main() {
         let _amt = stdin.read_line(&mut line);
         let splits: Vec<&str> = line.split_whitespace().collect();
         let mut sent = false;
-        match splits.as_slice() {
-            &["store", k, v] => {
-                let msg = ToServerMsg::Store(k.into(), v.into());
+        match *splits.as_slice() {
+            ["store", k, v] => {
+                let msg = ToServerMsg::Store(k.into(), v.into(), StoreOptions::default());
                 if let Err(e) = sender.send(msg) {
                     println!("Error: {}", e);
                 } else {
                     sent = true;
                 }
             }
-            &["get", k] => {
-                let msg = ToServerMsg::Get(k.into());
+            ["store_if", k, expected, v] => match expected.parse() {
+                Ok(expected) => {
+                    let options = StoreOptions::default();
+                    let msg = ToServerMsg::StoreIf(k.into(), v.into(), expected, options);
+                    if let Err(e) = sender.send(msg) {
+                        println!("Error: {}", e);
+                    } else {
+                        sent = true;
+                    }
+                }
+                Err(e) => println!("Invalid version: {}", e),
+            },
+            ["quorum_get", k, replicas] => match replicas.parse() {
+                Ok(replicas) => {
+                    let options = GetOptions {
+                        quorum: Some(ReadQuorum {
+                            replicas,
+                            resolver: Resolver::Majority,
+                        }),
+                        ..GetOptions::default()
+                    };
+                    if let Err(e) = sender.send(ToServerMsg::Get(k.into(), options)) {
+                        println!("Error: {}", e);
+                    } else {
+                        sent = true;
+                    }
+                }
+                Err(e) => println!("Invalid quorum: {}", e),
+            },
+            ["store_coded", k, v] => {
+                let erasure = ErasureOptions::default();
+                let msg =
+                    ToServerMsg::StoreCoded(k.into(), v.into(), erasure, StoreOptions::default());
+                if let Err(e) = sender.send(msg) {
+                    println!("Error: {}", e);
+                } else {
+                    sent = true;
+                }
+            }
+            ["put_blob", k, v] => {
+                let data = v.as_bytes().to_vec();
+                if let Err(e) = sender.put_blob(k.into(), data, StoreOptions::default()) {
+                    println!("Error: {}", e);
+                } else {
+                    sent = true;
+                }
+            }
+            ["get_blob", k] => {
+                if let Err(e) = sender.get_blob(k.into(), GetOptions::default()) {
+                    println!("Error: {}", e);
+                } else {
+                    sent = true;
+                }
+            }
+            ["delete", k] => {
+                let msg = ToServerMsg::Delete(k.into(), StoreOptions::default());
                 if let Err(e) = sender.send(msg) {
                     println!("Error: {}", e);
                 } else {
                     sent = true;
                 }
             }
+            ["get", k] => {
+                let msg = ToServerMsg::Get(k.into(), GetOptions::default());
+                if let Err(e) = sender.send(msg) {
+                    println!("Error: {}", e);
+                } else {
+                    sent = true;
+                }
+            }
+            ["provide", k] => {
+                if let Err(e) = sender.provide(BitKey::from_hash(k), StoreOptions::default()) {
+                    println!("Error: {}", e);
+                } else {
+                    sent = true;
+                }
+            }
+            ["providers", k] => {
+                if let Err(e) = sender.find_providers(BitKey::from_hash(k), GetOptions::default()) {
+                    println!("Error: {}", e);
+                } else {
+                    sent = true;
+                }
+            }
+            ["get_peers", info_hash] => match NodeId::from_hex(info_hash) {
+                Some(info_hash) => {
+                    if let Err(e) = sender.send(ToServerMsg::GetPeers(info_hash)) {
+                        println!("Error: {}", e);
+                    } else {
+                        sent = true;
+                    }
+                }
+                None => println!("Invalid info hash"),
+            },
+            ["announce_peer", info_hash, port] => {
+                match (NodeId::from_hex(info_hash), port.parse()) {
+                    (Some(info_hash), Ok(port)) => {
+                        let msg = ToServerMsg::AnnouncePeer(info_hash, port);
+                        if let Err(e) = sender.send(msg) {
+                            println!("Error: {}", e);
+                        } else {
+                            sent = true;
+                        }
+                    }
+                    _ => println!("Invalid info hash or port"),
+                }
+            }
+            ["stats"] => {
+                if let Err(e) = sender.send(ToServerMsg::Stats) {
+                    println!("Error: {}", e);
+                } else {
+                    sent = true;
+                }
+            }
             _ => println!("Unkown command"),
         }
         line.clear();
         if sent {
-            if let Ok(resp) = sender.receive() {
+            while let Ok(resp) = sender.receive() {
                 println!("{:?}", resp);
+                // Blobs arrive in pieces, before the response ending the read
+                if !matches!(resp, FromServerMsg::BlobData(_)) {
+                    break;
+                }
             }
         }
     }
@@ -0,0 +1,642 @@
+use crate::base::{BitKey, Node};
+use crate::krpc::{KrpcBody, KrpcMessage, MainlineNode, NodeId, Query, Response};
+use crate::limits::{DropCounters, Limits, RateLimiter};
+use crate::messages::Token;
+use crate::providers::ProviderTable;
+use crate::rand::rngs::ThreadRng;
+use crate::rand::thread_rng;
+use crate::rand::Rng;
+use crate::routing::{IpLimits, KBucketInsert, RoutingTable};
+use crate::server::{FromServerMsg, ServerReceiver, ToServerMsg};
+use crate::token::TokenSecrets;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How big buckets are on the mainline DHT, and how many nodes a lookup ends on.
+pub const K: usize = 8;
+// How many requests a lookup keeps in flight at once
+const ALPHA: usize = 3;
+// How many candidates a lookup remembers, so forged responses can't grow it without bound
+const MAX_CANDIDATES: usize = 4 * K;
+// How many peers a lookup collects before ignoring the rest
+const MAX_PEERS: usize = 256;
+const TIMEOUT: Duration = Duration::from_secs(5);
+const BUF_SIZE: usize = 2048;
+// The error codes BEP-5 defines
+const SERVER_ERROR: i64 = 202;
+const PROTOCOL_ERROR: i64 = 203;
+
+/// Represents the options used to run a server on the mainline DHT.
+#[derive(Clone, Debug, Default)]
+pub struct MainlineConfig {
+    /// The nodes we ask for our neighbours when starting, such as `router.bittorrent.com:6881`
+    pub bootstrap: Vec<SocketAddr>,
+}
+
+// Compare distances by XORing the whole 160 bits, which sorts them like big numbers
+fn distance(a: NodeId, b: NodeId) -> [u8; 20] {
+    let mut out = a.0;
+    for (x, y) in out.iter_mut().zip(b.0.iter()) {
+        *x ^= y;
+    }
+    out
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Purpose {
+    // Checking whether a node in our table is still alive
+    Ping(BitKey),
+    Lookup,
+    Announce,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    // We don't know the IDs of bootstrap nodes until they answer
+    id: Option<NodeId>,
+    addr: SocketAddr,
+    purpose: Purpose,
+    sent: Instant,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Status {
+    Fresh,
+    Waiting,
+    // Nodes give us a token along with their answer, if we asked for peers
+    Answered(Option<Vec<u8>>),
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Intention {
+    // Looking up our own ID, so that our neighbours learn about us
+    Join,
+    GetPeers,
+    Announce(u16),
+}
+
+// A lookup walks towards the target, querying the closest nodes it hasn't queried yet.
+struct Lookup {
+    target: NodeId,
+    intention: Intention,
+    candidates: Vec<(MainlineNode, Status)>,
+    peers: Vec<SocketAddr>,
+    // Once we announce ourselves, how many announcements are in flight, and how many succeeded
+    announcing: Option<(usize, usize)>,
+}
+
+impl Lookup {
+    fn new(target: NodeId, intention: Intention) -> Self {
+        Lookup {
+            target,
+            intention,
+            candidates: Vec::new(),
+            peers: Vec::new(),
+            announcing: None,
+        }
+    }
+
+    fn offer(&mut self, nodes: &[MainlineNode], this_id: NodeId) {
+        for node in nodes {
+            if node.id != this_id && !self.candidates.iter().any(|(n, _)| n.id == node.id) {
+                self.candidates.push((*node, Status::Fresh));
+            }
+        }
+        let target = self.target;
+        self.candidates
+            .sort_by_key(|(node, _)| distance(node.id, target));
+        self.candidates.truncate(MAX_CANDIDATES);
+    }
+
+    fn offer_peers(&mut self, peers: &[SocketAddr]) {
+        for peer in peers {
+            if self.peers.len() < MAX_PEERS && !self.peers.contains(peer) {
+                self.peers.push(*peer);
+            }
+        }
+    }
+
+    fn set(&mut self, id: NodeId, status: Status) {
+        if let Some((_, existing)) = self.candidates.iter_mut().find(|(n, _)| n.id == id) {
+            *existing = status;
+        }
+    }
+
+    // The closest nodes that haven't failed us, which the lookup converges on
+    fn closest(&self) -> impl Iterator<Item = &(MainlineNode, Status)> {
+        self.candidates
+            .iter()
+            .filter(|(_, status)| *status != Status::Failed)
+            .take(K)
+    }
+
+    fn next(&mut self) -> Vec<MainlineNode> {
+        let waiting = self
+            .candidates
+            .iter()
+            .filter(|(_, status)| *status == Status::Waiting)
+            .count();
+        let next: Vec<MainlineNode> = self
+            .closest()
+            .filter(|(_, status)| *status == Status::Fresh)
+            .map(|(node, _)| *node)
+            .take(ALPHA.saturating_sub(waiting))
+            .collect();
+        for node in &next {
+            self.set(node.id, Status::Waiting);
+        }
+        next
+    }
+
+    fn is_finished(&self) -> bool {
+        self.closest()
+            .all(|(_, status)| matches!(status, Status::Answered(_)))
+    }
+}
+
+// Represents a server speaking the mainline DHT protocol, instead of our own.
+//
+// Our routing table only keys nodes by 128 bits, so we keep the full 160 bit ID
+// of each node in the table on the side, and drop it when the node leaves the table.
+struct MainlineHandle {
+    this_id: NodeId,
+    table: RoutingTable,
+    ids: HashMap<BitKey, NodeId>,
+    receiver: ServerReceiver,
+    sock: UdpSocket,
+    pending: HashMap<Vec<u8>, Pending>,
+    lookup: Option<Lookup>,
+    peers: ProviderTable,
+    tokens: TokenSecrets,
+    limits: Limits,
+    ip_limiter: RateLimiter<IpAddr>,
+    dropped: DropCounters,
+    rng: ThreadRng,
+    buf: Box<[u8; BUF_SIZE]>,
+}
+
+impl MainlineHandle {
+    fn send(&mut self, message: &KrpcMessage, addr: SocketAddr) -> io::Result<()> {
+        self.sock.send_to(&message.to_bytes(), addr)?;
+        Ok(())
+    }
+
+    fn query(
+        &mut self,
+        id: Option<NodeId>,
+        addr: SocketAddr,
+        query: Query,
+        purpose: Purpose,
+    ) -> io::Result<()> {
+        let transaction_id = self.rng.gen::<[u8; 4]>().to_vec();
+        let pending = Pending {
+            id,
+            addr,
+            purpose,
+            sent: Instant::now(),
+        };
+        self.pending.insert(transaction_id.clone(), pending);
+        let message = KrpcMessage {
+            transaction_id,
+            version: None,
+            body: KrpcBody::Query(self.this_id, query),
+        };
+        self.send(&message, addr)
+    }
+
+    fn respond(
+        &mut self,
+        transaction_id: Vec<u8>,
+        body: KrpcBody,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        let message = KrpcMessage {
+            transaction_id,
+            version: None,
+            body,
+        };
+        self.send(&message, src)
+    }
+
+    fn closest(&self, target: NodeId) -> Vec<MainlineNode> {
+        // Our own node is in the table, but never has an ID on the side
+        self.table
+            .k_closest(target.to_bitkey(), K + 1)
+            .into_iter()
+            .filter_map(|node| {
+                let id = *self.ids.get(&node.id)?;
+                Some(MainlineNode {
+                    id,
+                    udp_addr: node.udp_addr,
+                })
+            })
+            .take(K)
+            .collect()
+    }
+
+    fn learn(&mut self, node: MainlineNode) -> io::Result<()> {
+        let key = node.id.to_bitkey();
+        // A node sharing the first 128 bits with one we know would take its place
+        let shadowed = self.ids.get(&key).is_some_and(|&id| id != node.id);
+        if shadowed || key == self.table.this_node_id() {
+            return Ok(());
+        }
+        let routed = Node {
+            id: key,
+            udp_addr: node.udp_addr,
+        };
+        let inserted = self.table.insert(routed);
+        if self.table.contains(&routed) {
+            self.ids.insert(key, node.id);
+        }
+        if let KBucketInsert::Ping(old) = inserted {
+            self.ping(old)?;
+        }
+        Ok(())
+    }
+
+    fn ping(&mut self, node: Node) -> io::Result<()> {
+        let pings = self
+            .pending
+            .values()
+            .filter(|p| matches!(p.purpose, Purpose::Ping(_)));
+        if pings.clone().any(|p| p.addr == node.udp_addr) {
+            return Ok(());
+        }
+        if pings.count() >= self.limits.max_pings_in_flight {
+            self.dropped.pings_skipped += 1;
+            return Ok(());
+        }
+        let id = self.ids.get(&node.id).copied();
+        self.query(id, node.udp_addr, Query::Ping, Purpose::Ping(node.id))
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let (amt, src) = match self.sock.recv_from(&mut self.buf[..]) {
+            Ok(received) => received,
+            Err(_) => return Ok(()),
+        };
+        if !self.ip_limiter.allow(src.ip(), Instant::now()) {
+            self.dropped.ip_rate_limited += 1;
+            return Ok(());
+        }
+        let message = match KrpcMessage::try_from(&self.buf[..amt]) {
+            Ok(message) => message,
+            Err(_) => {
+                self.dropped.malformed += 1;
+                return Ok(());
+            }
+        };
+        match message.body {
+            KrpcBody::Query(id, query) => self.handle_query(message.transaction_id, id, query, src),
+            KrpcBody::Response(id, response) => {
+                self.handle_response(&message.transaction_id, id, response, src)
+            }
+            KrpcBody::Error(_, _) => {
+                self.dropped.errors_received += 1;
+                self.handle_error(&message.transaction_id, src)
+            }
+        }
+    }
+
+    fn handle_query(
+        &mut self,
+        transaction_id: Vec<u8>,
+        id: NodeId,
+        query: Query,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        self.learn(MainlineNode { id, udp_addr: src })?;
+        let mut response = Response::default();
+        match query {
+            Query::Ping => {}
+            Query::FindNode(target) => response.nodes = self.closest(target),
+            Query::GetPeers(info_hash) => {
+                let peers = self.peers.get(info_hash.to_bitkey(), Instant::now());
+                if peers.is_empty() {
+                    response.nodes = self.closest(info_hash);
+                } else {
+                    response.values = peers.iter().map(|peer| peer.udp_addr).collect();
+                }
+                let token = self.tokens.token_for(src.ip());
+                response.token = Some(token.0.to_be_bytes().to_vec());
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                let valid = <[u8; 8]>::try_from(&token[..]).is_ok_and(|bytes| {
+                    let token = Token(u64::from_be_bytes(bytes));
+                    self.tokens.verify(src.ip(), token)
+                });
+                if !valid {
+                    let error = KrpcBody::Error(PROTOCOL_ERROR, String::from("bad token"));
+                    return self.respond(transaction_id, error, src);
+                }
+                let port = if implied_port { src.port() } else { port };
+                let peer = Node {
+                    id: id.to_bitkey(),
+                    udp_addr: SocketAddr::new(src.ip(), port),
+                };
+                if !self.peers.add(info_hash.to_bitkey(), peer, Instant::now()) {
+                    self.dropped.storage_full += 1;
+                    let error = KrpcBody::Error(SERVER_ERROR, String::from("out of storage"));
+                    return self.respond(transaction_id, error, src);
+                }
+            }
+        }
+        let body = KrpcBody::Response(self.this_id, response);
+        self.respond(transaction_id, body, src)
+    }
+
+    // Take out the request a response answers, if it came from the node we sent it to
+    fn take_pending(
+        &mut self,
+        transaction_id: &[u8],
+        id: Option<NodeId>,
+        src: SocketAddr,
+    ) -> Option<Pending> {
+        let pending = *self.pending.get(transaction_id)?;
+        let same_id = match (pending.id, id) {
+            (Some(expected), Some(id)) => expected == id,
+            _ => true,
+        };
+        if pending.addr != src || !same_id {
+            self.dropped.mismatched_responses += 1;
+            return None;
+        }
+        self.pending.remove(transaction_id)
+    }
+
+    fn handle_response(
+        &mut self,
+        transaction_id: &[u8],
+        id: NodeId,
+        response: Response,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        let pending = match self.take_pending(transaction_id, Some(id), src) {
+            None => return Ok(()),
+            Some(pending) => pending,
+        };
+        let node = MainlineNode { id, udp_addr: src };
+        self.learn(node)?;
+        let this_id = self.this_id;
+        match (pending.purpose, &mut self.lookup) {
+            (Purpose::Lookup, Some(lookup)) => {
+                // Bootstrap nodes aren't candidates yet, since we didn't know their IDs
+                lookup.offer(&[node], this_id);
+                lookup.set(id, Status::Answered(response.token));
+                lookup.offer(&response.nodes, this_id);
+                lookup.offer_peers(&response.values);
+            }
+            (Purpose::Announce, Some(lookup)) => {
+                if let Some((waiting, acked)) = &mut lookup.announcing {
+                    *waiting -= 1;
+                    *acked += 1;
+                }
+            }
+            _ => {}
+        }
+        self.step_lookup()
+    }
+
+    fn handle_error(&mut self, transaction_id: &[u8], src: SocketAddr) -> io::Result<()> {
+        if let Some(pending) = self.take_pending(transaction_id, None, src) {
+            self.fail(pending);
+        }
+        self.step_lookup()
+    }
+
+    fn fail(&mut self, pending: Pending) {
+        match (pending.purpose, &mut self.lookup) {
+            (Purpose::Ping(key), _) => {
+                self.table.remove(key);
+                self.ids.remove(&key);
+            }
+            (Purpose::Lookup, Some(lookup)) => {
+                if let Some(id) = pending.id {
+                    lookup.set(id, Status::Failed);
+                }
+            }
+            (Purpose::Announce, Some(lookup)) => {
+                if let Some((waiting, _)) = &mut lookup.announcing {
+                    *waiting -= 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn start_lookup(&mut self, target: NodeId, intention: Intention) -> io::Result<()> {
+        let mut lookup = Lookup::new(target, intention);
+        lookup.offer(&self.closest(target), self.this_id);
+        self.lookup = Some(lookup);
+        self.step_lookup()
+    }
+
+    fn step_lookup(&mut self) -> io::Result<()> {
+        let mut lookup = match self.lookup.take() {
+            None => return Ok(()),
+            Some(lookup) => lookup,
+        };
+        if let Some((waiting, acked)) = lookup.announcing {
+            if waiting == 0 {
+                self.receiver
+                    .to
+                    .send(FromServerMsg::StoreResp(acked))
+                    .unwrap();
+            } else {
+                self.lookup = Some(lookup);
+            }
+            return Ok(());
+        }
+        let target = lookup.target;
+        for node in lookup.next() {
+            let query = match lookup.intention {
+                Intention::Join => Query::FindNode(target),
+                Intention::GetPeers | Intention::Announce(_) => Query::GetPeers(target),
+            };
+            self.query(Some(node.id), node.udp_addr, query, Purpose::Lookup)?;
+        }
+        let searching = self.pending.values().any(|p| p.purpose == Purpose::Lookup);
+        if searching || !lookup.is_finished() {
+            self.lookup = Some(lookup);
+            return Ok(());
+        }
+        match lookup.intention {
+            Intention::Join => {}
+            Intention::GetPeers => {
+                let msg = FromServerMsg::GetPeersResp(lookup.peers);
+                self.receiver.to.send(msg).unwrap();
+            }
+            Intention::Announce(port) => {
+                // Only nodes that gave us a token will accept our announcement
+                let announce: Vec<(MainlineNode, Vec<u8>)> = lookup
+                    .closest()
+                    .filter_map(|(node, status)| match status {
+                        Status::Answered(Some(token)) => Some((*node, token.clone())),
+                        _ => None,
+                    })
+                    .collect();
+                lookup.announcing = Some((announce.len(), 0));
+                for (node, token) in announce {
+                    let query = Query::AnnouncePeer {
+                        info_hash: target,
+                        port,
+                        implied_port: false,
+                        token,
+                    };
+                    self.query(Some(node.id), node.udp_addr, query, Purpose::Announce)?;
+                }
+                self.lookup = Some(lookup);
+                return self.step_lookup();
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_stale(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.pending.retain(|_, pending| {
+            let stale = now.duration_since(pending.sent) > TIMEOUT;
+            if stale {
+                expired.push(*pending);
+            }
+            !stale
+        });
+        for pending in expired {
+            self.fail(pending);
+        }
+        self.tokens.rotate(&mut self.rng, now);
+        self.peers.prune(now);
+        self.ip_limiter.prune(now);
+        self.step_lookup()
+    }
+
+    fn handle_client(&mut self) -> io::Result<()> {
+        // Like our own server, we only run one lookup at a time
+        if self.lookup.is_some() {
+            return Ok(());
+        }
+        match self.receiver.from.try_recv() {
+            Ok(ToServerMsg::GetPeers(info_hash)) => {
+                self.start_lookup(info_hash, Intention::GetPeers)
+            }
+            Ok(ToServerMsg::AnnouncePeer(info_hash, port)) => {
+                self.start_lookup(info_hash, Intention::Announce(port))
+            }
+            Ok(ToServerMsg::Stats) => {
+                let msg = FromServerMsg::StatsResp(self.dropped);
+                self.receiver.to.send(msg).unwrap();
+                Ok(())
+            }
+            Ok(_) => {
+                self.receiver.to.send(FromServerMsg::Unsupported).unwrap();
+                Ok(())
+            }
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Run a server speaking the mainline DHT protocol, described in BEP-5, on a bound socket.
+///
+/// The server answers pings, node lookups, and peer lookups and announcements
+/// from other nodes, and joins the network by looking up its own ID through
+/// the bootstrap nodes.
+pub(crate) fn run(
+    receiver: ServerReceiver,
+    sock: UdpSocket,
+    config: MainlineConfig,
+    limits: Limits,
+    ip_limits: IpLimits,
+) -> io::Result<()> {
+    let mut rng = thread_rng();
+    let this_id = NodeId(rng.gen());
+    let this_node = Node {
+        id: this_id.to_bitkey(),
+        udp_addr: sock.local_addr()?,
+    };
+    let mut handle = MainlineHandle {
+        this_id,
+        table: RoutingTable::with_ip_limits(this_node, K, ip_limits),
+        ids: HashMap::new(),
+        receiver,
+        sock,
+        pending: HashMap::new(),
+        lookup: None,
+        peers: ProviderTable::new(
+            limits.max_providers_per_key,
+            limits.max_providers,
+            Instant::now(),
+        ),
+        tokens: TokenSecrets::new(&mut rng, Instant::now()),
+        limits,
+        ip_limiter: RateLimiter::new(limits.per_ip, Instant::now()),
+        dropped: DropCounters::default(),
+        rng,
+        buf: Box::new([0; BUF_SIZE]),
+    };
+    handle
+        .sock
+        .set_read_timeout(Some(Duration::from_millis(400)))?;
+    handle.lookup = Some(Lookup::new(this_id, Intention::Join));
+    for addr in config.bootstrap {
+        handle.query(None, addr, Query::FindNode(this_id), Purpose::Lookup)?;
+    }
+    loop {
+        handle.receive()?;
+        handle.remove_stale()?;
+        handle.handle_client()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first: u8) -> MainlineNode {
+        let mut id = [0; 20];
+        id[0] = first;
+        MainlineNode {
+            id: NodeId(id),
+            udp_addr: SocketAddr::new([10, 0, 0, first].into(), 6881),
+        }
+    }
+
+    #[test]
+    fn lookup_queries_closest_first() {
+        let target = node(0).id;
+        let mut lookup = Lookup::new(target, Intention::GetPeers);
+        let nodes: Vec<MainlineNode> = (1..=20).rev().map(node).collect();
+        lookup.offer(&nodes, node(0xFF).id);
+        let first: Vec<u8> = lookup.next().iter().map(|n| n.id.0[0]).collect();
+        assert_eq!(vec![1, 2, 3], first);
+        // We wait on the first answers before going further
+        assert!(lookup.next().is_empty());
+        lookup.set(node(1).id, Status::Answered(None));
+        lookup.set(node(2).id, Status::Failed);
+        let second: Vec<u8> = lookup.next().iter().map(|n| n.id.0[0]).collect();
+        assert_eq!(vec![4, 5], second);
+        assert!(!lookup.is_finished());
+    }
+
+    #[test]
+    fn lookup_candidates_are_bounded() {
+        let target = node(0).id;
+        let mut lookup = Lookup::new(target, Intention::Join);
+        let nodes: Vec<MainlineNode> = (1..=200).map(node).collect();
+        lookup.offer(&nodes, target);
+        lookup.offer(&nodes, target);
+        assert_eq!(MAX_CANDIDATES, lookup.candidates.len());
+        assert_eq!(1, lookup.candidates[0].0.id.0[0]);
+    }
+}
@@ -1,10 +1,31 @@
 use crate::base::{BitKey, Node};
 use crate::rand::distributions::{Distribution, Standard};
 use crate::rand::Rng;
+use crate::record::{
+    publisher_id, DeleteSignature, MutableRecord, MutableRecordRef, StoreSignature,
+    PUBLIC_KEY_BYTES, SIGNATURE_BYTES,
+};
+use hmac::{Hmac, Mac};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
 use std::convert::{TryFrom, TryInto};
 use std::net::{IpAddr, SocketAddr};
 
 const BITKEY_BYTES: usize = 16;
+const HEADER_BYTES: usize = 25;
+const CAPABILITIES_BYTES: usize = 4;
+
+/// The version of the protocol implemented by this crate.
+///
+/// This is included in the header of every message we send.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// How many bytes tagging a message for a private network adds on top of the message.
+pub const NETWORK_TAG_BYTES: usize = 32;
+// Strings, values, node lists, and value lists are prefixed with a single byte length
+const MAX_PREFIXED_LEN: usize = u8::MAX as usize;
 
 /// Represents an error when parsing out a message.
 ///
@@ -18,61 +39,538 @@ pub enum ParseError {
     InvalidString,
     /// The type of message was unrecognized
     UnknownMessageType,
+    /// The message wasn't tagged with the key for our network
+    WrongNetwork,
+    /// A node entry used an IP address type other than IPV4 or IPV6
+    UnknownAddressType,
+    /// The header carried a protocol version other than ours
+    UnsupportedVersion(u8),
+}
+
+/// Represents an error when serializing a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncodeError {
+    /// The buffer didn't have room for the whole message
+    BufferTooSmall,
+    /// A string was longer than 255 bytes
+    StringTooLong,
+    /// A list contained more than 255 nodes
+    TooManyNodes,
+    /// A page contained more than 255 values, or started past the 255th value
+    TooManyValues,
 }
 
 fn try_bitkey_from(data: &[u8]) -> Result<BitKey, ParseError> {
-    let len = std::mem::size_of::<BitKey>();
-    let bitkey_bytes = data[..len]
+    let bitkey_bytes = data
+        .get(..BITKEY_BYTES)
+        .ok_or(ParseError::InsufficientLength)?
         .try_into()
         .map_err(|_| ParseError::InsufficientLength)?;
     Ok(BitKey(u128::from_be_bytes(bitkey_bytes)))
 }
 
+fn try_u64_from(data: &[u8]) -> Result<u64, ParseError> {
+    let bytes = data
+        .get(..8)
+        .ok_or(ParseError::InsufficientLength)?
+        .try_into()
+        .map_err(|_| ParseError::InsufficientLength)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
 // This returns the string, and the total amount of bytes consumed
-fn try_string_from(data: &[u8]) -> Result<(String, usize), ParseError> {
+fn try_bytes_from(data: &[u8]) -> Result<(&[u8], usize), ParseError> {
     let (head, rest) = data.split_first().ok_or(ParseError::InsufficientLength)?;
     let byte_count = *head as usize;
-    if rest.len() < byte_count {
+    let bytes = rest
+        .get(..byte_count)
+        .ok_or(ParseError::InsufficientLength)?;
+    Ok((bytes, byte_count + 1))
+}
+
+fn try_str_from(data: &[u8]) -> Result<(&str, usize), ParseError> {
+    let (bytes, read_count) = try_bytes_from(data)?;
+    let string = std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidString)?;
+    Ok((string, read_count))
+}
+
+// This returns the node, and the total amount of bytes consumed
+fn try_node_from(data: &[u8]) -> Result<(Node, usize), ParseError> {
+    let start_len = 1 + BITKEY_BYTES;
+    if data.len() < start_len {
+        return Err(ParseError::InsufficientLength);
+    }
+    let id = try_bitkey_from(data)?;
+    let ip_type = data[BITKEY_BYTES];
+    let data = &data[start_len..];
+    let ip_len = match ip_type {
+        4 => 4,
+        6 => 16,
+        _ => return Err(ParseError::UnknownAddressType),
+    };
+    let end_len = ip_len + std::mem::size_of::<u16>();
+    if data.len() < end_len {
         return Err(ParseError::InsufficientLength);
     }
-    let string =
-        String::from_utf8(rest[..byte_count].into()).map_err(|_| ParseError::InvalidString)?;
-    Ok((string, byte_count + 1))
+    // The unwrapping is fine since we already checked the length
+    let ip = if ip_type == 4 {
+        let ip4_bytes: [u8; 4] = data[..ip_len].try_into().unwrap();
+        IpAddr::V4(ip4_bytes.into())
+    } else {
+        let ip16_bytes: [u8; 16] = data[..ip_len].try_into().unwrap();
+        IpAddr::V6(ip16_bytes.into())
+    };
+    let port_bytes = data[ip_len..end_len].try_into().unwrap();
+    let port = u16::from_be_bytes(port_bytes);
+    let udp_addr = SocketAddr::new(ip, port);
+    Ok((Node { id, udp_addr }, start_len + end_len))
+}
+
+// Signed stores and deletes are both a public key followed by a signature
+const DELETE_SIGNATURE_BYTES: usize = PUBLIC_KEY_BYTES + SIGNATURE_BYTES;
+const STORE_SIGNATURE_BYTES: usize = DELETE_SIGNATURE_BYTES;
+
+fn try_delete_signature_from(data: &[u8]) -> Result<DeleteSignature, ParseError> {
+    let bytes = data
+        .get(..DELETE_SIGNATURE_BYTES)
+        .ok_or(ParseError::InsufficientLength)?;
+    Ok(DeleteSignature {
+        public_key: bytes[..PUBLIC_KEY_BYTES].try_into().unwrap(),
+        signature: bytes[PUBLIC_KEY_BYTES..].try_into().unwrap(),
+    })
+}
+
+fn try_store_signature_from(data: &[u8]) -> Result<StoreSignature, ParseError> {
+    let bytes = data
+        .get(..STORE_SIGNATURE_BYTES)
+        .ok_or(ParseError::InsufficientLength)?;
+    Ok(StoreSignature {
+        public_key: bytes[..PUBLIC_KEY_BYTES].try_into().unwrap(),
+        signature: bytes[PUBLIC_KEY_BYTES..].try_into().unwrap(),
+    })
+}
+
+// This returns the record, and the total amount of bytes consumed
+fn try_record_from(data: &[u8]) -> Result<(MutableRecordRef<'_>, usize), ParseError> {
+    let public_key = data
+        .get(..PUBLIC_KEY_BYTES)
+        .ok_or(ParseError::InsufficientLength)?
+        .try_into()
+        .map_err(|_| ParseError::InsufficientLength)?;
+    let mut read_count = PUBLIC_KEY_BYTES;
+    let (salt, salt_len) = try_str_from(&data[read_count..])?;
+    read_count += salt_len;
+    let seq_bytes = data
+        .get(read_count..read_count + 8)
+        .ok_or(ParseError::InsufficientLength)?
+        .try_into()
+        .map_err(|_| ParseError::InsufficientLength)?;
+    read_count += 8;
+    let (value, value_len) = try_str_from(&data[read_count..])?;
+    read_count += value_len;
+    let signature = data
+        .get(read_count..read_count + SIGNATURE_BYTES)
+        .ok_or(ParseError::InsufficientLength)?
+        .try_into()
+        .map_err(|_| ParseError::InsufficientLength)?;
+    read_count += SIGNATURE_BYTES;
+    let record = MutableRecordRef {
+        public_key,
+        salt,
+        seq: u64::from_be_bytes(seq_bytes),
+        value,
+        signature,
+    };
+    Ok((record, read_count))
+}
+
+/// Represents a list of nodes, borrowed from the buffer of a message.
+///
+/// Every entry is checked when the list is parsed, but each node only gets
+/// decoded once we iterate over the list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodesRef<'a> {
+    remaining: usize,
+    data: &'a [u8],
+}
+
+impl<'a> TryFrom<&'a [u8]> for NodesRef<'a> {
+    type Error = ParseError;
+
+    fn try_from(data: &'a [u8]) -> Result<Self, Self::Error> {
+        try_nodes_from(data).map(|(nodes, _)| nodes)
+    }
 }
 
-fn try_nodes_from(data: &[u8]) -> Result<Vec<Node>, ParseError> {
+// This returns the list of nodes, as well as the number of bytes read
+fn try_nodes_from(data: &[u8]) -> Result<(NodesRef<'_>, usize), ParseError> {
     let (head, rest) = data.split_first().ok_or(ParseError::InsufficientLength)?;
-    let capacity = *head as usize;
-    let mut buf = Vec::with_capacity(capacity);
-    let mut data = rest;
-    while buf.len() < capacity {
-        let start_len = 1 + BITKEY_BYTES;
-        if data.len() < start_len {
-            return Err(ParseError::InsufficientLength);
+    let remaining = *head as usize;
+    let mut len = 0;
+    for _ in 0..remaining {
+        let (_, read_count) = try_node_from(&rest[len..])?;
+        len += read_count;
+    }
+    let nodes = NodesRef {
+        remaining,
+        data: &rest[..len],
+    };
+    Ok((nodes, 1 + len))
+}
+
+impl<'a> Iterator for NodesRef<'a> {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        if self.remaining == 0 {
+            return None;
         }
-        let id = try_bitkey_from(data).unwrap();
-        let ip_type = data[BITKEY_BYTES];
-        data = &data[start_len..];
-        let ip_len = if ip_type == 4 { 4 } else { 16 };
-        let end_len = ip_len + std::mem::size_of::<u16>();
-        if data.len() < end_len {
-            return Err(ParseError::InsufficientLength);
+        // The unwrapping is fine since we checked every entry when parsing the list
+        let (node, read_count) = try_node_from(self.data).unwrap();
+        self.data = &self.data[read_count..];
+        self.remaining -= 1;
+        Some(node)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for NodesRef<'a> {}
+
+/// Represents a value stored under a key, along with the node that published it.
+///
+/// A key can hold many values, so that publishers storing under the same key
+/// don't overwrite each other. The publisher signs each value it stores, and
+/// its ID comes from the public key it signed with, so values passed along by
+/// other nodes can be traced back to it.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PublishedValue {
+    /// The ID of the node that stored this value
+    pub publisher: BitKey,
+    /// The version of this value, with higher versions superseding lower ones
+    pub version: u64,
+    /// The value itself, which can hold any bytes
+    pub value: Vec<u8>,
+    /// The signature of the publisher over the key, version, and value
+    pub signature: StoreSignature,
+}
+
+impl PublishedValue {
+    /// Check that the publisher signed this value, as stored under this key.
+    pub fn verify(&self, key: &str) -> bool {
+        self.signature.publisher() == self.publisher
+            && self.signature.verify(key, self.version, &self.value)
+    }
+
+    /// Compare two values, with the value that should win a conflict comparing as greater.
+    ///
+    /// Higher versions win, and ties are broken by publisher, then by the value
+    /// itself, so every node picks the same winner, whatever order it saw the values in.
+    pub fn precedence(&self, other: &Self) -> Ordering {
+        self.version
+            .cmp(&other.version)
+            .then(self.publisher.0.cmp(&other.publisher.0))
+            .then_with(|| self.value.cmp(&other.value))
+    }
+}
+
+/// Represents a value stored under a key, borrowed from the buffer of a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PublishedValueRef<'a> {
+    pub publisher: BitKey,
+    pub version: u64,
+    pub value: &'a [u8],
+    pub signature: StoreSignature,
+}
+
+impl<'a> From<PublishedValueRef<'a>> for PublishedValue {
+    fn from(value: PublishedValueRef<'a>) -> Self {
+        PublishedValue {
+            publisher: value.publisher,
+            version: value.version,
+            value: value.value.into(),
+            signature: value.signature,
+        }
+    }
+}
+
+/// Represents something held under a key, as pulled by a neighbour.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SyncEntry {
+    /// A value held under the key
+    Value(PublishedValue),
+    /// A tombstone, with the version its publisher deleted up to, and the signed delete
+    Tombstone(u64, DeleteSignature),
+}
+
+/// Represents something held under a key, borrowed from the buffer of a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncEntryRef<'a> {
+    Value(PublishedValueRef<'a>),
+    Tombstone(u64, DeleteSignature),
+}
+
+impl<'a> From<SyncEntryRef<'a>> for SyncEntry {
+    fn from(entry: SyncEntryRef<'a>) -> Self {
+        match entry {
+            SyncEntryRef::Value(value) => SyncEntry::Value(value.into()),
+            SyncEntryRef::Tombstone(version, signature) => SyncEntry::Tombstone(version, signature),
+        }
+    }
+}
+
+/// Represents part of the values held by a key.
+///
+/// A key can hold more values than fit in a single message, so values get
+/// sent in pages. If `start` plus the number of values is less than `total`,
+/// the requester can ask for the next page, starting where this one ended.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ValuePage {
+    /// The index of the first value in this page
+    pub start: u8,
+    /// How many values the key holds in total
+    pub total: u8,
+    /// The values in this page
+    pub values: Vec<PublishedValue>,
+}
+
+impl ValuePage {
+    /// The index of the first value the next page should start at, if there is one.
+    pub fn next_start(&self) -> Option<u8> {
+        if self.values.is_empty() {
+            return None;
         }
-        // The unwrapping is fine since we already checked the length
-        let ip = if ip_type == 4 {
-            let ip4_bytes: [u8; 4] = data[..ip_len].try_into().unwrap();
-            IpAddr::V4(ip4_bytes.into())
+        let next = usize::from(self.start) + self.values.len();
+        if next < usize::from(self.total) {
+            u8::try_from(next).ok()
         } else {
-            let ip16_bytes: [u8; 16] = data[..ip_len].try_into().unwrap();
-            IpAddr::V6(ip16_bytes.into())
-        };
-        let port_bytes = data[ip_len..end_len].try_into().unwrap();
-        let port = u16::from_be_bytes(port_bytes);
-        let udp_addr = SocketAddr::new(ip, port);
-        buf.push(Node { id, udp_addr });
-        data = &data[end_len..]
+            None
+        }
+    }
+}
+
+/// Represents a list of values, borrowed from the buffer of a message.
+///
+/// Like [NodesRef](struct.NodesRef.html), every entry is checked when the list
+/// is parsed, but only decoded once we iterate over the list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValuesRef<'a> {
+    remaining: usize,
+    data: &'a [u8],
+}
+
+// Store and StoreIf share a layout, differing only in what the version means
+fn try_store_from(data: &[u8]) -> Result<StoreFields<'_>, ParseError> {
+    let token = data.try_into()?;
+    let version = try_u64_from(&data[TOKEN_BYTES..])?;
+    let rest = &data[TOKEN_BYTES + 8..];
+    let signature = try_store_signature_from(rest)?;
+    let rest = &rest[STORE_SIGNATURE_BYTES..];
+    let (key, read_count) = try_str_from(rest)?;
+    let (val, _) = try_bytes_from(&rest[read_count..])?;
+    Ok((key, val, version, signature, token))
+}
+
+type StoreFields<'a> = (&'a str, &'a [u8], u64, StoreSignature, Token);
+
+// This returns the list of values, as well as the number of bytes read
+fn try_values_from(data: &[u8]) -> Result<(ValuesRef<'_>, usize), ParseError> {
+    let (head, rest) = data.split_first().ok_or(ParseError::InsufficientLength)?;
+    let remaining = *head as usize;
+    let mut len = 0;
+    for _ in 0..remaining {
+        let (_, read_count) = try_value_from(&rest[len..])?;
+        len += read_count;
+    }
+    let values = ValuesRef {
+        remaining,
+        data: &rest[..len],
+    };
+    Ok((values, 1 + len))
+}
+
+// This returns the value, and the total amount of bytes consumed
+//
+// The publisher isn't sent, since it comes from the public key the value was signed with.
+fn try_value_from(data: &[u8]) -> Result<(PublishedValueRef<'_>, usize), ParseError> {
+    let version = try_u64_from(data)?;
+    let signature = try_store_signature_from(&data[8..])?;
+    let (value, read_count) = try_bytes_from(&data[8 + STORE_SIGNATURE_BYTES..])?;
+    let value = PublishedValueRef {
+        publisher: publisher_id(&signature.public_key),
+        version,
+        value,
+        signature,
+    };
+    Ok((value, 8 + STORE_SIGNATURE_BYTES + read_count))
+}
+
+impl<'a> Iterator for ValuesRef<'a> {
+    type Item = PublishedValueRef<'a>;
+
+    fn next(&mut self) -> Option<PublishedValueRef<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        // The unwrapping is fine since we checked every entry when parsing the list
+        let (value, read_count) = try_value_from(self.data).unwrap();
+        self.data = &self.data[read_count..];
+        self.remaining -= 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for ValuesRef<'a> {}
+
+/// Represents a list of range hashes, borrowed from the buffer of a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashesRef<'a> {
+    data: &'a [u8],
+}
+
+fn try_hashes_from(data: &[u8]) -> Result<HashesRef<'_>, ParseError> {
+    let (head, rest) = data.split_first().ok_or(ParseError::InsufficientLength)?;
+    let len = *head as usize * 8;
+    let data = rest.get(..len).ok_or(ParseError::InsufficientLength)?;
+    Ok(HashesRef { data })
+}
+
+impl<'a> Iterator for HashesRef<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let hash = try_u64_from(self.data).ok()?;
+        self.data = &self.data[8..];
+        Some(hash)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.data.len() / 8, Some(self.data.len() / 8))
+    }
+}
+
+impl<'a> ExactSizeIterator for HashesRef<'a> {}
+
+/// Represents a list of keys, each with one of their entries, borrowed from the buffer of a message.
+///
+/// Like [ValuesRef](struct.ValuesRef.html), every entry is checked when the list
+/// is parsed, but only decoded once we iterate over the list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntriesRef<'a> {
+    remaining: usize,
+    data: &'a [u8],
+}
+
+// This returns the key and entry, as well as the number of bytes read
+fn try_entry_from(data: &[u8]) -> Result<((&str, SyncEntryRef<'_>), usize), ParseError> {
+    let (key, key_len) = try_str_from(data)?;
+    let (kind, rest) = data[key_len..]
+        .split_first()
+        .ok_or(ParseError::InsufficientLength)?;
+    match kind {
+        0 => {
+            let (value, value_len) = try_value_from(rest)?;
+            Ok(((key, SyncEntryRef::Value(value)), key_len + 1 + value_len))
+        }
+        1 => {
+            let version = try_u64_from(rest)?;
+            let signature = try_delete_signature_from(&rest[8..])?;
+            let entry = SyncEntryRef::Tombstone(version, signature);
+            Ok(((key, entry), key_len + 1 + 8 + DELETE_SIGNATURE_BYTES))
+        }
+        _ => Err(ParseError::UnknownMessageType),
+    }
+}
+
+fn try_entries_from(data: &[u8]) -> Result<EntriesRef<'_>, ParseError> {
+    let (head, rest) = data.split_first().ok_or(ParseError::InsufficientLength)?;
+    let remaining = *head as usize;
+    let mut len = 0;
+    for _ in 0..remaining {
+        let (_, read_count) = try_entry_from(&rest[len..])?;
+        len += read_count;
+    }
+    Ok(EntriesRef {
+        remaining,
+        data: &rest[..len],
+    })
+}
+
+impl<'a> Iterator for EntriesRef<'a> {
+    type Item = (&'a str, SyncEntryRef<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        // The unwrapping is fine since we checked every entry when parsing the list
+        let (entry, read_count) = try_entry_from(self.data).unwrap();
+        self.data = &self.data[read_count..];
+        self.remaining -= 1;
+        Some(entry)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for EntriesRef<'a> {}
+
+/// Represents part of the values held by a key, borrowed from the buffer of a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValuePageRef<'a> {
+    pub start: u8,
+    pub total: u8,
+    pub values: ValuesRef<'a>,
+}
+
+impl<'a> From<ValuePageRef<'a>> for ValuePage {
+    fn from(page: ValuePageRef<'a>) -> Self {
+        ValuePage {
+            start: page.start,
+            total: page.total,
+            values: page.values.map(PublishedValue::from).collect(),
+        }
+    }
+}
+
+/// Represents the key shared by every node in a private network.
+///
+/// Every message sent on a private network ends with an HMAC over the header
+/// and the payload, using this key. This lets us reject messages from
+/// nodes belonging to another network, even if they share the same hosts.
+#[derive(Clone)]
+pub struct NetworkKey([u8; 32]);
+
+impl NetworkKey {
+    /// Create a new key from raw bytes.
+    pub fn new(key: [u8; 32]) -> Self {
+        NetworkKey(key)
+    }
+
+    /// Create a new key by hashing a passphrase, or some other network identifier.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut key = [0; 32];
+        key.copy_from_slice(&Sha256::digest(passphrase.as_bytes()));
+        NetworkKey(key)
+    }
+
+    fn mac(&self, data: &[u8]) -> Hmac<Sha256> {
+        // HMAC accepts keys of any size
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(data);
+        mac
     }
-    Ok(buf)
 }
 
 /// Represents a Transaction ID used to identify RPC calls
@@ -82,13 +580,16 @@ fn try_nodes_from(data: &[u8]) -> Result<Vec<Node>, ParseError> {
 /// Transaction IDs can be generated randomly, but the Message struct already
 /// provides a utility for generating them when creating a message.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TransactionID(u64);
 
 impl TryFrom<&[u8]> for TransactionID {
     type Error = ParseError;
 
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
-        let bytes = data[..std::mem::size_of::<u64>()]
+        let bytes = data
+            .get(..std::mem::size_of::<u64>())
+            .ok_or(ParseError::InsufficientLength)?
             .try_into()
             .map_err(|_| ParseError::InsufficientLength)?;
         Ok(TransactionID(u64::from_be_bytes(bytes)))
@@ -101,6 +602,154 @@ impl Distribution<TransactionID> for Standard {
     }
 }
 
+/// Represents a token allowing a node to store values with us.
+///
+/// We hand out tokens in our responses to FindNode and FindValue calls,
+/// and require nodes to echo a valid token back when storing a value with us.
+/// Tokens are opaque to every node except the one that generated them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Token(pub u64);
+
+const TOKEN_BYTES: usize = 8;
+
+impl TryFrom<&[u8]> for Token {
+    type Error = ParseError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let bytes = data
+            .get(..TOKEN_BYTES)
+            .ok_or(ParseError::InsufficientLength)?
+            .try_into()
+            .map_err(|_| ParseError::InsufficientLength)?;
+        Ok(Token(u64::from_be_bytes(bytes)))
+    }
+}
+
+/// Represents the set of protocol extensions supported by a node.
+///
+/// Nodes exchange their capabilities when pinging each other, and
+/// should only use an extension with a node if that node supports it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// No extensions are supported.
+    pub const NONE: Capabilities = Capabilities(0);
+    /// The node understands `Error` responses.
+    pub const ERROR_RESPONSES: Capabilities = Capabilities(1);
+    // Bit 1 is reserved, having been set aside for values larger than 255 bytes
+    /// The node understands signed records.
+    pub const SIGNATURES: Capabilities = Capabilities(1 << 2);
+    // Bit 3 is reserved, having been set aside for values with a time to live
+    /// The node keeps track of which nodes provide each key.
+    pub const PROVIDERS: Capabilities = Capabilities(1 << 4);
+    /// The node keeps tombstones for deleted values.
+    pub const DELETES: Capabilities = Capabilities(1 << 5);
+    /// The node accepts copies of values published by other nodes.
+    pub const REPLICATION: Capabilities = Capabilities(1 << 6);
+    /// The node can compare the values it holds with its neighbours.
+    pub const SYNC: Capabilities = Capabilities(1 << 7);
+
+    /// The extensions supported by this implementation.
+    pub fn ours() -> Self {
+        Capabilities::ERROR_RESPONSES
+            .with(Capabilities::SIGNATURES)
+            .with(Capabilities::PROVIDERS)
+            .with(Capabilities::DELETES)
+            .with(Capabilities::REPLICATION)
+            .with(Capabilities::SYNC)
+    }
+
+    /// Check whether or not every extension in another set is supported.
+    pub fn supports(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Combine two sets of extensions together.
+    pub fn with(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl TryFrom<&[u8]> for Capabilities {
+    type Error = ParseError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let bytes = data
+            .get(..CAPABILITIES_BYTES)
+            .ok_or(ParseError::InsufficientLength)?
+            .try_into()
+            .map_err(|_| ParseError::InsufficientLength)?;
+        Ok(Capabilities(u32::from_be_bytes(bytes)))
+    }
+}
+
+/// Represents the reason a node gave for failing to handle an RPC call.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ErrorCode {
+    /// The value was too large for the node to store
+    ValueTooLarge,
+    /// The node has received too many messages from us recently
+    RateLimited,
+    /// The write token included in a Store call was invalid
+    BadToken,
+    /// The node doesn't know how to handle this kind of message
+    UnsupportedMessage,
+    /// The node has run out of space to store values
+    StorageFull,
+    /// The signature on a mutable record or a delete was invalid
+    InvalidSignature,
+    /// The node already has a version of the record with an equal or higher sequence number
+    StaleRecord,
+    /// The key wasn't at the version a conditional store expected
+    VersionMismatch,
+    /// The publisher deleted this version of the value, or a later one
+    Deleted,
+    /// The version was too far ahead of the clock of the node
+    VersionTooHigh,
+    /// An error code we don't know about, from a newer version of the protocol
+    Other(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::ValueTooLarge,
+            2 => ErrorCode::RateLimited,
+            3 => ErrorCode::BadToken,
+            4 => ErrorCode::UnsupportedMessage,
+            5 => ErrorCode::StorageFull,
+            6 => ErrorCode::InvalidSignature,
+            7 => ErrorCode::StaleRecord,
+            8 => ErrorCode::VersionMismatch,
+            9 => ErrorCode::Deleted,
+            10 => ErrorCode::VersionTooHigh,
+            other => ErrorCode::Other(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::ValueTooLarge => 1,
+            ErrorCode::RateLimited => 2,
+            ErrorCode::BadToken => 3,
+            ErrorCode::UnsupportedMessage => 4,
+            ErrorCode::StorageFull => 5,
+            ErrorCode::InvalidSignature => 6,
+            ErrorCode::StaleRecord => 7,
+            ErrorCode::VersionMismatch => 8,
+            ErrorCode::Deleted => 9,
+            ErrorCode::VersionTooHigh => 10,
+            ErrorCode::Other(other) => other,
+        }
+    }
+}
+
 /// Represents the Header included with every RPC message.
 ///
 /// This contains information about the node that sent the message, as well
@@ -108,27 +757,32 @@ impl Distribution<TransactionID> for Standard {
 /// is unique when this message is a call, and matches the request when
 /// this message is a response
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Header {
     /// The ID for the node that is sending this message
     pub node_id: BitKey,
     /// A transaction ID identifying this RPC call
     pub transaction_id: TransactionID,
+    /// The version of the protocol used by the sender
+    pub version: u8,
 }
 
 impl TryFrom<&[u8]> for Header {
     type Error = ParseError;
 
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
-        if data.len() < std::mem::size_of::<Header>() {
+        if data.len() < HEADER_BYTES {
             return Err(ParseError::InsufficientLength);
         }
         let (start, rest) = data.split_at(std::mem::size_of::<BitKey>());
-        // We know that the length is sufficient in both cases
+        // We know that the length is sufficient in every case
         let node_id = try_bitkey_from(start).unwrap();
         let transaction_id = rest.try_into().unwrap();
+        let version = data[HEADER_BYTES - 1];
         Ok(Header {
             node_id,
             transaction_id,
+            version,
         })
     }
 }
@@ -136,80 +790,657 @@ impl TryFrom<&[u8]> for Header {
 /// Represents the data differing between RPC messages.
 ///
 /// This contains branches for both RPC requests, and RPC responses.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum RPCPayload {
     /// Request a Ping response from a node.
     ///
-    /// This is mainly used to check whether or not a node is still alive.
-    Ping,
-    /// Respond to a ping request from a node.
-    PingResp,
-    /// Ask for the value bound to a given key
-    FindValue(String),
-    /// Respond with the value for the key requested
-    FindValueResp(String),
+    /// This is mainly used to check whether or not a node is still alive,
+    /// but also lets us tell the node which extensions we support.
+    Ping(Capabilities),
+    /// Respond to a ping request from a node, with the extensions we support.
+    PingResp(Capabilities),
+    /// Ask for the values bound to a given key, starting at a given index
+    FindValue(String, u8),
+    /// Respond with a page of the values for the key requested, and a token for storing
+    FindValueResp(ValuePage, Token),
     /// Respond with up to K of the closest nodes we know of to the requested key
     ///
-    /// This will get returned instead of `FindValuesResp` unless we've received
+    /// This will get returned instead of `FindValueResp` unless we've received
     /// a `Store` call directly.
-    FindValueNodes(Vec<Node>),
+    FindValueNodes(Vec<Node>, Token),
     /// Try and find the K closest nodes to a given key
     FindNode(BitKey),
-    /// Respond with up to K of the closest nodes to the requested key
-    FindNodeResp(Vec<Node>),
-    /// Store a `(key, value)` pair in a given node
-    Store(String, String),
+    /// Respond with up to K of the closest nodes to the requested key, and a token for storing
+    FindNodeResp(Vec<Node>, Token),
+    /// Store a `(key, value)` pair at some version in a given node, echoing the token it gave us
+    ///
+    /// The store is signed with the key pair our ID comes from, so nobody else
+    /// can store values in our name.
+    Store(String, Vec<u8>, u64, StoreSignature, Token),
     /// Respond to a `Store` request, confirming that it happened
     StoreResp,
+    /// Respond to any request, explaining why it couldn't be handled
+    ///
+    /// This lets the requester give up immediately, instead of waiting
+    /// for the request to time out.
+    Error(ErrorCode, String),
+    /// Store a signed record in a given node, echoing the token it gave us
+    StoreRecord(MutableRecord, Token),
+    /// Ask for the signed record stored under a given key
+    FindRecord(BitKey),
+    /// Respond with the record requested, closer nodes, and a token for storing
+    ///
+    /// If we don't have the record, we respond with `FindValueNodes` instead.
+    FindRecordResp(MutableRecord, Vec<Node>, Token),
+    /// Announce that we provide the content for a key, echoing the token the node gave us
+    ///
+    /// The node responds with `StoreResp` once it's recorded us as a provider.
+    AddProvider(BitKey, Token),
+    /// Ask for the nodes providing the content for a key
+    GetProviders(BitKey),
+    /// Respond with the providers for the key requested, closer nodes, and a token for storing
+    GetProvidersResp(Vec<Node>, Vec<Node>, Token),
+    /// Store a `(key, value)` pair, if the key is still at the version we expect
+    ///
+    /// The value replaces every value held by the key, and is given the next version,
+    /// which the signature covers. If the key is at another version, the node
+    /// responds with an `Error` instead.
+    StoreIf(String, Vec<u8>, u64, StoreSignature, Token),
+    /// Delete the values we stored under a key, up to and including some version
+    ///
+    /// The delete is signed with the key pair our ID comes from, so nobody else can
+    /// delete our values. The node keeps a tombstone for a while, so that the deleted
+    /// values can't be stored again. It responds with `StoreResp` once the tombstone
+    /// is in place.
+    Delete(String, u64, DeleteSignature, Token),
+    /// Store a copy of a value some other node published, to repair a replica missing it
+    ///
+    /// The node responds with `StoreResp`, or with an `Error` if a `Store` from
+    /// the publisher would have been refused.
+    Replicate(String, PublishedValue, Token),
+    /// Ask for a summary of the values held under keys in an inclusive range
+    SyncHashes(BitKey, BitKey),
+    /// Respond with a hash of the values held in each slice of the range requested
+    SyncHashesResp(Vec<u64>),
+    /// Ask for every value and tombstone held under keys in an inclusive range
+    SyncPull(BitKey, BitKey),
+    /// Respond with the values and tombstones held in the range requested, sorted by key
+    ///
+    /// The flag is set if some of the entries didn't fit in this response.
+    SyncEntries(Vec<(String, SyncEntry)>, bool),
+}
+
+impl RPCPayload {
+    fn encoded_len(&self) -> usize {
+        use RPCPayload::*;
+        match self {
+            Ping(_) | PingResp(_) => 1 + CAPABILITIES_BYTES,
+            FindNode(_) => 1 + BITKEY_BYTES,
+            FindNodeResp(nodes, _) | FindValueNodes(nodes, _) => {
+                1 + TOKEN_BYTES + 1 + nodes.iter().map(encoded_node_len).sum::<usize>()
+            }
+            Store(key, val, _, _, _) | StoreIf(key, val, _, _, _) => {
+                1 + TOKEN_BYTES + 8 + STORE_SIGNATURE_BYTES + 1 + key.len() + 1 + val.len()
+            }
+            StoreResp => 1,
+            Delete(key, _, _, _) => 1 + TOKEN_BYTES + 8 + DELETE_SIGNATURE_BYTES + 1 + key.len(),
+            Replicate(key, value, _) => 1 + TOKEN_BYTES + 1 + key.len() + encoded_value_len(value),
+            SyncHashes(_, _) | SyncPull(_, _) => 1 + 2 * BITKEY_BYTES,
+            SyncHashesResp(hashes) => 1 + 1 + 8 * hashes.len(),
+            SyncEntries(entries, _) => {
+                let entries_len = entries.iter().map(encoded_entry_len).sum::<usize>();
+                1 + 1 + 1 + entries_len
+            }
+            FindValue(key, _) => 1 + 1 + key.len() + 1,
+            FindValueResp(page, _) => {
+                let values_len = page.values.iter().map(encoded_value_len).sum::<usize>();
+                1 + TOKEN_BYTES + 2 + 1 + values_len
+            }
+            Error(_, reason) => 2 + 1 + reason.len(),
+            StoreRecord(record, _) => 1 + TOKEN_BYTES + encoded_record_len(record),
+            FindRecord(_) => 1 + BITKEY_BYTES,
+            FindRecordResp(record, nodes, _) => {
+                let nodes_len = 1 + nodes.iter().map(encoded_node_len).sum::<usize>();
+                1 + TOKEN_BYTES + encoded_record_len(record) + nodes_len
+            }
+            AddProvider(_, _) => 1 + TOKEN_BYTES + BITKEY_BYTES,
+            GetProviders(_) => 1 + BITKEY_BYTES,
+            GetProvidersResp(providers, nodes, _) => {
+                let providers_len = 1 + providers.iter().map(encoded_node_len).sum::<usize>();
+                let nodes_len = 1 + nodes.iter().map(encoded_node_len).sum::<usize>();
+                1 + TOKEN_BYTES + providers_len + nodes_len
+            }
+        }
+    }
+
+    // Check that every length prefix in this payload fits in a byte
+    fn check_lengths(&self) -> Result<(), EncodeError> {
+        use RPCPayload::*;
+        if let FindValueResp(page, _) = self {
+            if page.values.iter().any(|v| v.value.len() > MAX_PREFIXED_LEN) {
+                return Err(EncodeError::StringTooLong);
+            }
+            if page.values.len() > MAX_PREFIXED_LEN {
+                return Err(EncodeError::TooManyValues);
+            }
+            return Ok(());
+        }
+        if let SyncEntries(entries, _) = self {
+            let too_long = |(key, entry): &(String, SyncEntry)| match entry {
+                SyncEntry::Value(value) => {
+                    key.len() > MAX_PREFIXED_LEN || value.value.len() > MAX_PREFIXED_LEN
+                }
+                SyncEntry::Tombstone(_, _) => key.len() > MAX_PREFIXED_LEN,
+            };
+            if entries.iter().any(too_long) {
+                return Err(EncodeError::StringTooLong);
+            }
+            if entries.len() > MAX_PREFIXED_LEN {
+                return Err(EncodeError::TooManyValues);
+            }
+            return Ok(());
+        }
+        if let SyncHashesResp(hashes) = self {
+            if hashes.len() > MAX_PREFIXED_LEN {
+                return Err(EncodeError::TooManyValues);
+            }
+            return Ok(());
+        }
+        // Only the longest string or value matters, since they all share the same limit
+        let (longest, nodes) = match self {
+            FindNodeResp(nodes, _) | FindValueNodes(nodes, _) => (0, nodes.len()),
+            Store(key, val, _, _, _) | StoreIf(key, val, _, _, _) => (key.len().max(val.len()), 0),
+            FindValue(key, _) | Error(_, key) | Delete(key, _, _, _) => (key.len(), 0),
+            Replicate(key, value, _) => (key.len().max(value.value.len()), 0),
+            SyncHashes(_, _) | SyncPull(_, _) => (0, 0),
+            SyncHashesResp(_) | SyncEntries(_, _) => (0, 0),
+            FindValueResp(_, _) => (0, 0),
+            StoreRecord(record, _) => (record.salt.len().max(record.value.len()), 0),
+            FindRecordResp(record, nodes, _) => {
+                (record.salt.len().max(record.value.len()), nodes.len())
+            }
+            GetProvidersResp(providers, nodes, _) => (0, providers.len().max(nodes.len())),
+            Ping(_) | PingResp(_) | FindNode(_) | FindRecord(_) | StoreResp => (0, 0),
+            AddProvider(_, _) | GetProviders(_) => (0, 0),
+        };
+        if longest > MAX_PREFIXED_LEN {
+            return Err(EncodeError::StringTooLong);
+        }
+        if nodes > MAX_PREFIXED_LEN {
+            return Err(EncodeError::TooManyNodes);
+        }
+        Ok(())
+    }
+
+    // This returns the number of bytes written, and assumes the buffer is large enough
+    fn write(self, buf: &mut [u8]) -> usize {
+        use RPCPayload::*;
+        match self {
+            Ping(capabilities) => {
+                buf[0] = 1;
+                write_capabilities(capabilities, &mut buf[1..]);
+                1 + CAPABILITIES_BYTES
+            }
+            PingResp(capabilities) => {
+                buf[0] = 2;
+                write_capabilities(capabilities, &mut buf[1..]);
+                1 + CAPABILITIES_BYTES
+            }
+            FindNode(id) => {
+                buf[0] = 3;
+                write_bitkey(id, &mut buf[1..]);
+                1 + BITKEY_BYTES
+            }
+            FindNodeResp(nodes, token) => {
+                buf[0] = 4;
+                write_token(token, &mut buf[1..]);
+                let len = write_nodes(nodes, &mut buf[9..]);
+                len + 9
+            }
+            Store(key, val, version, signature, token) => {
+                buf[0] = 5;
+                write_token(token, &mut buf[1..]);
+                buf[9..17].copy_from_slice(&version.to_be_bytes());
+                let count = 17 + write_store_signature(signature, &mut buf[17..]);
+                let key_len = write_string(key, &mut buf[count..]);
+                let val_len = write_bytes(&val, &mut buf[count + key_len..]);
+                key_len + val_len + count
+            }
+            StoreResp => {
+                buf[0] = 6;
+                1
+            }
+            FindValue(key, start) => {
+                buf[0] = 7;
+                let len = write_string(key, &mut buf[1..]);
+                buf[1 + len] = start;
+                len + 2
+            }
+            FindValueNodes(nodes, token) => {
+                buf[0] = 8;
+                write_token(token, &mut buf[1..]);
+                let len = write_nodes(nodes, &mut buf[9..]);
+                len + 9
+            }
+            FindValueResp(page, token) => {
+                buf[0] = 9;
+                write_token(token, &mut buf[1..]);
+                buf[9] = page.start;
+                buf[10] = page.total;
+                let len = write_values(page.values, &mut buf[11..]);
+                len + 11
+            }
+            Error(code, reason) => {
+                buf[0] = 10;
+                buf[1] = code.into();
+                let len = write_string(reason, &mut buf[2..]);
+                len + 2
+            }
+            StoreRecord(record, token) => {
+                buf[0] = 11;
+                write_token(token, &mut buf[1..]);
+                let len = write_record(record, &mut buf[9..]);
+                len + 9
+            }
+            FindRecord(key) => {
+                buf[0] = 12;
+                write_bitkey(key, &mut buf[1..]);
+                1 + BITKEY_BYTES
+            }
+            FindRecordResp(record, nodes, token) => {
+                buf[0] = 13;
+                write_token(token, &mut buf[1..]);
+                let record_len = write_record(record, &mut buf[9..]);
+                let nodes_len = write_nodes(nodes, &mut buf[9 + record_len..]);
+                record_len + nodes_len + 9
+            }
+            AddProvider(key, token) => {
+                buf[0] = 14;
+                write_token(token, &mut buf[1..]);
+                write_bitkey(key, &mut buf[9..]);
+                9 + BITKEY_BYTES
+            }
+            GetProviders(key) => {
+                buf[0] = 15;
+                write_bitkey(key, &mut buf[1..]);
+                1 + BITKEY_BYTES
+            }
+            GetProvidersResp(providers, nodes, token) => {
+                buf[0] = 16;
+                write_token(token, &mut buf[1..]);
+                let providers_len = write_nodes(providers, &mut buf[9..]);
+                let nodes_len = write_nodes(nodes, &mut buf[9 + providers_len..]);
+                providers_len + nodes_len + 9
+            }
+            StoreIf(key, val, expected, signature, token) => {
+                buf[0] = 17;
+                write_token(token, &mut buf[1..]);
+                buf[9..17].copy_from_slice(&expected.to_be_bytes());
+                let count = 17 + write_store_signature(signature, &mut buf[17..]);
+                let key_len = write_string(key, &mut buf[count..]);
+                let val_len = write_bytes(&val, &mut buf[count + key_len..]);
+                key_len + val_len + count
+            }
+            Delete(key, version, signature, token) => {
+                buf[0] = 18;
+                write_token(token, &mut buf[1..]);
+                buf[9..17].copy_from_slice(&version.to_be_bytes());
+                let count = 17 + write_delete_signature(signature, &mut buf[17..]);
+                let len = write_string(key, &mut buf[count..]);
+                len + count
+            }
+            Replicate(key, value, token) => {
+                buf[0] = 19;
+                write_token(token, &mut buf[1..]);
+                let key_len = write_string(key, &mut buf[9..]);
+                let value_len = write_value(value, &mut buf[9 + key_len..]);
+                key_len + value_len + 9
+            }
+            SyncHashes(lo, hi) => {
+                buf[0] = 20;
+                write_bitkey(lo, &mut buf[1..]);
+                write_bitkey(hi, &mut buf[1 + BITKEY_BYTES..]);
+                1 + 2 * BITKEY_BYTES
+            }
+            SyncHashesResp(hashes) => {
+                buf[0] = 21;
+                buf[1] = hashes.len() as u8;
+                for (i, hash) in hashes.iter().enumerate() {
+                    buf[2 + 8 * i..10 + 8 * i].copy_from_slice(&hash.to_be_bytes());
+                }
+                2 + 8 * hashes.len()
+            }
+            SyncPull(lo, hi) => {
+                buf[0] = 22;
+                write_bitkey(lo, &mut buf[1..]);
+                write_bitkey(hi, &mut buf[1 + BITKEY_BYTES..]);
+                1 + 2 * BITKEY_BYTES
+            }
+            SyncEntries(entries, more) => {
+                buf[0] = 23;
+                buf[1] = more as u8;
+                buf[2] = entries.len() as u8;
+                let mut count = 3;
+                for (key, entry) in entries {
+                    count += write_string(key, &mut buf[count..]);
+                    count += write_entry(entry, &mut buf[count..]);
+                }
+                count
+            }
+        }
+    }
+
+    /// Check whether or not this payload is a response to an RPC call.
+    pub fn is_response(&self) -> bool {
+        use RPCPayload::*;
+        match self {
+            Ping(_) | FindValue(_, _) | FindNode(_) | Store(_, _, _, _, _) => false,
+            StoreIf(_, _, _, _, _) | Delete(_, _, _, _) | Replicate(_, _, _) => false,
+            SyncHashes(_, _) | SyncPull(_, _) => false,
+            SyncHashesResp(_) | SyncEntries(_, _) => true,
+            StoreRecord(_, _) | FindRecord(_) | AddProvider(_, _) | GetProviders(_) => false,
+            PingResp(_) | FindValueResp(_, _) | FindValueNodes(_, _) | FindNodeResp(_, _) => true,
+            StoreResp | Error(_, _) | FindRecordResp(_, _, _) | GetProvidersResp(_, _, _) => true,
+        }
+    }
+}
+
+/// Represents the payload of a message, borrowed from the buffer it was received in.
+///
+/// This mirrors [RPCPayload](enum.RPCPayload.html), but avoids allocating
+/// any strings or lists of nodes. An owned payload can be created from this one
+/// once we know we need to hold onto it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RPCPayloadRef<'a> {
+    Ping(Capabilities),
+    PingResp(Capabilities),
+    FindValue(&'a str, u8),
+    FindValueResp(ValuePageRef<'a>, Token),
+    FindValueNodes(NodesRef<'a>, Token),
+    FindNode(BitKey),
+    FindNodeResp(NodesRef<'a>, Token),
+    Store(&'a str, &'a [u8], u64, StoreSignature, Token),
+    StoreResp,
+    Error(ErrorCode, &'a str),
+    StoreRecord(MutableRecordRef<'a>, Token),
+    FindRecord(BitKey),
+    FindRecordResp(MutableRecordRef<'a>, NodesRef<'a>, Token),
+    AddProvider(BitKey, Token),
+    GetProviders(BitKey),
+    GetProvidersResp(NodesRef<'a>, NodesRef<'a>, Token),
+    StoreIf(&'a str, &'a [u8], u64, StoreSignature, Token),
+    Delete(&'a str, u64, DeleteSignature, Token),
+    Replicate(&'a str, PublishedValueRef<'a>, Token),
+    SyncHashes(BitKey, BitKey),
+    SyncHashesResp(HashesRef<'a>),
+    SyncPull(BitKey, BitKey),
+    SyncEntries(EntriesRef<'a>, bool),
+}
+
+impl<'a> RPCPayloadRef<'a> {
+    /// Check whether or not this payload is a response to an RPC call.
+    pub fn is_response(&self) -> bool {
+        use RPCPayloadRef::*;
+        match self {
+            Ping(_) | FindValue(_, _) | FindNode(_) | Store(_, _, _, _, _) => false,
+            StoreIf(_, _, _, _, _) | Delete(_, _, _, _) | Replicate(_, _, _) => false,
+            SyncHashes(_, _) | SyncPull(_, _) => false,
+            SyncHashesResp(_) | SyncEntries(_, _) => true,
+            StoreRecord(_, _) | FindRecord(_) | AddProvider(_, _) | GetProviders(_) => false,
+            PingResp(_) | FindValueResp(_, _) | FindValueNodes(_, _) | FindNodeResp(_, _) => true,
+            StoreResp | Error(_, _) | FindRecordResp(_, _, _) | GetProvidersResp(_, _, _) => true,
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for RPCPayloadRef<'a> {
+    type Error = ParseError;
+
+    fn try_from(data: &'a [u8]) -> Result<Self, ParseError> {
+        use RPCPayloadRef::*;
+        let (msg_type, rest) = data.split_first().ok_or(ParseError::InsufficientLength)?;
+        match msg_type {
+            1 => Ok(Ping(rest.try_into()?)),
+            2 => Ok(PingResp(rest.try_into()?)),
+            3 => {
+                let id = try_bitkey_from(rest)?;
+                Ok(FindNode(id))
+            }
+            4 => {
+                let token = rest.try_into()?;
+                let nodes = rest[TOKEN_BYTES..].try_into()?;
+                Ok(FindNodeResp(nodes, token))
+            }
+            5 => {
+                let (key, val, version, signature, token) = try_store_from(rest)?;
+                Ok(Store(key, val, version, signature, token))
+            }
+            6 => Ok(StoreResp),
+            7 => {
+                let (key, read_count) = try_str_from(rest)?;
+                let start = *rest.get(read_count).ok_or(ParseError::InsufficientLength)?;
+                Ok(FindValue(key, start))
+            }
+            8 => {
+                let token = rest.try_into()?;
+                let nodes = rest[TOKEN_BYTES..].try_into()?;
+                Ok(FindValueNodes(nodes, token))
+            }
+            9 => {
+                let token = rest.try_into()?;
+                let rest = &rest[TOKEN_BYTES..];
+                if rest.len() < 2 {
+                    return Err(ParseError::InsufficientLength);
+                }
+                let (values, _) = try_values_from(&rest[2..])?;
+                let page = ValuePageRef {
+                    start: rest[0],
+                    total: rest[1],
+                    values,
+                };
+                Ok(FindValueResp(page, token))
+            }
+            10 => {
+                let (code, rest) = rest.split_first().ok_or(ParseError::InsufficientLength)?;
+                let (reason, _) = try_str_from(rest)?;
+                Ok(Error((*code).into(), reason))
+            }
+            11 => {
+                let token = rest.try_into()?;
+                let (record, _) = try_record_from(&rest[TOKEN_BYTES..])?;
+                Ok(StoreRecord(record, token))
+            }
+            12 => {
+                let key = try_bitkey_from(rest)?;
+                Ok(FindRecord(key))
+            }
+            13 => {
+                let token = rest.try_into()?;
+                let rest = &rest[TOKEN_BYTES..];
+                let (record, read_count) = try_record_from(rest)?;
+                let nodes = rest[read_count..].try_into()?;
+                Ok(FindRecordResp(record, nodes, token))
+            }
+            14 => {
+                let token = rest.try_into()?;
+                let key = try_bitkey_from(&rest[TOKEN_BYTES..])?;
+                Ok(AddProvider(key, token))
+            }
+            15 => {
+                let key = try_bitkey_from(rest)?;
+                Ok(GetProviders(key))
+            }
+            16 => {
+                let token = rest.try_into()?;
+                let rest = &rest[TOKEN_BYTES..];
+                let (providers, read_count) = try_nodes_from(rest)?;
+                let nodes = rest[read_count..].try_into()?;
+                Ok(GetProvidersResp(providers, nodes, token))
+            }
+            17 => {
+                let (key, val, expected, signature, token) = try_store_from(rest)?;
+                Ok(StoreIf(key, val, expected, signature, token))
+            }
+            18 => {
+                let token = rest.try_into()?;
+                let version = try_u64_from(&rest[TOKEN_BYTES..])?;
+                let rest = &rest[TOKEN_BYTES + 8..];
+                let signature = try_delete_signature_from(rest)?;
+                let (key, _) = try_str_from(&rest[DELETE_SIGNATURE_BYTES..])?;
+                Ok(Delete(key, version, signature, token))
+            }
+            19 => {
+                let token = rest.try_into()?;
+                let rest = &rest[TOKEN_BYTES..];
+                let (key, read_count) = try_str_from(rest)?;
+                let (value, _) = try_value_from(&rest[read_count..])?;
+                Ok(Replicate(key, value, token))
+            }
+            20 | 22 => {
+                let lo = try_bitkey_from(rest)?;
+                let hi = try_bitkey_from(&rest[BITKEY_BYTES..])?;
+                match msg_type {
+                    20 => Ok(SyncHashes(lo, hi)),
+                    _ => Ok(SyncPull(lo, hi)),
+                }
+            }
+            21 => Ok(SyncHashesResp(try_hashes_from(rest)?)),
+            23 => {
+                let (more, rest) = rest.split_first().ok_or(ParseError::InsufficientLength)?;
+                let entries = try_entries_from(rest)?;
+                Ok(SyncEntries(entries, *more != 0))
+            }
+            _ => Err(ParseError::UnknownMessageType),
+        }
+    }
 }
 
-impl TryFrom<&[u8]> for RPCPayload {
-    type Error = ParseError;
-
-    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
-        let (msg_type, rest) = data.split_first().ok_or(ParseError::InsufficientLength)?;
-        match msg_type {
-            1 => Ok(RPCPayload::Ping),
-            2 => Ok(RPCPayload::PingResp),
-            3 => {
-                let id = try_bitkey_from(rest)?;
-                Ok(RPCPayload::FindNode(id))
+impl<'a> From<RPCPayloadRef<'a>> for RPCPayload {
+    fn from(payload: RPCPayloadRef<'a>) -> Self {
+        use RPCPayloadRef::*;
+        match payload {
+            Ping(capabilities) => RPCPayload::Ping(capabilities),
+            PingResp(capabilities) => RPCPayload::PingResp(capabilities),
+            FindValue(key, start) => RPCPayload::FindValue(key.into(), start),
+            FindValueResp(page, token) => RPCPayload::FindValueResp(page.into(), token),
+            FindValueNodes(nodes, token) => RPCPayload::FindValueNodes(nodes.collect(), token),
+            FindNode(id) => RPCPayload::FindNode(id),
+            FindNodeResp(nodes, token) => RPCPayload::FindNodeResp(nodes.collect(), token),
+            Store(key, val, version, signature, token) => {
+                RPCPayload::Store(key.into(), val.into(), version, signature, token)
             }
-            4 => {
-                let nodes = try_nodes_from(rest)?;
-                Ok(RPCPayload::FindNodeResp(nodes))
+            StoreResp => RPCPayload::StoreResp,
+            Error(code, reason) => RPCPayload::Error(code, reason.into()),
+            StoreRecord(record, token) => RPCPayload::StoreRecord(record.into(), token),
+            FindRecord(key) => RPCPayload::FindRecord(key),
+            FindRecordResp(record, nodes, token) => {
+                RPCPayload::FindRecordResp(record.into(), nodes.collect(), token)
             }
-            5 => {
-                let (key, read_count) = try_string_from(rest)?;
-                let rest = &rest[read_count..];
-                let (val, _) = try_string_from(rest)?;
-                Ok(RPCPayload::Store(key, val))
+            AddProvider(key, token) => RPCPayload::AddProvider(key, token),
+            GetProviders(key) => RPCPayload::GetProviders(key),
+            GetProvidersResp(providers, nodes, token) => {
+                RPCPayload::GetProvidersResp(providers.collect(), nodes.collect(), token)
             }
-            6 => Ok(RPCPayload::StoreResp),
-            7 => {
-                let (key, _) = try_string_from(rest)?;
-                Ok(RPCPayload::FindValue(key))
+            StoreIf(key, val, expected, signature, token) => {
+                RPCPayload::StoreIf(key.into(), val.into(), expected, signature, token)
             }
-            8 => {
-                let nodes = try_nodes_from(rest)?;
-                Ok(RPCPayload::FindValueNodes(nodes))
+            Delete(key, version, signature, token) => {
+                RPCPayload::Delete(key.into(), version, signature, token)
             }
-            9 => {
-                let (val, _) = try_string_from(rest)?;
-                Ok(RPCPayload::FindValueResp(val))
+            Replicate(key, value, token) => RPCPayload::Replicate(key.into(), value.into(), token),
+            SyncHashes(lo, hi) => RPCPayload::SyncHashes(lo, hi),
+            SyncHashesResp(hashes) => RPCPayload::SyncHashesResp(hashes.collect()),
+            SyncPull(lo, hi) => RPCPayload::SyncPull(lo, hi),
+            SyncEntries(entries, more) => {
+                let entries = entries.map(|(key, entry)| (key.into(), entry.into()));
+                RPCPayload::SyncEntries(entries.collect(), more)
             }
-            _ => Err(ParseError::UnknownMessageType),
         }
     }
 }
 
+impl TryFrom<&[u8]> for RPCPayload {
+    type Error = ParseError;
+
+    fn try_from(data: &[u8]) -> Result<Self, ParseError> {
+        RPCPayloadRef::try_from(data).map(RPCPayload::from)
+    }
+}
+
+/// Represents a message borrowed from the buffer it was received in.
+///
+/// Creating this view only checks that there's enough room for a header.
+/// The header and payload are only parsed when asked for, which lets us
+/// throw away messages we aren't interested in without decoding all of them.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageRef<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parse out the header of this message.
+    pub fn header(&self) -> Header {
+        // We checked that there was room for a header when creating this view
+        Header::try_from(self.data).unwrap()
+    }
+
+    /// Parse out the payload of this message, without copying anything.
+    pub fn payload(&self) -> Result<RPCPayloadRef<'a>, ParseError> {
+        RPCPayloadRef::try_from(&self.data[HEADER_BYTES..])
+    }
+
+    /// Parse out an owned message, allocating as necessary.
+    pub fn to_message(&self) -> Result<Message, ParseError> {
+        let payload = self.payload()?.into();
+        Ok(Message {
+            header: self.header(),
+            payload,
+        })
+    }
+
+    /// View a message, checking that it belongs to our network.
+    ///
+    /// On a private network, this will reject any message that wasn't tagged with
+    /// the key for our network, before even looking at the contents of the message.
+    /// On an open network, with no key, messages carry no tag.
+    pub fn parse(data: &'a [u8], network: Option<&NetworkKey>) -> Result<Self, ParseError> {
+        let key = match network {
+            None => return Self::untagged(data),
+            Some(key) => key,
+        };
+        if data.len() < NETWORK_TAG_BYTES {
+            return Err(ParseError::InsufficientLength);
+        }
+        let (data, tag) = data.split_at(data.len() - NETWORK_TAG_BYTES);
+        key.mac(data)
+            .verify_slice(tag)
+            .map_err(|_| ParseError::WrongNetwork)?;
+        Self::untagged(data)
+    }
+
+    // View a message without a network tag
+    fn untagged(data: &'a [u8]) -> Result<Self, ParseError> {
+        if data.len() < HEADER_BYTES {
+            return Err(ParseError::InsufficientLength);
+        }
+        // Payload layouts differ between versions, so we can't read other versions safely
+        let version = data[HEADER_BYTES - 1];
+        if version != PROTOCOL_VERSION {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+        Ok(MessageRef { data })
+    }
+}
+
 /// Represents an RPC message sent between two nodes.
 ///
 /// Every header contains a header, giving us information about
 /// the sender, as well as identifying the RPC call, allowing us
 /// to link responses with requests. After the header, we have
 /// a payload identifying the specific kind of request we're dealing with.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Message {
     /// This contains general metadata about this message
     pub header: Header,
@@ -230,8 +1461,9 @@ impl Message {
         let header = Header {
             transaction_id,
             node_id: this_node_id,
+            version: PROTOCOL_VERSION,
         };
-        Self::response(header, payload)
+        Message { header, payload }
     }
 
     /// Create a new message, including our own node_id, a payload, and matching a transaction ID.
@@ -242,72 +1474,102 @@ impl Message {
     /// a fresh one.
     /// This can be done with
     /// [create](struct.Message.html#method.create).
-    pub fn response(header: Header, payload: RPCPayload) -> Self {
+    pub fn response(this_node_id: BitKey, request: Header, payload: RPCPayload) -> Self {
+        let header = Header {
+            node_id: this_node_id,
+            transaction_id: request.transaction_id,
+            version: PROTOCOL_VERSION,
+        };
         Message { header, payload }
     }
 
-    /// Serialize a message to a buffer, returning the number of bytes written.
-    pub fn write(self, buf: &mut [u8]) -> usize {
-        use RPCPayload::*;
-        write_bitkey(self.header.node_id, buf);
-        write_transaction_id(self.header.transaction_id, &mut buf[BITKEY_BYTES..]);
-        match self.payload {
-            Ping => {
-                buf[24] = 1;
-                25
-            }
-            PingResp => {
-                buf[24] = 2;
-                25
-            }
-            FindNode(id) => {
-                buf[24] = 3;
-                write_bitkey(id, &mut buf[25..]);
-                41
-            }
-            FindNodeResp(nodes) => {
-                buf[24] = 4;
-                let len = write_nodes(nodes, &mut buf[25..]);
-                len + 25
-            }
-            Store(key, val) => {
-                buf[24] = 5;
-                let key_len = write_string(key, &mut buf[25..]);
-                let val_len = write_string(val, &mut buf[25 + key_len..]);
-                key_len + val_len + 25
-            }
-            StoreResp => {
-                buf[24] = 6;
-                25
+    /// Calculate the number of bytes this message takes up once serialized.
+    ///
+    /// This doesn't include the tag added by
+    /// [write_tagged](struct.Message.html#method.write_tagged).
+    pub fn encoded_len(&self) -> usize {
+        HEADER_BYTES + self.payload.encoded_len()
+    }
+
+    /// Remove nodes from this message until it fits in a given number of bytes.
+    ///
+    /// This only affects messages containing a list of nodes. Since we send nodes
+    /// from closest to furthest, we drop the furthest nodes first. Other messages
+    /// are left untouched, even if they don't fit.
+    ///
+    /// Providers are only dropped once there are no closer nodes left to drop.
+    /// Values are dropped from the end of a page, leaving them for the next page.
+    pub fn trim_to(&mut self, size: usize) {
+        let overflow = self.encoded_len().saturating_sub(size);
+        match &mut self.payload {
+            RPCPayload::FindNodeResp(nodes, _)
+            | RPCPayload::FindValueNodes(nodes, _)
+            | RPCPayload::FindRecordResp(_, nodes, _) => {
+                pop_nodes(nodes, overflow);
             }
-            FindValue(key) => {
-                buf[24] = 7;
-                let len = write_string(key, &mut buf[25..]);
-                len + 25
+            RPCPayload::GetProvidersResp(providers, nodes, _) => {
+                let removed = pop_nodes(nodes, overflow);
+                pop_nodes(providers, overflow.saturating_sub(removed));
             }
-            FindValueNodes(nodes) => {
-                buf[24] = 8;
-                let len = write_nodes(nodes, &mut buf[25..]);
-                len + 25
+            RPCPayload::FindValueResp(page, _) => {
+                let mut removed = 0;
+                while removed < overflow {
+                    match page.values.pop() {
+                        Some(value) => removed += encoded_value_len(&value),
+                        None => break,
+                    }
+                }
             }
-            FindValueResp(val) => {
-                buf[24] = 9;
-                let len = write_string(val, &mut buf[25..]);
-                len + 25
+            RPCPayload::SyncEntries(entries, more) => {
+                let mut removed = 0;
+                while removed < overflow {
+                    match entries.pop() {
+                        Some(entry) => removed += encoded_entry_len(&entry),
+                        None => break,
+                    }
+                    *more = true;
+                }
             }
+            _ => {}
         }
     }
-}
 
-impl TryFrom<&[u8]> for Message {
-    type Error = ParseError;
+    /// Serialize a message to a buffer, returning the number of bytes written.
+    ///
+    /// This fails without writing anything if the buffer is too small, or if the message
+    /// contains strings or node lists too long to fit in the message format.
+    pub fn write(self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        self.payload.check_lengths()?;
+        if buf.len() < self.encoded_len() {
+            return Err(EncodeError::BufferTooSmall);
+        }
+        write_bitkey(self.header.node_id, buf);
+        write_transaction_id(self.header.transaction_id, &mut buf[BITKEY_BYTES..]);
+        buf[HEADER_BYTES - 1] = self.header.version;
+        Ok(HEADER_BYTES + self.payload.write(&mut buf[HEADER_BYTES..]))
+    }
 
-    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
-        let header = data.try_into()?;
-        // Indexing past this is safe, since we managed to parse the header
-        let data = &data[std::mem::size_of::<Header>()..];
-        let payload = data.try_into()?;
-        Ok(Message { header, payload })
+    /// Serialize a message for a private network, returning the number of bytes written.
+    ///
+    /// This works like [write](struct.Message.html#method.write), but appends
+    /// a tag using the key for our network.
+    pub fn write_tagged(self, key: &NetworkKey, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let room = buf
+            .len()
+            .checked_sub(NETWORK_TAG_BYTES)
+            .ok_or(EncodeError::BufferTooSmall)?;
+        let len = self.write(&mut buf[..room])?;
+        let tag = key.mac(&buf[..len]).finalize().into_bytes();
+        buf[len..len + NETWORK_TAG_BYTES].copy_from_slice(&tag);
+        Ok(len + NETWORK_TAG_BYTES)
+    }
+
+    /// Parse a message, checking that it belongs to our network.
+    ///
+    /// This works like [MessageRef::parse](struct.MessageRef.html#method.parse),
+    /// but copies the message out.
+    pub fn parse(data: &[u8], network: Option<&NetworkKey>) -> Result<Self, ParseError> {
+        MessageRef::parse(data, network)?.to_message()
     }
 }
 
@@ -327,17 +1589,111 @@ fn write_transaction_id(id: TransactionID, buf: &mut [u8]) {
     }
 }
 
+fn write_capabilities(capabilities: Capabilities, buf: &mut [u8]) {
+    buf[..CAPABILITIES_BYTES].copy_from_slice(&capabilities.0.to_be_bytes());
+}
+
+fn write_token(token: Token, buf: &mut [u8]) {
+    buf[..TOKEN_BYTES].copy_from_slice(&token.0.to_be_bytes());
+}
+
+fn encoded_record_len(record: &MutableRecord) -> usize {
+    PUBLIC_KEY_BYTES + 1 + record.salt.len() + 8 + 1 + record.value.len() + SIGNATURE_BYTES
+}
+
+fn write_store_signature(signature: StoreSignature, buf: &mut [u8]) -> usize {
+    buf[..PUBLIC_KEY_BYTES].copy_from_slice(&signature.public_key);
+    buf[PUBLIC_KEY_BYTES..STORE_SIGNATURE_BYTES].copy_from_slice(&signature.signature);
+    STORE_SIGNATURE_BYTES
+}
+
+fn write_delete_signature(signature: DeleteSignature, buf: &mut [u8]) -> usize {
+    buf[..PUBLIC_KEY_BYTES].copy_from_slice(&signature.public_key);
+    buf[PUBLIC_KEY_BYTES..DELETE_SIGNATURE_BYTES].copy_from_slice(&signature.signature);
+    DELETE_SIGNATURE_BYTES
+}
+
+fn write_record(record: MutableRecord, buf: &mut [u8]) -> usize {
+    buf[..PUBLIC_KEY_BYTES].copy_from_slice(&record.public_key);
+    let mut count = PUBLIC_KEY_BYTES;
+    count += write_string(record.salt, &mut buf[count..]);
+    buf[count..count + 8].copy_from_slice(&record.seq.to_be_bytes());
+    count += 8;
+    count += write_string(record.value, &mut buf[count..]);
+    buf[count..count + SIGNATURE_BYTES].copy_from_slice(&record.signature);
+    count + SIGNATURE_BYTES
+}
+
+// Remove nodes from the end of a list until at least some number of bytes are freed
+fn pop_nodes(nodes: &mut Vec<Node>, overflow: usize) -> usize {
+    let mut removed = 0;
+    while removed < overflow {
+        match nodes.pop() {
+            Some(node) => removed += encoded_node_len(&node),
+            None => break,
+        }
+    }
+    removed
+}
+
+fn encoded_value_len(value: &PublishedValue) -> usize {
+    8 + STORE_SIGNATURE_BYTES + 1 + value.value.len()
+}
+
+fn encoded_entry_len((key, entry): &(String, SyncEntry)) -> usize {
+    let entry_len = match entry {
+        SyncEntry::Value(value) => encoded_value_len(value),
+        SyncEntry::Tombstone(_, _) => 8 + DELETE_SIGNATURE_BYTES,
+    };
+    1 + key.len() + 1 + entry_len
+}
+
+fn write_entry(entry: SyncEntry, buf: &mut [u8]) -> usize {
+    match entry {
+        SyncEntry::Value(value) => {
+            buf[0] = 0;
+            1 + write_value(value, &mut buf[1..])
+        }
+        SyncEntry::Tombstone(version, signature) => {
+            buf[0] = 1;
+            buf[1..9].copy_from_slice(&version.to_be_bytes());
+            9 + write_delete_signature(signature, &mut buf[9..])
+        }
+    }
+}
+
+fn write_value(value: PublishedValue, buf: &mut [u8]) -> usize {
+    buf[..8].copy_from_slice(&value.version.to_be_bytes());
+    let count = 8 + write_store_signature(value.signature, &mut buf[8..]);
+    count + write_bytes(&value.value, &mut buf[count..])
+}
+
+fn write_values(values: Vec<PublishedValue>, buf: &mut [u8]) -> usize {
+    buf[0] = values.len() as u8;
+    let mut count = 1;
+    for value in values {
+        count += write_value(value, &mut buf[count..]);
+    }
+    count
+}
+
+fn encoded_node_len(node: &Node) -> usize {
+    let ip_len = if node.udp_addr.is_ipv4() { 4 } else { 16 };
+    BITKEY_BYTES + 1 + ip_len + 2
+}
+
 // This will only work with strings less than 256 bytes
-fn write_string(string: String, buf: &mut [u8]) -> usize {
-    let len = string.len();
+fn write_bytes(bytes: &[u8], buf: &mut [u8]) -> usize {
+    let len = bytes.len();
     buf[0] = len as u8;
-    let str_buf = &mut buf[1..];
-    for (i, b) in string.bytes().enumerate() {
-        str_buf[i] = b;
-    }
+    buf[1..=len].copy_from_slice(bytes);
     len + 1
 }
 
+fn write_string(string: String, buf: &mut [u8]) -> usize {
+    write_bytes(string.as_bytes(), buf)
+}
+
 fn write_nodes(nodes: Vec<Node>, mut buf: &mut [u8]) -> usize {
     buf[0] = nodes.len() as u8;
     let mut count = 1;
@@ -366,6 +1722,7 @@ fn write_nodes(nodes: Vec<Node>, mut buf: &mut [u8]) -> usize {
         let port = node.udp_addr.port();
         buf[0] = (port >> 8) as u8;
         buf[1] = port as u8;
+        buf = &mut buf[2..];
         count += written + 19;
     }
     count
@@ -374,44 +1731,69 @@ fn write_nodes(nodes: Vec<Node>, mut buf: &mut [u8]) -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     const HEADER: Header = Header {
         node_id: BitKey(0x102030405060708090A0B0C0D0E0F),
         transaction_id: TransactionID(0x0102030405060708),
+        version: PROTOCOL_VERSION,
+    };
+    const TOKEN: Token = Token(0x1112131415161718);
+    const STORE_SIGNATURE: StoreSignature = StoreSignature {
+        public_key: [1; PUBLIC_KEY_BYTES],
+        signature: [2; SIGNATURE_BYTES],
     };
     const PING_REQ_MSG: Message = Message {
         header: HEADER,
-        payload: RPCPayload::Ping,
+        payload: RPCPayload::Ping(Capabilities::ERROR_RESPONSES),
     };
-    const PING_REQ_BYTES: [u8; 25] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 1,
+    const PING_REQ_BYTES: [u8; 30] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 1, 0, 0,
+        0, 1,
     ];
     const PING_RESP_MSG: Message = Message {
         header: HEADER,
-        payload: RPCPayload::PingResp,
+        payload: RPCPayload::PingResp(Capabilities::ERROR_RESPONSES),
     };
-    const PING_RESP_BYTES: [u8; 25] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 2,
+    const PING_RESP_BYTES: [u8; 30] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 2, 0, 0,
+        0, 1,
     ];
     fn find_value_req_msg() -> Message {
         Message {
             header: HEADER,
-            payload: RPCPayload::FindValue(String::from("AAAA")),
+            payload: RPCPayload::FindValue(String::from("AAAA"), 3),
         }
     }
-    const FIND_VALUE_REQ_BYTES: [u8; 30] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 7, 4, 65, 65,
-        65, 65,
+    const FIND_VALUE_REQ_BYTES: [u8; 32] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 7, 4, 65,
+        65, 65, 65, 3,
     ];
     fn find_value_resp_msg() -> Message {
         Message {
             header: HEADER,
-            payload: RPCPayload::FindValueResp(String::from("AAAA")),
+            payload: RPCPayload::FindValueResp(find_value_page(), TOKEN),
         }
     }
-    const FIND_VALUE_RESP_BYTES: [u8; 30] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 9, 4, 65, 65,
-        65, 65,
+    fn find_value_page() -> ValuePage {
+        ValuePage {
+            start: 1,
+            total: 3,
+            values: vec![PublishedValue {
+                publisher: STORE_SIGNATURE.publisher(),
+                version: 2,
+                value: b"AAAA".to_vec(),
+                signature: STORE_SIGNATURE,
+            }],
+        }
+    }
+    const FIND_VALUE_RESP_BYTES: [u8; 146] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 9, 17, 18,
+        19, 20, 21, 22, 23, 24, 1, 3, 1, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 4, 65, 65, 65,
+        65,
     ];
     fn find_value_nodes_msg() -> Message {
         let nodes = vec![Node {
@@ -420,20 +1802,21 @@ mod tests {
         }];
         Message {
             header: HEADER,
-            payload: RPCPayload::FindValueNodes(nodes),
+            payload: RPCPayload::FindValueNodes(nodes, TOKEN),
         }
     }
-    const FIND_VALUE_NODES_BYTES: [u8; 49] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 8, 1, 0, 1,
-        2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 4, 127, 0, 0, 1, 31, 144,
+    const FIND_VALUE_NODES_BYTES: [u8; 58] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 8, 17, 18,
+        19, 20, 21, 22, 23, 24, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 4, 127, 0,
+        0, 1, 31, 144,
     ];
     const FIND_NODE_REQ_MSG: Message = Message {
         header: HEADER,
         payload: RPCPayload::FindNode(HEADER.node_id),
     };
-    const FIND_NODE_REQ_BYTES: [u8; 41] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 0, 1, 2,
-        3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    const FIND_NODE_REQ_BYTES: [u8; 42] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 3, 0, 1,
+        2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
     ];
     fn find_node_resp_msg() -> Message {
         let nodes = vec![Node {
@@ -442,61 +1825,79 @@ mod tests {
         }];
         Message {
             header: HEADER,
-            payload: RPCPayload::FindNodeResp(nodes),
+            payload: RPCPayload::FindNodeResp(nodes, TOKEN),
         }
     }
-    const FIND_NODE_RESP_BYTES: [u8; 49] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 4, 1, 0, 1,
-        2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 4, 127, 0, 0, 1, 31, 144,
+    const FIND_NODE_RESP_BYTES: [u8; 58] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 4, 17, 18,
+        19, 20, 21, 22, 23, 24, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 4, 127, 0,
+        0, 1, 31, 144,
     ];
     fn store_req_msg() -> Message {
         let key = String::from("AAAA");
-        let val = String::from("BBBB");
+        let val = b"BBBB".to_vec();
         Message {
             header: HEADER,
-            payload: RPCPayload::Store(key, val),
+            payload: RPCPayload::Store(key, val, 3, STORE_SIGNATURE, TOKEN),
         }
     }
-    const STORE_REQ_BYTES: [u8; 35] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 5, 4, 65, 65,
-        65, 65, 4, 66, 66, 66, 66,
+    const STORE_REQ_BYTES: [u8; 148] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 5, 17, 18,
+        19, 20, 21, 22, 23, 24, 0, 0, 0, 0, 0, 0, 0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 4, 65, 65, 65, 65, 4, 66,
+        66, 66, 66,
     ];
     const STORE_RESP_MSG: Message = Message {
         header: HEADER,
         payload: RPCPayload::StoreResp,
     };
-    const STORE_RESP_BYTES: [u8; 25] = [
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 6,
+    const STORE_RESP_BYTES: [u8; 26] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 6,
+    ];
+    fn error_msg() -> Message {
+        Message {
+            header: HEADER,
+            payload: RPCPayload::Error(ErrorCode::BadToken, String::from("AAAA")),
+        }
+    }
+    const ERROR_BYTES: [u8; 32] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2, 3, 4, 5, 6, 7, 8, 3, 10, 3, 4,
+        65, 65, 65, 65,
     ];
 
     #[test]
     fn ping_req_write() {
         let mut buf = [0; 0x100];
-        let count = PING_REQ_MSG.write(&mut buf);
+        let count = PING_REQ_MSG.write(&mut buf).unwrap();
         assert_eq!(&PING_REQ_BYTES, &buf[..count]);
     }
 
     #[test]
     fn ping_req_read() {
-        assert_eq!(Ok(PING_REQ_MSG), Message::try_from(&PING_REQ_BYTES[0..]));
+        assert_eq!(Ok(PING_REQ_MSG), Message::parse(&PING_REQ_BYTES[0..], None));
     }
 
     #[test]
     fn ping_resp_write() {
         let mut buf = [0; 0x100];
-        let count = PING_RESP_MSG.write(&mut buf);
+        let count = PING_RESP_MSG.write(&mut buf).unwrap();
         assert_eq!(&PING_RESP_BYTES, &buf[..count]);
     }
 
     #[test]
     fn ping_resp_read() {
-        assert_eq!(Ok(PING_RESP_MSG), Message::try_from(&PING_RESP_BYTES[0..]));
+        assert_eq!(
+            Ok(PING_RESP_MSG),
+            Message::parse(&PING_RESP_BYTES[0..], None)
+        );
     }
 
     #[test]
     fn find_value_req_write() {
         let mut buf = [0; 0x100];
-        let count = find_value_req_msg().write(&mut buf);
+        let count = find_value_req_msg().write(&mut buf).unwrap();
         assert_eq!(&FIND_VALUE_REQ_BYTES, &buf[..count]);
     }
 
@@ -504,29 +1905,29 @@ mod tests {
     fn find_value_req_read() {
         assert_eq!(
             Ok(find_value_req_msg()),
-            Message::try_from(&FIND_VALUE_REQ_BYTES[0..])
+            Message::parse(&FIND_VALUE_REQ_BYTES[0..], None)
         );
     }
 
     #[test]
     fn find_value_resp_write() {
         let mut buf = [0; 0x100];
-        let count = find_value_resp_msg().write(&mut buf);
+        let count = find_value_resp_msg().write(&mut buf).unwrap();
         assert_eq!(&FIND_VALUE_RESP_BYTES, &buf[..count]);
     }
 
     #[test]
     fn find_value_resp_read() {
         assert_eq!(
-            Ok(find_value_req_msg()),
-            Message::try_from(&FIND_VALUE_REQ_BYTES[0..])
+            Ok(find_value_resp_msg()),
+            Message::parse(&FIND_VALUE_RESP_BYTES[0..], None)
         );
     }
 
     #[test]
     fn find_value_nodes_write() {
         let mut buf = [0; 0x100];
-        let count = find_value_nodes_msg().write(&mut buf);
+        let count = find_value_nodes_msg().write(&mut buf).unwrap();
         assert_eq!(&FIND_VALUE_NODES_BYTES[0..], &buf[..count]);
     }
 
@@ -534,14 +1935,14 @@ mod tests {
     fn find_value_nodes_read() {
         assert_eq!(
             Ok(find_value_nodes_msg()),
-            Message::try_from(&FIND_VALUE_NODES_BYTES[0..])
+            Message::parse(&FIND_VALUE_NODES_BYTES[0..], None)
         );
     }
 
     #[test]
     fn find_node_req_write() {
         let mut buf = [0; 0x100];
-        let count = FIND_NODE_REQ_MSG.write(&mut buf);
+        let count = FIND_NODE_REQ_MSG.write(&mut buf).unwrap();
         assert_eq!(&FIND_NODE_REQ_BYTES[0..], &buf[..count]);
     }
 
@@ -549,14 +1950,14 @@ mod tests {
     fn find_node_req_read() {
         assert_eq!(
             Ok(FIND_NODE_REQ_MSG),
-            Message::try_from(&FIND_NODE_REQ_BYTES[0..])
+            Message::parse(&FIND_NODE_REQ_BYTES[0..], None)
         );
     }
 
     #[test]
     fn find_node_resp_write() {
         let mut buf = [0; 0x100];
-        let count = find_node_resp_msg().write(&mut buf);
+        let count = find_node_resp_msg().write(&mut buf).unwrap();
         assert_eq!(&FIND_NODE_RESP_BYTES[0..], &buf[..count]);
     }
 
@@ -564,14 +1965,14 @@ mod tests {
     fn find_node_resp_read() {
         assert_eq!(
             Ok(find_node_resp_msg()),
-            Message::try_from(&FIND_NODE_RESP_BYTES[0..])
+            Message::parse(&FIND_NODE_RESP_BYTES[0..], None)
         );
     }
 
     #[test]
     fn store_req_write() {
         let mut buf = [0; 0x100];
-        let count = store_req_msg().write(&mut buf);
+        let count = store_req_msg().write(&mut buf).unwrap();
         assert_eq!(&STORE_REQ_BYTES[0..], &buf[..count]);
     }
 
@@ -579,14 +1980,14 @@ mod tests {
     fn store_req_read() {
         assert_eq!(
             Ok(store_req_msg()),
-            Message::try_from(&STORE_REQ_BYTES[0..])
+            Message::parse(&STORE_REQ_BYTES[0..], None)
         );
     }
 
     #[test]
     fn store_resp_write() {
         let mut buf = [0; 0x100];
-        let count = STORE_RESP_MSG.write(&mut buf);
+        let count = STORE_RESP_MSG.write(&mut buf).unwrap();
         assert_eq!(&STORE_RESP_BYTES, &buf[..count]);
     }
 
@@ -594,7 +1995,399 @@ mod tests {
     fn store_resp_read() {
         assert_eq!(
             Ok(STORE_RESP_MSG),
-            Message::try_from(&STORE_RESP_BYTES[0..])
+            Message::parse(&STORE_RESP_BYTES[0..], None)
+        );
+    }
+
+    #[test]
+    fn error_write() {
+        let mut buf = [0; 0x100];
+        let count = error_msg().write(&mut buf).unwrap();
+        assert_eq!(&ERROR_BYTES[0..], &buf[..count]);
+    }
+
+    #[test]
+    fn error_read() {
+        assert_eq!(Ok(error_msg()), Message::parse(&ERROR_BYTES[0..], None));
+    }
+
+    #[test]
+    fn error_keeps_unknown_codes() {
+        let mut bytes = ERROR_BYTES;
+        bytes[26] = 200;
+        let payload = RPCPayload::Error(ErrorCode::Other(200), String::from("AAAA"));
+        assert_eq!(Ok(payload), RPCPayload::try_from(&bytes[25..]));
+        assert_eq!(200, u8::from(ErrorCode::Other(200)));
+    }
+
+    #[test]
+    fn tagged_round_trip() {
+        let key = NetworkKey::from_passphrase("network A");
+        let mut buf = [0; 0x100];
+        let count = store_req_msg().write_tagged(&key, &mut buf).unwrap();
+        assert_eq!(&STORE_REQ_BYTES[0..], &buf[..count - NETWORK_TAG_BYTES]);
+        assert_eq!(
+            Ok(store_req_msg()),
+            Message::parse(&buf[..count], Some(&key))
+        );
+    }
+
+    #[test]
+    fn tagged_rejects_other_networks() {
+        let key = NetworkKey::from_passphrase("network A");
+        let other = NetworkKey::from_passphrase("network B");
+        let mut buf = [0; 0x100];
+        let count = PING_REQ_MSG.write_tagged(&key, &mut buf).unwrap();
+        assert_eq!(
+            Err(ParseError::WrongNetwork),
+            Message::parse(&buf[..count], Some(&other))
+        );
+        let padded = [&STORE_REQ_BYTES[0..], &[0; NETWORK_TAG_BYTES]].concat();
+        assert_eq!(
+            Err(ParseError::WrongNetwork),
+            Message::parse(&padded, Some(&key))
+        );
+    }
+
+    #[test]
+    fn short_find_node_is_rejected() {
+        let short = &FIND_NODE_REQ_BYTES[..FIND_NODE_REQ_BYTES.len() - 1];
+        assert_eq!(
+            Err(ParseError::InsufficientLength),
+            Message::parse(short, None)
         );
     }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut bytes = FIND_NODE_REQ_BYTES;
+        bytes[HEADER_BYTES - 1] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Err(ParseError::UnsupportedVersion(PROTOCOL_VERSION + 1)),
+            Message::parse(&bytes[..], None)
+        );
+    }
+
+    #[test]
+    fn unknown_address_type_is_rejected() {
+        let mut bytes = FIND_NODE_RESP_BYTES;
+        bytes[51] = 5;
+        assert_eq!(
+            Err(ParseError::UnknownAddressType),
+            Message::parse(&bytes[0..], None)
+        );
+    }
+
+    #[test]
+    fn write_rejects_small_buffers() {
+        let mut buf = [0; 0x100];
+        let len = store_req_msg().encoded_len();
+        assert_eq!(
+            Err(EncodeError::BufferTooSmall),
+            store_req_msg().write(&mut buf[..len - 1])
+        );
+        assert_eq!(
+            Err(EncodeError::BufferTooSmall),
+            store_req_msg().write_tagged(&NetworkKey::new([0; 32]), &mut buf[..len])
+        );
+    }
+
+    #[test]
+    fn write_rejects_long_strings() {
+        let mut buf = [0; 0x200];
+        let message = Message {
+            header: HEADER,
+            payload: RPCPayload::FindValue("A".repeat(256), 0),
+        };
+        assert_eq!(Err(EncodeError::StringTooLong), message.write(&mut buf));
+    }
+
+    #[test]
+    fn trim_drops_furthest_nodes() {
+        let node = |i: u128| Node {
+            id: BitKey(i),
+            udp_addr: "127.0.0.1:8080".parse().unwrap(),
+        };
+        let mut message = Message {
+            header: HEADER,
+            payload: RPCPayload::FindNodeResp((0..20).map(node).collect(), TOKEN),
+        };
+        message.trim_to(200);
+        assert!(message.encoded_len() <= 200);
+        let expected = (0..7).map(node).collect();
+        assert_eq!(RPCPayload::FindNodeResp(expected, TOKEN), message.payload);
+    }
+
+    #[test]
+    fn trim_drops_closer_nodes_before_providers() {
+        let node = |i: u128| Node {
+            id: BitKey(i),
+            udp_addr: "127.0.0.1:8080".parse().unwrap(),
+        };
+        let providers: Vec<Node> = (0..5).map(node).collect();
+        let mut message = Message {
+            header: HEADER,
+            payload: RPCPayload::GetProvidersResp(
+                providers.clone(),
+                (5..10).map(node).collect(),
+                TOKEN,
+            ),
+        };
+        message.trim_to(200);
+        assert!(message.encoded_len() <= 200);
+        let expected = RPCPayload::GetProvidersResp(providers, (5..7).map(node).collect(), TOKEN);
+        assert_eq!(expected, message.payload);
+    }
+
+    #[test]
+    fn trim_leaves_values_for_the_next_page() {
+        let value = |i: u128| PublishedValue {
+            publisher: BitKey(i),
+            version: 1,
+            value: vec![b'A'; 20],
+            signature: STORE_SIGNATURE,
+        };
+        let page = ValuePage {
+            start: 0,
+            total: 10,
+            values: (0..10).map(value).collect(),
+        };
+        let mut message = Message {
+            header: HEADER,
+            payload: RPCPayload::FindValueResp(page, TOKEN),
+        };
+        message.trim_to(500);
+        assert!(message.encoded_len() <= 500);
+        match message.payload {
+            RPCPayload::FindValueResp(page, _) => {
+                assert_eq!((0..3).map(value).collect::<Vec<_>>(), page.values);
+                assert_eq!(Some(3), page.next_start());
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn precedence_is_deterministic() {
+        let value = |publisher: u128, version: u64, value: &str| PublishedValue {
+            publisher: BitKey(publisher),
+            version,
+            value: value.into(),
+            signature: STORE_SIGNATURE,
+        };
+        let mut values = vec![value(3, 1, "a"), value(1, 2, "b"), value(2, 2, "a")];
+        let mut reversed = values.clone();
+        reversed.reverse();
+        values.sort_by(|a, b| b.precedence(a));
+        reversed.sort_by(|a, b| b.precedence(a));
+        assert_eq!(values, reversed);
+        assert_eq!(value(2, 2, "a"), values[0]);
+    }
+
+    #[test]
+    fn message_ref_reads_nodes_lazily() {
+        let message = MessageRef::parse(&FIND_NODE_RESP_BYTES[0..], None).unwrap();
+        assert_eq!(HEADER, message.header());
+        match message.payload() {
+            Ok(RPCPayloadRef::FindNodeResp(nodes, token)) => {
+                assert_eq!(TOKEN, token);
+                assert_eq!(1, nodes.len());
+                let expected: Vec<Node> = match find_node_resp_msg().payload {
+                    RPCPayload::FindNodeResp(nodes, _) => nodes,
+                    _ => unreachable!(),
+                };
+                assert_eq!(expected, nodes.collect::<Vec<_>>());
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+        assert_eq!(Ok(find_node_resp_msg()), message.to_message());
+    }
+
+    #[test]
+    fn message_ref_reads_header_of_bad_payload() {
+        let mut bytes = STORE_RESP_BYTES;
+        bytes[HEADER_BYTES] = 0xFF;
+        let message = MessageRef::parse(&bytes[0..], None).unwrap();
+        assert_eq!(HEADER, message.header());
+        assert_eq!(Err(ParseError::UnknownMessageType), message.payload());
+    }
+
+    fn arb_string() -> impl Strategy<Value = String> {
+        // Strings are prefixed by a single byte length
+        "\\PC{0,60}"
+    }
+
+    fn arb_bytes() -> impl Strategy<Value = Vec<u8>> {
+        // Values can hold any bytes, not just text
+        proptest::collection::vec(any::<u8>(), 0..=255)
+    }
+
+    fn arb_node() -> impl Strategy<Value = Node> {
+        (any::<u128>(), any::<IpAddr>(), any::<u16>()).prop_map(|(id, ip, port)| Node {
+            id: BitKey(id),
+            udp_addr: SocketAddr::new(ip, port),
+        })
+    }
+
+    fn arb_entry() -> impl Strategy<Value = SyncEntry> {
+        prop_oneof![
+            arb_value().prop_map(SyncEntry::Value),
+            (any::<u64>(), arb_delete_signature())
+                .prop_map(|(version, sig)| SyncEntry::Tombstone(version, sig)),
+        ]
+    }
+
+    fn arb_store_signature() -> impl Strategy<Value = StoreSignature> {
+        arb_delete_signature().prop_map(|signature| StoreSignature {
+            public_key: signature.public_key,
+            signature: signature.signature,
+        })
+    }
+
+    fn arb_delete_signature() -> impl Strategy<Value = DeleteSignature> {
+        let signature = proptest::collection::vec(any::<u8>(), SIGNATURE_BYTES);
+        (any::<[u8; PUBLIC_KEY_BYTES]>(), signature).prop_map(|(public_key, signature)| {
+            DeleteSignature {
+                public_key,
+                signature: signature.try_into().unwrap(),
+            }
+        })
+    }
+
+    fn arb_record() -> impl Strategy<Value = MutableRecord> {
+        let signature = proptest::collection::vec(any::<u8>(), SIGNATURE_BYTES);
+        (
+            any::<[u8; 32]>(),
+            arb_string(),
+            any::<u64>(),
+            arb_string(),
+            signature,
+        )
+            .prop_map(|(public_key, salt, seq, value, signature)| MutableRecord {
+                public_key,
+                salt,
+                seq,
+                value,
+                signature: signature.try_into().unwrap(),
+            })
+    }
+
+    fn arb_value() -> impl Strategy<Value = PublishedValue> {
+        (any::<u64>(), arb_bytes(), arb_store_signature()).prop_map(
+            |(version, value, signature)| PublishedValue {
+                // The publisher isn't sent, since the public key gives it away
+                publisher: signature.publisher(),
+                version,
+                value,
+                signature,
+            },
+        )
+    }
+
+    fn arb_page() -> impl Strategy<Value = ValuePage> {
+        let values = proptest::collection::vec(arb_value(), 0..8);
+        (any::<u8>(), any::<u8>(), values).prop_map(|(start, total, values)| ValuePage {
+            start,
+            total,
+            values,
+        })
+    }
+
+    fn arb_payload() -> impl Strategy<Value = RPCPayload> {
+        let nodes = || proptest::collection::vec(arb_node(), 0..20);
+        let token = || any::<u64>().prop_map(Token);
+        prop_oneof![
+            any::<u32>().prop_map(|c| RPCPayload::Ping(Capabilities(c))),
+            any::<u32>().prop_map(|c| RPCPayload::PingResp(Capabilities(c))),
+            (arb_string(), any::<u8>()).prop_map(|(k, s)| RPCPayload::FindValue(k, s)),
+            (arb_page(), token()).prop_map(|(p, t)| RPCPayload::FindValueResp(p, t)),
+            (nodes(), token()).prop_map(|(n, t)| RPCPayload::FindValueNodes(n, t)),
+            any::<u128>().prop_map(|id| RPCPayload::FindNode(BitKey(id))),
+            (nodes(), token()).prop_map(|(n, t)| RPCPayload::FindNodeResp(n, t)),
+            (
+                arb_string(),
+                arb_bytes(),
+                any::<u64>(),
+                arb_store_signature(),
+                token()
+            )
+                .prop_map(|(k, v, version, sig, t)| RPCPayload::Store(k, v, version, sig, t)),
+            (
+                arb_string(),
+                arb_bytes(),
+                any::<u64>(),
+                arb_store_signature(),
+                token()
+            )
+                .prop_map(|(k, v, expected, sig, t)| RPCPayload::StoreIf(k, v, expected, sig, t)),
+            (arb_string(), any::<u64>(), arb_delete_signature(), token())
+                .prop_map(|(k, version, sig, t)| RPCPayload::Delete(k, version, sig, t)),
+            (arb_string(), arb_value(), token())
+                .prop_map(|(k, v, t)| RPCPayload::Replicate(k, v, t)),
+            (any::<u128>(), any::<u128>())
+                .prop_map(|(lo, hi)| RPCPayload::SyncHashes(BitKey(lo), BitKey(hi))),
+            proptest::collection::vec(any::<u64>(), 0..20).prop_map(RPCPayload::SyncHashesResp),
+            (any::<u128>(), any::<u128>())
+                .prop_map(|(lo, hi)| RPCPayload::SyncPull(BitKey(lo), BitKey(hi))),
+            (
+                proptest::collection::vec((arb_string(), arb_entry()), 0..8),
+                any::<bool>()
+            )
+                .prop_map(|(entries, more)| RPCPayload::SyncEntries(entries, more)),
+            Just(RPCPayload::StoreResp),
+            (any::<u8>(), arb_string()).prop_map(|(c, r)| RPCPayload::Error(c.into(), r)),
+            (arb_record(), token()).prop_map(|(r, t)| RPCPayload::StoreRecord(r, t)),
+            any::<u128>().prop_map(|key| RPCPayload::FindRecord(BitKey(key))),
+            (arb_record(), nodes(), token())
+                .prop_map(|(r, n, t)| RPCPayload::FindRecordResp(r, n, t)),
+            (any::<u128>(), token()).prop_map(|(k, t)| RPCPayload::AddProvider(BitKey(k), t)),
+            any::<u128>().prop_map(|key| RPCPayload::GetProviders(BitKey(key))),
+            (nodes(), nodes(), token()).prop_map(|(p, n, t)| RPCPayload::GetProvidersResp(p, n, t)),
+        ]
+    }
+
+    fn arb_message() -> impl Strategy<Value = Message> {
+        (any::<u128>(), any::<u64>(), arb_payload()).prop_map(
+            |(node_id, transaction_id, payload)| Message {
+                header: Header {
+                    node_id: BitKey(node_id),
+                    transaction_id: TransactionID(transaction_id),
+                    version: PROTOCOL_VERSION,
+                },
+                payload,
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn write_then_read_round_trips(message in arb_message()) {
+            let mut buf = [0; 0x800];
+            let count = message.clone().write(&mut buf).unwrap();
+            prop_assert_eq!(message.encoded_len(), count);
+            prop_assert_eq!(Ok(message), Message::parse(&buf[..count], None));
+        }
+
+        #[test]
+        fn truncated_messages_are_rejected(message in arb_message(), cut in any::<usize>()) {
+            let mut buf = [0; 0x800];
+            let count = message.write(&mut buf).unwrap();
+            let truncated = &buf[..cut % count];
+            prop_assert!(Message::parse(truncated, None).is_err());
+        }
+
+        #[test]
+        fn parsing_never_panics(data in proptest::collection::vec(any::<u8>(), 0..0x200)) {
+            let _ = Message::parse(&data[..], None);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn messages_round_trip_through_json() {
+        for message in [find_node_resp_msg(), store_req_msg(), error_msg()] {
+            let json = serde_json::to_string(&message).unwrap();
+            assert_eq!(message, serde_json::from_str(&json).unwrap());
+        }
+    }
 }
@@ -0,0 +1,145 @@
+use crate::base::{BitKey, Node};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a provider stays in the table, unless it announces itself again.
+pub const PROVIDER_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often we sweep the whole table for expired providers.
+const PRUNE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Represents the nodes that have announced they can provide some content.
+///
+/// Unlike the key store, this doesn't hold the content itself, only the contact
+/// information of the nodes holding it. Each provider expires unless it
+/// announces itself again, so nodes that leave the network eventually disappear.
+pub struct ProviderTable {
+    providers: HashMap<BitKey, Vec<(Node, Instant)>>,
+    max_per_key: usize,
+    max_total: usize,
+    total: usize,
+    pruned_at: Instant,
+}
+
+impl ProviderTable {
+    /// Create an empty table, with limits on how many providers we hold.
+    pub fn new(max_per_key: usize, max_total: usize, now: Instant) -> Self {
+        ProviderTable {
+            providers: HashMap::new(),
+            max_per_key,
+            max_total,
+            total: 0,
+            pruned_at: now,
+        }
+    }
+
+    /// Add a provider for a key, returning false if there's no room for it.
+    ///
+    /// A provider announcing itself again has its expiration pushed back.
+    pub fn add(&mut self, key: BitKey, node: Node, now: Instant) -> bool {
+        let expires = now + PROVIDER_TTL;
+        let entry = match self.providers.get_mut(&key) {
+            Some(entry) => entry,
+            // We only make room for a new key once we know the provider fits
+            None if self.max_per_key == 0 || self.total >= self.max_total => return false,
+            None => {
+                self.providers.insert(key, vec![(node, expires)]);
+                self.total += 1;
+                return true;
+            }
+        };
+        let before = entry.len();
+        entry.retain(|(_, expiration)| *expiration > now);
+        self.total -= before - entry.len();
+        if let Some(existing) = entry.iter_mut().find(|(n, _)| n.id == node.id) {
+            *existing = (node, expires);
+            return true;
+        }
+        if entry.len() >= self.max_per_key || self.total >= self.max_total {
+            return false;
+        }
+        entry.push((node, expires));
+        self.total += 1;
+        true
+    }
+
+    /// Get the providers for a key that haven't expired yet.
+    pub fn get(&self, key: BitKey, now: Instant) -> Vec<Node> {
+        self.providers.get(&key).map_or_else(Vec::new, |entry| {
+            entry
+                .iter()
+                .filter(|(_, expiration)| *expiration > now)
+                .map(|(node, _)| *node)
+                .collect()
+        })
+    }
+
+    /// Remove every expired provider, if we haven't done so recently.
+    pub fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned_at) < PRUNE_PERIOD {
+            return;
+        }
+        self.pruned_at = now;
+        let total = &mut self.total;
+        self.providers.retain(|_, entry| {
+            let before = entry.len();
+            entry.retain(|(_, expiration)| *expiration > now);
+            *total -= before - entry.len();
+            !entry.is_empty()
+        });
+    }
+
+    /// How many providers we hold, across every key.
+    pub fn len(&self) -> usize {
+        self.total
+    }
+
+    /// Check whether or not we hold no providers at all.
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_node(id: u128) -> Node {
+        Node {
+            id: BitKey(id),
+            udp_addr: "127.0.0.1:8080".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn providers_are_bounded() {
+        let now = Instant::now();
+        let mut table = ProviderTable::new(2, 3, now);
+        assert!(table.add(BitKey(1), make_node(1), now));
+        assert!(table.add(BitKey(1), make_node(2), now));
+        assert!(!table.add(BitKey(1), make_node(3), now));
+        // Announcing again doesn't take up more room
+        assert!(table.add(BitKey(1), make_node(2), now));
+        assert!(table.add(BitKey(2), make_node(3), now));
+        assert!(!table.add(BitKey(3), make_node(4), now));
+        // Rejected providers don't leave an empty entry behind
+        assert!(!table.providers.contains_key(&BitKey(3)));
+        assert_eq!(3, table.len());
+        assert_eq!(vec![make_node(1), make_node(2)], table.get(BitKey(1), now));
+    }
+
+    #[test]
+    fn providers_expire() {
+        let now = Instant::now();
+        let mut table = ProviderTable::new(2, 2, now);
+        table.add(BitKey(1), make_node(1), now);
+        let later = now + PROVIDER_TTL / 2;
+        table.add(BitKey(1), make_node(2), later);
+        let expired = now + PROVIDER_TTL;
+        assert_eq!(vec![make_node(2)], table.get(BitKey(1), expired));
+        // Expired providers make room for new ones
+        assert!(table.add(BitKey(1), make_node(3), expired));
+        table.prune(expired + PROVIDER_TTL);
+        assert!(table.is_empty());
+        assert!(table.get(BitKey(1), expired).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::messages::PublishedValue;
+use std::cmp::Ordering;
+
+/// Represents how a quorum read picks an answer when replicas disagree.
+#[derive(Clone, Copy, Debug)]
+pub enum Resolver {
+    /// The answer given by the most replicas, with ties going to the newest answer.
+    Majority,
+    /// The answer holding the value with the highest precedence.
+    Newest,
+    /// A function picking the answer, given the answer of every replica.
+    ///
+    /// Each answer is sorted by precedence, so the first value wins any conflict.
+    Custom(fn(&[Vec<PublishedValue>]) -> Vec<PublishedValue>),
+}
+
+/// Represents the options for reading a key from several replicas.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadQuorum {
+    /// How many of the K closest nodes need to answer for the read to succeed.
+    pub replicas: usize,
+    /// How to pick an answer when the replicas disagree.
+    pub resolver: Resolver,
+}
+
+/// Represents the outcome of reading a key from several replicas.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuorumRead {
+    /// The values the resolver picked, sorted by precedence.
+    pub values: Vec<PublishedValue>,
+    /// How many replicas answered.
+    pub replies: usize,
+    /// Whether or not the replicas gave different answers.
+    pub conflict: bool,
+}
+
+// Compare answers by their winning value, with empty answers being the oldest
+fn newer(a: &[PublishedValue], b: &[PublishedValue]) -> Ordering {
+    match (a.first(), b.first()) {
+        (Some(a), Some(b)) => a.precedence(b),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+/// Pick an answer among the values held by each replica.
+pub fn resolve(mut answers: Vec<Vec<PublishedValue>>, resolver: Resolver) -> QuorumRead {
+    // Replicas may send the same values in any order
+    for answer in &mut answers {
+        answer.sort_by(|a, b| b.precedence(a));
+    }
+    let conflict = answers.windows(2).any(|pair| pair[0] != pair[1]);
+    let values = match resolver {
+        Resolver::Majority => {
+            let votes =
+                |answer: &Vec<PublishedValue>| answers.iter().filter(|a| *a == answer).count();
+            answers
+                .iter()
+                .max_by(|a, b| votes(a).cmp(&votes(b)).then_with(|| newer(a, b)))
+                .cloned()
+                .unwrap_or_default()
+        }
+        Resolver::Newest => answers
+            .iter()
+            .max_by(|a, b| newer(a, b))
+            .cloned()
+            .unwrap_or_default(),
+        Resolver::Custom(pick) => pick(&answers),
+    };
+    QuorumRead {
+        values,
+        replies: answers.len(),
+        conflict,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Publisher;
+
+    fn value(version: u64, value: &str) -> PublishedValue {
+        let publisher = Publisher::from_seed([1; 32]);
+        publisher.publish("A", version, value.into()).unwrap()
+    }
+
+    #[test]
+    fn majority_outvotes_newer_values() {
+        let stale = vec![value(1, "a")];
+        let answers = vec![stale.clone(), vec![value(2, "b")], stale.clone()];
+        let read = resolve(answers.clone(), Resolver::Majority);
+        assert_eq!(stale, read.values);
+        assert_eq!(3, read.replies);
+        assert!(read.conflict);
+        let read = resolve(answers, Resolver::Newest);
+        assert_eq!(vec![value(2, "b")], read.values);
+    }
+
+    #[test]
+    fn answers_agree_regardless_of_order() {
+        let answers = vec![
+            vec![value(1, "a"), value(2, "b")],
+            vec![value(2, "b"), value(1, "a")],
+        ];
+        let read = resolve(answers, Resolver::Majority);
+        assert!(!read.conflict);
+        assert_eq!(vec![value(2, "b"), value(1, "a")], read.values);
+        // Ties between answers go to the newest one
+        let answers = vec![vec![], vec![value(1, "a")]];
+        assert_eq!(
+            vec![value(1, "a")],
+            resolve(answers, Resolver::Majority).values
+        );
+        let read = resolve(vec![], Resolver::Custom(|_| vec![value(3, "c")]));
+        assert_eq!(vec![value(3, "c")], read.values);
+        assert_eq!(0, read.replies);
+    }
+}
@@ -0,0 +1,408 @@
+use crate::base::BitKey;
+use crate::messages::{EncodeError, PublishedValue};
+use crate::rand::Rng;
+use crate::sha1::Sha1;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// How many bytes are in the public key of a publisher.
+pub const PUBLIC_KEY_BYTES: usize = 32;
+/// How many bytes are in the signature of a record.
+pub const SIGNATURE_BYTES: usize = 64;
+// Salts and values are prefixed with a single byte length when signed
+const MAX_STRING_BYTES: usize = u8::MAX as usize;
+
+/// Calculate the key a mutable record is stored under.
+///
+/// This is the hash of the public key of the publisher, followed by the salt,
+/// so a publisher can have many records, but nobody else can write to them.
+pub fn record_key(public_key: &[u8; PUBLIC_KEY_BYTES], salt: &str) -> BitKey {
+    let mut hasher = Sha1::new();
+    hasher.update(public_key);
+    hasher.update(salt.as_bytes());
+    // Like BitKey::from_hash, we keep the least significant 128 bits
+    let bytes = hasher.digest().bytes()[4..].try_into().unwrap();
+    BitKey(u128::from_be_bytes(bytes))
+}
+
+/// Calculate the node ID a publisher takes, binding the values it stores to its key pair.
+///
+/// This is the hash of a fixed prefix followed by the public key, so it can't
+/// collide with the key of one of the records of the publisher.
+pub fn publisher_id(public_key: &[u8; PUBLIC_KEY_BYTES]) -> BitKey {
+    let mut hasher = Sha1::new();
+    hasher.update(b"publisher");
+    hasher.update(public_key);
+    let bytes = hasher.digest().bytes()[4..].try_into().unwrap();
+    BitKey(u128::from_be_bytes(bytes))
+}
+
+// The signature covers everything in the record besides the public key,
+// which is already bound to the record through its key.
+fn signed_bytes(salt: &str, seq: u64, value: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(2 + salt.len() + 8 + value.len());
+    buf.push(salt.len() as u8);
+    buf.extend_from_slice(salt.as_bytes());
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value.as_bytes());
+    buf
+}
+
+// Stores are tagged like deletes, so neither can pass for the other, or for a signed record
+fn store_bytes(key: &str, version: u64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(5 + 1 + key.len() + 8 + 1 + value.len());
+    buf.extend_from_slice(b"store");
+    buf.push(key.len() as u8);
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value);
+    buf
+}
+
+// Deletes are tagged, so a signed delete can never pass for a signed record
+fn delete_bytes(key: &str, version: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(6 + 1 + key.len() + 8);
+    buf.extend_from_slice(b"delete");
+    buf.push(key.len() as u8);
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf
+}
+
+/// Represents the key pair used to publish mutable records.
+pub struct Publisher {
+    key: SigningKey,
+}
+
+impl Publisher {
+    /// Generate a new key pair at random.
+    pub fn generate<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        Self::from_seed(rng.gen())
+    }
+
+    /// Create a key pair from a secret seed, letting a publisher keep the same key.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Publisher {
+            key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    /// The public key identifying this publisher.
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_BYTES] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    /// The node ID this publisher stores values under.
+    pub fn id(&self) -> BitKey {
+        publisher_id(&self.public_key())
+    }
+
+    /// Sign a value stored under a key at some version.
+    ///
+    /// This fails with `StringTooLong` if the key or value is longer than 255 bytes.
+    pub fn sign_store(
+        &self,
+        key: &str,
+        version: u64,
+        value: &[u8],
+    ) -> Result<StoreSignature, EncodeError> {
+        if key.len() > MAX_STRING_BYTES || value.len() > MAX_STRING_BYTES {
+            return Err(EncodeError::StringTooLong);
+        }
+        let signature = self.key.sign(&store_bytes(key, version, value));
+        Ok(StoreSignature {
+            public_key: self.public_key(),
+            signature: signature.to_bytes(),
+        })
+    }
+
+    /// Sign a value, to be stored under a key at some version.
+    ///
+    /// This fails with `StringTooLong` if the key or value is longer than 255 bytes.
+    pub fn publish(
+        &self,
+        key: &str,
+        version: u64,
+        value: Vec<u8>,
+    ) -> Result<PublishedValue, EncodeError> {
+        let signature = self.sign_store(key, version, &value)?;
+        Ok(PublishedValue {
+            publisher: self.id(),
+            version,
+            value,
+            signature,
+        })
+    }
+
+    /// Sign the deletion of the values stored under a key, up to and including some version.
+    ///
+    /// This fails with `StringTooLong` if the key is longer than 255 bytes.
+    pub fn sign_delete(&self, key: &str, version: u64) -> Result<DeleteSignature, EncodeError> {
+        if key.len() > MAX_STRING_BYTES {
+            return Err(EncodeError::StringTooLong);
+        }
+        let signature = self.key.sign(&delete_bytes(key, version));
+        Ok(DeleteSignature {
+            public_key: self.public_key(),
+            signature: signature.to_bytes(),
+        })
+    }
+
+    /// Create a signed record, to be stored at `record_key(public_key, salt)`.
+    ///
+    /// Nodes only replace a record with one that has a higher sequence number,
+    /// so each new version of a record should increase it.
+    ///
+    /// The salt and value are each prefixed with a single byte length, so this
+    /// fails with `StringTooLong` if either is longer than 255 bytes.
+    pub fn sign(&self, salt: &str, seq: u64, value: &str) -> Result<MutableRecord, EncodeError> {
+        if salt.len() > MAX_STRING_BYTES || value.len() > MAX_STRING_BYTES {
+            return Err(EncodeError::StringTooLong);
+        }
+        let signature = self.key.sign(&signed_bytes(salt, seq, value));
+        Ok(MutableRecord {
+            public_key: self.public_key(),
+            salt: salt.into(),
+            seq,
+            value: value.into(),
+            signature: signature.to_bytes(),
+        })
+    }
+}
+
+/// Represents a value that only its publisher can modify.
+///
+/// Unlike values stored with `Store`, these records are signed, and stored under
+/// a key derived from the public key of the publisher. Nodes check the signature
+/// before storing a record, and only replace a record with a newer version.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MutableRecord {
+    /// The public key of the publisher
+    pub public_key: [u8; PUBLIC_KEY_BYTES],
+    /// Lets a single publisher have many records, each with a different salt
+    pub salt: String,
+    /// The version of this record, which increases with each update
+    pub seq: u64,
+    /// The value this record holds
+    pub value: String,
+    /// The signature of the publisher over the rest of the record
+    #[cfg_attr(feature = "serde", serde(with = "signature_bytes"))]
+    pub signature: [u8; SIGNATURE_BYTES],
+}
+
+/// Represents the proof that a publisher stored a value.
+///
+/// Values are tagged with the ID of their publisher, which comes from this
+/// public key, so nobody can store a value in the name of another publisher.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StoreSignature {
+    /// The public key of the publisher
+    pub public_key: [u8; PUBLIC_KEY_BYTES],
+    /// The signature of the publisher over the key, version, and value stored
+    #[cfg_attr(feature = "serde", serde(with = "signature_bytes"))]
+    pub signature: [u8; SIGNATURE_BYTES],
+}
+
+impl StoreSignature {
+    /// The ID of the publisher that stored the value.
+    pub fn publisher(&self) -> BitKey {
+        publisher_id(&self.public_key)
+    }
+
+    /// Check that the publisher signed this value, stored under this key at this version.
+    pub fn verify(&self, key: &str, version: u64, value: &[u8]) -> bool {
+        let public_key = match VerifyingKey::from_bytes(&self.public_key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        public_key
+            .verify(&store_bytes(key, version, value), &signature)
+            .is_ok()
+    }
+}
+
+/// Represents the proof that a publisher asked for its values to be deleted.
+///
+/// Values are only tagged with the ID of their publisher, so nodes check that
+/// the public key hashes to that ID, as well as checking the signature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeleteSignature {
+    /// The public key of the publisher
+    pub public_key: [u8; PUBLIC_KEY_BYTES],
+    /// The signature of the publisher over the key and version deleted
+    #[cfg_attr(feature = "serde", serde(with = "signature_bytes"))]
+    pub signature: [u8; SIGNATURE_BYTES],
+}
+
+impl DeleteSignature {
+    /// The ID of the publisher whose values this deletes.
+    pub fn publisher(&self) -> BitKey {
+        publisher_id(&self.public_key)
+    }
+
+    /// Check that the publisher signed the deletion of this key, up to this version.
+    pub fn verify(&self, key: &str, version: u64) -> bool {
+        let public_key = match VerifyingKey::from_bytes(&self.public_key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        public_key
+            .verify(&delete_bytes(key, version), &signature)
+            .is_ok()
+    }
+}
+
+// Serde only handles arrays of up to 32 elements, so signatures need some help
+#[cfg(feature = "serde")]
+mod signature_bytes {
+    use super::SIGNATURE_BYTES;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::convert::TryInto;
+
+    pub fn serialize<S: Serializer>(
+        signature: &[u8; SIGNATURE_BYTES],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(signature)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; SIGNATURE_BYTES], D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let len = bytes.len();
+        bytes
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"64 bytes"))
+    }
+}
+
+impl MutableRecord {
+    /// The key this record is stored under.
+    pub fn key(&self) -> BitKey {
+        record_key(&self.public_key, &self.salt)
+    }
+
+    /// Check that this record was signed by its publisher.
+    pub fn verify(&self) -> bool {
+        let public_key = match VerifyingKey::from_bytes(&self.public_key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        let signed = signed_bytes(&self.salt, self.seq, &self.value);
+        public_key.verify(&signed, &signature).is_ok()
+    }
+
+    /// How many bytes of storage this record takes up.
+    pub fn size(&self) -> usize {
+        PUBLIC_KEY_BYTES + self.salt.len() + 8 + self.value.len() + SIGNATURE_BYTES
+    }
+}
+
+/// Represents a mutable record, borrowed from the buffer of a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MutableRecordRef<'a> {
+    pub public_key: [u8; PUBLIC_KEY_BYTES],
+    pub salt: &'a str,
+    pub seq: u64,
+    pub value: &'a str,
+    pub signature: [u8; SIGNATURE_BYTES],
+}
+
+impl<'a> From<MutableRecordRef<'a>> for MutableRecord {
+    fn from(record: MutableRecordRef<'a>) -> Self {
+        MutableRecord {
+            public_key: record.public_key,
+            salt: record.salt.into(),
+            seq: record.seq,
+            value: record.value.into(),
+            signature: record.signature,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::thread_rng;
+
+    #[test]
+    fn signed_records_verify() {
+        let publisher = Publisher::generate(&mut thread_rng());
+        let record = publisher.sign("profile", 1, "Hello World").unwrap();
+        assert!(record.verify());
+        assert_eq!(record_key(&publisher.public_key(), "profile"), record.key());
+        let forged = MutableRecord {
+            seq: 2,
+            ..record.clone()
+        };
+        assert!(!forged.verify());
+        let other = Publisher::generate(&mut thread_rng());
+        let stolen = MutableRecord {
+            public_key: other.public_key(),
+            ..record
+        };
+        assert!(!stolen.verify());
+    }
+
+    #[test]
+    fn salts_separate_records() {
+        let publisher = Publisher::from_seed([1; 32]);
+        let a = publisher.sign("a", 1, "Hello World").unwrap();
+        let b = publisher.sign("b", 1, "Hello World").unwrap();
+        assert_ne!(a.key(), b.key());
+    }
+
+    #[test]
+    fn signed_deletes_verify() {
+        let publisher = Publisher::generate(&mut thread_rng());
+        let delete = publisher.sign_delete("key", 5).unwrap();
+        assert!(delete.verify("key", 5));
+        assert_eq!(publisher.id(), delete.publisher());
+        assert!(!delete.verify("key", 6));
+        assert!(!delete.verify("other", 5));
+        let other = Publisher::generate(&mut thread_rng());
+        let stolen = DeleteSignature {
+            public_key: other.public_key(),
+            ..delete
+        };
+        assert!(!stolen.verify("key", 5));
+    }
+
+    #[test]
+    fn signed_stores_verify() {
+        let publisher = Publisher::generate(&mut thread_rng());
+        let store = publisher.sign_store("key", 5, b"value").unwrap();
+        assert!(store.verify("key", 5, b"value"));
+        assert_eq!(publisher.id(), store.publisher());
+        assert!(!store.verify("key", 6, b"value"));
+        assert!(!store.verify("other", 5, b"value"));
+        assert!(!store.verify("key", 5, b"forged"));
+        // A signed store can't be passed off as a signed delete
+        let delete = DeleteSignature {
+            public_key: store.public_key,
+            signature: store.signature,
+        };
+        assert!(!delete.verify("key", 5));
+    }
+
+    #[test]
+    fn long_salts_are_rejected() {
+        let publisher = Publisher::from_seed([1; 32]);
+        let salt = "A".repeat(256);
+        let result = publisher.sign(&salt, 1, "Hello World");
+        assert_eq!(Err(EncodeError::StringTooLong), result);
+        assert!(publisher.sign(&salt[1..], 1, "Hello World").is_ok());
+    }
+}
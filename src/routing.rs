@@ -1,5 +1,72 @@
 use crate::base::{BitKey, Node, KEY_SIZE};
-use std::collections::VecDeque;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+/// Represents the subnet an address belongs to, for the purposes of IP diversity.
+///
+/// An attacker usually controls many addresses in the same subnet, so we
+/// limit how many nodes can share one in our routing table. IPv4 addresses
+/// are grouped by /24, and IPv6 addresses by /64.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Subnet {
+    V4([u8; 3]),
+    V6([u8; 8]),
+}
+
+impl Subnet {
+    /// Find the subnet for an address.
+    ///
+    /// This returns `None` for loopback addresses, which aren't subject to limits,
+    /// since running many nodes on a single machine is useful for testing.
+    pub fn of(ip: IpAddr) -> Option<Self> {
+        if ip.is_loopback() {
+            return None;
+        }
+        match ip {
+            IpAddr::V4(v4) => {
+                let o = v4.octets();
+                Some(Subnet::V4([o[0], o[1], o[2]]))
+            }
+            IpAddr::V6(v6) => {
+                let mut prefix = [0; 8];
+                prefix.copy_from_slice(&v6.octets()[..8]);
+                Some(Subnet::V6(prefix))
+            }
+        }
+    }
+}
+
+/// Represents limits on how many nodes sharing a subnet we keep around.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct IpLimits {
+    /// How many nodes sharing a subnet can be in a single bucket
+    pub per_bucket: usize,
+    /// How many nodes sharing a subnet can be in the whole routing table
+    pub per_table: usize,
+}
+
+impl IpLimits {
+    /// Limits that never reject any node.
+    pub fn unlimited() -> Self {
+        IpLimits {
+            per_bucket: usize::MAX,
+            per_table: usize::MAX,
+        }
+    }
+}
+
+impl Default for IpLimits {
+    fn default() -> Self {
+        IpLimits {
+            per_bucket: 2,
+            per_table: 10,
+        }
+    }
+}
 
 /// Represents the result of inserting into a KBucket.
 ///
@@ -21,6 +88,11 @@ pub enum KBucketInsert {
     /// otherwise we call
     /// [remove](struct.KBucket.html#method.failed_ping).
     Ping(Node),
+    /// We couldn't insert the item, because too many nodes share its subnet.
+    ///
+    /// The node might still be kept around in the replacement cache, if a spot
+    /// for its subnet opens up later.
+    Rejected,
 }
 
 /// This represents a KBucket used in the Kademlia DHT.
@@ -31,15 +103,18 @@ pub enum KBucketInsert {
 /// We have this preference for long-lived nodes, since the longer a node
 /// lives, the longer it tends to stay alive as well.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct KBucket {
     // The max size never changes, and should usually be 20, but
     // we store it inside the struct itself since we access it frequently.
     max_size: usize,
+    // How many nodes in this bucket can share a subnet
+    per_subnet: usize,
     // This acts as a FILO stack for pending nodes.
     // New nodes can only be inserted into a full bucket if an existing
     // node in that bucket is died. We always want to insert the most
     // recently known nodes, so we use this stack order for the waiting
-    // elements.
+    // elements. This holds at most max_size nodes, each at most once.
     waiting: Vec<Node>,
     // This holds the actual elements in the bucket
     data: VecDeque<Node>,
@@ -50,13 +125,45 @@ impl KBucket {
     ///
     /// The default specified in the Kademlia paper is 20.
     pub fn new(max_size: usize) -> Self {
+        Self::with_subnet_limit(max_size, usize::MAX)
+    }
+
+    /// Create a new KBucket, limiting how many nodes can share a subnet.
+    ///
+    /// See [Subnet](enum.Subnet.html) for how addresses are grouped.
+    pub fn with_subnet_limit(max_size: usize, per_subnet: usize) -> Self {
         KBucket {
             max_size,
+            per_subnet,
             waiting: Vec::new(),
             data: VecDeque::with_capacity(max_size),
         }
     }
 
+    fn subnet_count(&self, subnet: Option<Subnet>) -> usize {
+        match subnet {
+            None => 0,
+            Some(subnet) => self
+                .data
+                .iter()
+                .filter(|x| Subnet::of(x.udp_addr.ip()) == Some(subnet))
+                .count(),
+        }
+    }
+
+    fn contains(&self, id: BitKey) -> bool {
+        self.data.iter().any(|x| x.id == id)
+    }
+
+    // Push a node onto the waiting stack, forgetting the oldest node once it's full
+    fn wait(&mut self, item: Node) {
+        self.waiting.retain(|x| x.id != item.id);
+        if self.waiting.len() >= self.max_size {
+            self.waiting.remove(0);
+        }
+        self.waiting.push(item);
+    }
+
     /// Try and insert an element into the bucket.
     ///
     /// This should be called whenever any message is received from a node,
@@ -68,16 +175,22 @@ impl KBucket {
     /// still alive. After performing that check, either insert should
     /// be called again, since we received a ping response from that node,
     /// or remove should be called, since we know that node has died.
+    ///
+    /// If too many nodes in the bucket already share a subnet with this one,
+    /// the node goes into the replacement cache, and `Rejected` is returned.
     pub fn insert(&mut self, item: Node) -> KBucketInsert {
         let existing = self.data.iter().position(|x| *x == item);
         if let Some(index) = existing {
             self.data.remove(index);
+        } else if self.subnet_count(Subnet::of(item.udp_addr.ip())) >= self.per_subnet {
+            self.wait(item);
+            return KBucketInsert::Rejected;
         }
         if self.data.len() < self.max_size {
             self.data.push_back(item);
             KBucketInsert::Inserted
         } else {
-            self.waiting.push(item);
+            self.wait(item);
             KBucketInsert::Ping(self.data[0])
         }
     }
@@ -93,10 +206,25 @@ impl KBucket {
     /// tried to insert most recently, but couldn't because of the lack of
     /// dead nodes.
     pub fn remove(&mut self, id: BitKey) {
+        self.remove_with(id, |_| true)
+    }
+
+    /// Remove a dead node from this bucket, with an extra condition on its replacement.
+    ///
+    /// This works like [remove](struct.KBucket.html#method.remove), except that
+    /// we only replace the node with a waiting one satisfying the condition.
+    pub fn remove_with<F: Fn(&Node) -> bool>(&mut self, id: BitKey, allowed: F) {
         let existing = self.data.iter().position(|x| x.id == id);
         if let Some(index) = existing {
             self.data.remove(index);
-            if let Some(new) = self.waiting.pop() {
+            // We look for the most recent node we're allowed to insert
+            let replacement = self.waiting.iter().rposition(|x| {
+                !self.contains(x.id)
+                    && self.subnet_count(Subnet::of(x.udp_addr.ip())) < self.per_subnet
+                    && allowed(x)
+            });
+            if let Some(i) = replacement {
+                let new = self.waiting.remove(i);
                 self.data.push_back(new);
             }
         }
@@ -125,6 +253,8 @@ impl KBucket {
 /// contains nodes whose distance from this instance is between 2 subsequent
 /// powers of 2. This means that the further away a range is from us, the less
 /// information we have about nodes in that range.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "RoutingTableSnapshot"))]
 pub struct RoutingTable {
     // We node to know which nodemaps to this instance,
     // since the routing table is based on buckets of certain
@@ -138,6 +268,36 @@ pub struct RoutingTable {
     // For example, if the distance between a node and this node is 00101b,
     // then this would go in the bucket with index 2.
     buckets: Vec<KBucket>,
+    // How many nodes sharing a subnet can be in the whole table
+    per_table: usize,
+    // How many nodes in the table share each subnet
+    #[cfg_attr(feature = "serde", serde(skip))]
+    subnets: HashMap<Subnet, usize>,
+}
+
+// Routing tables are restored through this, letting us recount subnets from the buckets
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct RoutingTableSnapshot {
+    this_node: Node,
+    buckets: Vec<KBucket>,
+    per_table: usize,
+}
+
+#[cfg(feature = "serde")]
+impl From<RoutingTableSnapshot> for RoutingTable {
+    fn from(snapshot: RoutingTableSnapshot) -> Self {
+        let mut table = RoutingTable {
+            this_node: snapshot.this_node,
+            buckets: snapshot.buckets,
+            per_table: snapshot.per_table,
+            subnets: HashMap::new(),
+        };
+        for bucket in 0..table.buckets.len() {
+            table.count_subnets(bucket, true);
+        }
+        table
+    }
 }
 
 impl RoutingTable {
@@ -147,14 +307,59 @@ impl RoutingTable {
     /// in order to evaluate the distance between this instance and the nodes
     /// we try and insert into the routing table.
     pub fn new(this_node: Node, bucket_size: usize) -> Self {
-        let buckets = vec![KBucket::new(bucket_size); KEY_SIZE];
-        RoutingTable { this_node, buckets }
+        Self::with_ip_limits(this_node, bucket_size, IpLimits::unlimited())
+    }
+
+    /// Construct a new routing table, limiting how many nodes can share a subnet.
+    ///
+    /// This makes it harder for an attacker controlling a single subnet to fill
+    /// our routing table with nodes they control.
+    pub fn with_ip_limits(this_node: Node, bucket_size: usize, limits: IpLimits) -> Self {
+        let bucket = KBucket::with_subnet_limit(bucket_size, limits.per_bucket);
+        let buckets = vec![bucket; KEY_SIZE];
+        RoutingTable {
+            this_node,
+            buckets,
+            per_table: limits.per_table,
+            subnets: HashMap::new(),
+        }
+    }
+
+    fn bucket_index(&self, id: BitKey) -> usize {
+        self.this_node.id.distance(id).leading_zeros() as usize
+    }
+
+    // This should be called before and after modifying a bucket, which lets us
+    // keep our subnet counts in sync with the buckets.
+    fn count_subnets(&mut self, bucket: usize, add: bool) {
+        for node in &self.buckets[bucket].data {
+            if let Some(subnet) = Subnet::of(node.udp_addr.ip()) {
+                let count = self.subnets.entry(subnet).or_insert(0);
+                if add {
+                    *count += 1;
+                } else {
+                    *count -= 1;
+                }
+            }
+        }
+        self.subnets.retain(|_, count| *count > 0);
+    }
+
+    fn subnet_full(subnets: &HashMap<Subnet, usize>, per_table: usize, node: &Node) -> bool {
+        match Subnet::of(node.udp_addr.ip()) {
+            None => false,
+            Some(subnet) => subnets.get(&subnet).is_some_and(|&c| c >= per_table),
+        }
     }
 
     pub fn this_node_id(&self) -> BitKey {
         self.this_node.id
     }
 
+    pub fn this_node(&self) -> Node {
+        self.this_node
+    }
+
     /// Insert a node from the routing table.
     ///
     /// See
@@ -165,6 +370,9 @@ impl RoutingTable {
     /// Inserting the node for this instance will just return `KBucketInsert::Inserted`
     /// but do nothing to the underlying buckets. There's no reason
     /// to ever call this method with the node for this instance however.
+    ///
+    /// If too many nodes in the table already share a subnet with this node,
+    /// then `KBucketInsert::Rejected` is returned, and the node is forgotten.
     pub fn insert(&mut self, node: Node) -> KBucketInsert {
         // In theory no one should even try to insert this node, but
         // it can be handled as if we successfully inserted it.
@@ -173,9 +381,28 @@ impl RoutingTable {
         if self.this_node == node {
             return KBucketInsert::Inserted;
         }
-        let distance = self.this_node.distance(&node);
-        let i = distance.leading_zeros() as usize;
-        self.buckets[i].insert(node)
+        let i = self.bucket_index(node.id);
+        if !self.buckets[i].contains(node.id)
+            && Self::subnet_full(&self.subnets, self.per_table, &node)
+        {
+            return KBucketInsert::Rejected;
+        }
+        self.count_subnets(i, false);
+        let result = self.buckets[i].insert(node);
+        self.count_subnets(i, true);
+        result
+    }
+
+    /// Check whether or not a node is currently in the routing table, at the same address.
+    ///
+    /// The node for this instance always counts as being in the table.
+    pub fn contains(&self, node: &Node) -> bool {
+        let same = |x: &Node| x.id == node.id && x.udp_addr == node.udp_addr;
+        same(&self.this_node)
+            || self.buckets[self.bucket_index(node.id)]
+                .data
+                .iter()
+                .any(same)
     }
 
     /// Remove a node from the routing table.
@@ -190,9 +417,11 @@ impl RoutingTable {
         if self.this_node.id == id {
             return;
         }
-        let distance = self.this_node.id.distance(id);
-        let i = distance.leading_zeros() as usize;
-        self.buckets[i].remove(id);
+        let i = self.bucket_index(id);
+        self.count_subnets(i, false);
+        let (subnets, per_table) = (&self.subnets, self.per_table);
+        self.buckets[i].remove_with(id, |x| !Self::subnet_full(subnets, per_table, x));
+        self.count_subnets(i, true);
     }
 
     /// Find the k_closest elements to the target key in the routing table.
@@ -241,7 +470,7 @@ impl RoutingTable {
             let i = distance.leading_zeros();
             let bucket = i as usize;
             to_take -= self.buckets[bucket].k_closest(&mut buf, target, to_take);
-            distance ^= 1 << (KEY_SIZE as u32 - i);
+            distance ^= 1 << (KEY_SIZE as u32 - 1 - i);
         }
         if to_take > 0 {
             buf.push(self.this_node);
@@ -310,6 +539,72 @@ mod tests {
         assert_eq!(Some(make_node(max_size as u128)), bucket.data.pop_back());
     }
 
+    #[test]
+    fn kbucket_waiting_is_bounded() {
+        let mut bucket = KBucket::with_subnet_limit(2, 1);
+        bucket.insert(make_node_at(0, "10.0.0.1:10"));
+        // The same node being rejected over and over only waits once
+        for _ in 0..10 {
+            bucket.insert(make_node_at(1, "10.0.0.2:10"));
+        }
+        assert_eq!(1, bucket.waiting.len());
+        for id in 2..10 {
+            bucket.insert(make_node_at(id, "10.0.0.3:10"));
+        }
+        assert_eq!(2, bucket.waiting.len());
+        assert_eq!(make_node_at(9, "10.0.0.3:10"), bucket.waiting[1]);
+    }
+
+    fn make_node_at(id: u128, addr: &str) -> Node {
+        Node {
+            id: BitKey(id),
+            udp_addr: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn kbucket_limits_subnets() {
+        let mut bucket = KBucket::with_subnet_limit(20, 2);
+        let a = make_node_at(1, "10.0.0.1:10");
+        let b = make_node_at(2, "10.0.0.2:10");
+        let c = make_node_at(3, "10.0.0.3:10");
+        assert_eq!(KBucketInsert::Inserted, bucket.insert(a));
+        assert_eq!(KBucketInsert::Inserted, bucket.insert(b));
+        assert_eq!(KBucketInsert::Rejected, bucket.insert(c));
+        // Refreshing a node already in the bucket is fine
+        assert_eq!(KBucketInsert::Inserted, bucket.insert(a));
+        let other = make_node_at(4, "10.0.1.1:10");
+        assert_eq!(KBucketInsert::Inserted, bucket.insert(other));
+        // The rejected node can take the place of a dead node in its subnet
+        bucket.remove(BitKey(1));
+        assert_eq!(Some(c), bucket.data.pop_back());
+    }
+
+    #[test]
+    fn kbucket_ignores_loopback_subnets() {
+        let mut bucket = KBucket::with_subnet_limit(20, 1);
+        for x in 0..10 {
+            let node = make_node_at(x, "127.0.0.1:10");
+            assert_eq!(KBucketInsert::Inserted, bucket.insert(node));
+        }
+    }
+
+    #[test]
+    fn routing_table_limits_subnets() {
+        let this_node = make_node_at(0, "10.0.0.1:10");
+        let limits = IpLimits {
+            per_bucket: 1,
+            per_table: 2,
+        };
+        let mut table = RoutingTable::with_ip_limits(this_node, 20, limits);
+        let ipv6 = "[2001:db8::1]:10";
+        assert_eq!(KBucketInsert::Inserted, table.insert(make_node_at(1, ipv6)));
+        assert_eq!(KBucketInsert::Inserted, table.insert(make_node_at(2, ipv6)));
+        assert_eq!(KBucketInsert::Rejected, table.insert(make_node_at(4, ipv6)));
+        table.remove(BitKey(1));
+        assert_eq!(KBucketInsert::Inserted, table.insert(make_node_at(4, ipv6)));
+    }
+
     #[test]
     fn routing_table_can_insert() {
         let udp_addr = "127.0.0.1:1234".parse().unwrap();
@@ -325,20 +620,67 @@ mod tests {
         }
     }
 
+    #[test]
+    fn routing_table_contains_matches_address() {
+        let mut table = RoutingTable::new(make_node(0), 20);
+        let node = make_node_at(1, "10.0.0.1:10");
+        assert!(!table.contains(&node));
+        table.insert(node);
+        assert!(table.contains(&node));
+        assert!(!table.contains(&make_node_at(1, "10.0.0.2:10")));
+        table.remove(node.id);
+        assert!(!table.contains(&node));
+    }
+
+    #[test]
+    fn routing_table_closest_to_other_keys() {
+        let this_node = make_node(0);
+        let mut table = RoutingTable::new(this_node, 20);
+        for i in 0..4 {
+            table.insert(make_node(1 << i));
+        }
+        let closest = table.k_closest(BitKey(1 << 127 | 5), 3);
+        assert_eq!(vec![make_node(4), make_node(1), this_node], closest);
+    }
+
     #[test]
     fn routing_table_closest_is_everything_when_small() {
         let max_size = 20;
         let this_node = make_node(0);
         let mut table = RoutingTable::new(this_node, max_size);
-        let mut nodes = Vec::with_capacity(max_size as usize);
+        let mut nodes = Vec::with_capacity(max_size);
         nodes.push(this_node);
         for i in 0..(max_size - 1) {
             let node = make_node(1 << i);
             nodes.push(node);
             table.insert(node);
         }
-        assert_eq!(nodes, table.k_closest(this_node.id, max_size as usize));
+        assert_eq!(nodes, table.k_closest(this_node.id, max_size));
         assert_eq!(Vec::<Node>::new(), table.k_closest(this_node.id, 0));
         assert_eq!(vec![this_node], table.k_closest(this_node.id, 1));
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn routing_table_snapshot_keeps_subnet_limits() {
+        let this_node = make_node_at(0, "10.0.0.1:10");
+        let limits = IpLimits {
+            per_bucket: 2,
+            per_table: 2,
+        };
+        let mut table = RoutingTable::with_ip_limits(this_node, 20, limits);
+        let ipv6 = "[2001:db8::1]:10";
+        table.insert(make_node_at(1, ipv6));
+        table.insert(make_node_at(2, ipv6));
+        let json = serde_json::to_string(&table).unwrap();
+        let mut restored: RoutingTable = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            table.k_closest(BitKey(0), 20),
+            restored.k_closest(BitKey(0), 20)
+        );
+        assert_eq!(
+            KBucketInsert::Rejected,
+            restored.insert(make_node_at(4, ipv6))
+        );
+    }
 }
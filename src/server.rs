@@ -1,29 +1,162 @@
 use crate::base::{BitKey, Node};
-use crate::messages::{Header, Message, RPCPayload, TransactionID};
+use crate::blob::{self, BlobError, BlobReader, Manifest};
+use crate::erasure::{ErasureError, ErasureOptions};
+use crate::krpc::NodeId;
+use crate::limits::{DropCounters, Limits, Rate, RateLimiter, Suspicion};
+use crate::mainline::{self, MainlineConfig};
+use crate::messages::{
+    Capabilities, ErrorCode, Header, Message, MessageRef, NetworkKey, ParseError, PublishedValue,
+    RPCPayload, SyncEntry, Token, TransactionID, ValuePage, NETWORK_TAG_BYTES,
+};
+use crate::providers::ProviderTable;
+use crate::quorum::{resolve, QuorumRead, ReadQuorum};
 use crate::rand::rngs::ThreadRng;
 use crate::rand::thread_rng;
-use crate::routing::{KBucketInsert, RoutingTable};
-use std::collections::HashMap;
-use std::convert::TryFrom;
+use crate::rand::Rng;
+use crate::record::{DeleteSignature, MutableRecord, Publisher};
+use crate::routing::{IpLimits, KBucketInsert, RoutingTable};
+use crate::store::{InsertError, ValueStore};
+use crate::sync::{responsibility_range, split_range, SYNC_PERIOD};
+use crate::token::TokenSecrets;
+use crate::transport::{now_micros, ReplayGuard, SecureChannel, OVERHEAD};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::sync::mpsc::{channel, Receiver, RecvError, SendError, Sender};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // How big to make our buckets
 const K: usize = 20;
 const BUF_SIZE: usize = 2048;
 
+/// Represents the options for a Store operation.
+#[derive(Clone, Copy, Debug)]
+pub struct StoreOptions {
+    /// How many disjoint paths to use when looking up the nodes to store at.
+    ///
+    /// Using more paths makes it harder for a single malicious node to
+    /// hijack the lookup, at the cost of contacting more nodes.
+    pub disjoint_paths: usize,
+    /// How many of the closest nodes need to acknowledge the write for it to succeed.
+    ///
+    /// The server responds with `WriteQuorumNotMet` if fewer nodes acknowledge it,
+    /// even though the nodes that did still hold on to the write.
+    pub write_quorum: usize,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            disjoint_paths: 1,
+            write_quorum: 1,
+        }
+    }
+}
+
+/// Represents the options for a Get operation.
+#[derive(Clone, Copy, Debug)]
+pub struct GetOptions {
+    /// How many disjoint paths to use when looking up the value.
+    ///
+    /// See [StoreOptions](struct.StoreOptions.html) for more details.
+    pub disjoint_paths: usize,
+    /// If present, the value is read from several of the closest nodes, and checked for conflicts.
+    ///
+    /// Otherwise, the values of every node we hear from are merged together.
+    pub quorum: Option<ReadQuorum>,
+}
+
+impl Default for GetOptions {
+    fn default() -> Self {
+        GetOptions {
+            disjoint_paths: 1,
+            quorum: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ToServerMsg {
-    Store(String, String),
-    Get(String),
+    Store(String, Vec<u8>, StoreOptions),
+    // Only stores the value if the key is at the given version
+    StoreIf(String, Vec<u8>, u64, StoreOptions),
+    // Deletes every value we've stored under the key so far
+    Delete(String, StoreOptions),
+    // Stores the data as a blob with parity chunks, so it can be read back with GetBlob
+    // even if some of its chunks are lost, ignoring disjoint paths
+    StoreCoded(String, Vec<u8>, ErasureOptions, StoreOptions),
+    // Splits the data into chunks keyed by their hash, and publishes a manifest under the name
+    PutBlob(String, Vec<u8>, StoreOptions),
+    GetBlob(String, GetOptions),
+    Get(String, GetOptions),
+    // Records are boxed, to keep the other messages small
+    StoreRecord(Box<MutableRecord>, StoreOptions),
+    GetRecord(BitKey, GetOptions),
+    Provide(BitKey, StoreOptions),
+    FindProviders(BitKey, GetOptions),
+    // Looks up the peers for a torrent, only on the mainline DHT
+    GetPeers(NodeId),
+    // Announces that we're a peer for a torrent on some port, only on the mainline DHT
+    AnnouncePeer(NodeId, u16),
+    Stats,
 }
 
 #[derive(Debug)]
 pub enum FromServerMsg {
-    StoreResp,
-    GetResp(Option<String>),
+    // Every write succeeds with the number of replicas that acknowledged it
+    StoreResp(usize),
+    // Fewer replicas than the write quorum acknowledged a write, with this many doing so
+    WriteQuorumNotMet(usize),
+    // Values are sorted by precedence, so the first value wins any conflict
+    GetResp(Vec<PublishedValue>),
+    // A conditional store found the key at another version, which we know if we checked it ourselves
+    VersionMismatch(Option<u64>),
+    QuorumGetResp(QuorumRead),
+    // Fewer replicas than the quorum answered a quorum read, with this many answering
+    QuorumNotMet(usize),
+    CodingFailed(ErasureError),
+    // The next piece of the blob being read, in order
+    BlobData(Vec<u8>),
+    // The length of the blob, once all of it was read, or nothing if some of it couldn't be found
+    GetBlobResp(Option<usize>),
+    BlobFailed(BlobError),
+    GetRecordResp(Option<MutableRecord>),
+    FindProvidersResp(Vec<Node>),
+    GetPeersResp(Vec<SocketAddr>),
+    StatsResp(DropCounters),
+    // The request doesn't exist in the protocol the server is speaking
+    Unsupported,
+}
+
+/// Represents the options used to configure a server.
+///
+/// The default configuration is an open network, with no encryption.
+#[derive(Default)]
+pub struct ServerConfig {
+    /// If present, only messages tagged with this key are accepted.
+    ///
+    /// This lets us keep separate networks running on the same hosts, with nodes
+    /// from one network never joining another.
+    pub network_key: Option<NetworkKey>,
+    /// If present, all traffic is encrypted and authenticated with this channel.
+    ///
+    /// Every node on the network needs to be using the same key, and datagrams
+    /// that fail to open with this key are dropped.
+    pub channel: Option<SecureChannel>,
+    /// The limits we place on the traffic we handle.
+    pub limits: Limits,
+    /// The limits we place on nodes sharing a subnet in our routing table.
+    pub ip_limits: IpLimits,
+    /// If present, the largest datagram we'll send, including tags and encryption.
+    ///
+    /// Lists of nodes get trimmed to fit in this size, to avoid fragmentation.
+    /// 1232 bytes is a safe choice for most networks.
+    pub mtu: Option<usize>,
+    /// If present, the server joins the mainline DHT instead of a network of our own.
+    ///
+    /// Private networks, encryption, and the MTU don't apply to the mainline DHT,
+    /// so they're ignored in this mode.
+    pub mainline: Option<MainlineConfig>,
 }
 
 pub struct ServerSender {
@@ -39,11 +172,65 @@ impl ServerSender {
     pub fn receive(&self) -> Result<FromServerMsg, RecvError> {
         self.from.recv()
     }
+
+    /// Announce that this node provides the content for a key.
+    ///
+    /// The server responds with `StoreResp` once the nodes closest to the key
+    /// have acknowledged the announcement. Announcements expire, so they should be
+    /// repeated for as long as we hold the content.
+    pub fn provide(
+        &self,
+        key: BitKey,
+        options: StoreOptions,
+    ) -> Result<(), SendError<ToServerMsg>> {
+        self.send(ToServerMsg::Provide(key, options))
+    }
+
+    /// Look for the nodes providing the content for a key.
+    ///
+    /// The server responds with `FindProvidersResp`, which is empty if no providers were found.
+    pub fn find_providers(
+        &self,
+        key: BitKey,
+        options: GetOptions,
+    ) -> Result<(), SendError<ToServerMsg>> {
+        self.send(ToServerMsg::FindProviders(key, options))
+    }
+
+    /// Store a blob of any size, under a name.
+    ///
+    /// The blob is split into chunks, each stored under the hash of its content,
+    /// and a manifest listing these chunks is stored under the name. The server
+    /// responds with `StoreResp` once every node holding a chunk or the manifest
+    /// has answered, if each of them was acknowledged by the write quorum, and
+    /// with `WriteQuorumNotMet` otherwise.
+    pub fn put_blob(
+        &self,
+        name: String,
+        data: Vec<u8>,
+        options: StoreOptions,
+    ) -> Result<(), SendError<ToServerMsg>> {
+        self.send(ToServerMsg::PutBlob(name, data, options))
+    }
+
+    /// Read the blob stored under a name.
+    ///
+    /// The server fetches a few chunks of the blob at a time, and responds with
+    /// `BlobData` for each piece of the blob, in order, as soon as it can. It then
+    /// responds with `GetBlobResp`, which holds the length of the blob, or nothing
+    /// if the blob couldn't be read in full.
+    pub fn get_blob(
+        &self,
+        name: String,
+        options: GetOptions,
+    ) -> Result<(), SendError<ToServerMsg>> {
+        self.send(ToServerMsg::GetBlob(name, options))
+    }
 }
 
 pub struct ServerReceiver {
-    from: Receiver<ToServerMsg>,
-    to: Sender<FromServerMsg>,
+    pub(crate) from: Receiver<ToServerMsg>,
+    pub(crate) to: Sender<FromServerMsg>,
 }
 
 pub fn make_server_comms() -> (ServerSender, ServerReceiver) {
@@ -60,8 +247,30 @@ pub fn make_server_comms() -> (ServerSender, ServerReceiver) {
     (sender, receiver)
 }
 
+// How many mismatched responses a peer can send before we stop trusting it, for a while
+const SUSPICIOUS_THRESHOLD: u32 = 3;
+// How many values a lookup keeps, which is as many as a single key can report holding
+const MAX_QUERY_VALUES: usize = u8::MAX as usize;
+// How many addresses we keep track of mismatched responses from
+const MAX_SUSPICIOUS: usize = 4096;
+// How often we tell an address that we're dropping its requests
+const RATE_LIMIT_NOTICES: Rate = Rate {
+    burst: 1,
+    per_second: 1,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransactionCheck {
+    // We never started this transaction
+    Unknown,
+    // The response comes from the node we sent the request to
+    Valid,
+    // The response matches a transaction, but doesn't come from the right node
+    Mismatched,
+}
+
 struct TransactionTable {
-    transactions: HashMap<TransactionID, (Instant, BitKey)>,
+    transactions: HashMap<TransactionID, (Instant, Node)>,
 }
 
 impl TransactionTable {
@@ -71,13 +280,19 @@ impl TransactionTable {
         }
     }
 
-    fn insert(&mut self, header: Header) {
-        let expiration = (Instant::now(), header.node_id);
+    fn insert(&mut self, header: Header, to: Node) {
+        let expiration = (Instant::now(), to);
         self.transactions.insert(header.transaction_id, expiration);
     }
 
-    fn contains(&self, transaction_id: TransactionID) -> bool {
-        self.transactions.contains_key(&transaction_id)
+    fn check(&self, header: Header, src: SocketAddr) -> TransactionCheck {
+        match self.transactions.get(&header.transaction_id) {
+            None => TransactionCheck::Unknown,
+            Some((_, node)) if node.id == header.node_id && node.udp_addr == src => {
+                TransactionCheck::Valid
+            }
+            Some(_) => TransactionCheck::Mismatched,
+        }
     }
 
     fn remove(&mut self, transaction_id: TransactionID) -> bool {
@@ -86,9 +301,9 @@ impl TransactionTable {
 
     fn remove_stale(&mut self, buf: &mut Vec<BitKey>) {
         let now = Instant::now();
-        self.transactions.retain(|_, (then, key)| {
+        self.transactions.retain(|_, (then, node)| {
             if now.duration_since(*then) > Duration::new(5, 0) {
-                buf.push(*key);
+                buf.push(node.id);
                 false
             } else {
                 true
@@ -99,15 +314,49 @@ impl TransactionTable {
 
 #[derive(Debug, Clone, PartialEq)]
 enum QueryIntention {
-    Store(String, String),
+    Store(String, PublishedValue),
+    // The version we expect the key to be at, and the value to store if it is
+    StoreIf(String, u64, PublishedValue),
+    Delete(String, u64, DeleteSignature),
+    // The keys and values to store at once, how many nodes store each value,
+    // and how many of the keys need to reach a quorum for the write to succeed
+    StoreEach(Vec<(String, PublishedValue)>, usize, usize),
+    // The keys of the chunks of a blob we're missing
+    GetChunks(Vec<String>),
     Get(String),
+    // Like a Get, but the value is the manifest of a blob
+    GetManifest(String),
+    StoreRecord(MutableRecord),
+    GetRecord(BitKey),
+    Provide(BitKey),
+    FindProviders(BitKey),
 }
 
 impl QueryIntention {
-    fn key_to_find(&self) -> Option<String> {
+    fn target(&self) -> BitKey {
+        match self {
+            QueryIntention::Store(key, _)
+            | QueryIntention::StoreIf(key, _, _)
+            | QueryIntention::Delete(key, _, _)
+            | QueryIntention::GetManifest(key)
+            | QueryIntention::Get(key) => BitKey::from_hash(key),
+            // Each path has a target of its own, so this is only the target of the first one
+            QueryIntention::StoreEach(_, _, _) | QueryIntention::GetChunks(_) => {
+                BitKey::from_hash(&self.path_key(0).unwrap_or_default())
+            }
+            QueryIntention::StoreRecord(record) => record.key(),
+            QueryIntention::GetRecord(key)
+            | QueryIntention::Provide(key)
+            | QueryIntention::FindProviders(key) => *key,
+        }
+    }
+
+    // The key a path looks for, when each path looks for a different key
+    fn path_key(&self, path: usize) -> Option<String> {
         match self {
-            QueryIntention::Store(_, _) => None,
-            QueryIntention::Get(key) => Some(key.clone()),
+            QueryIntention::StoreEach(entries, _, _) => entries.get(path).map(|e| e.0.clone()),
+            QueryIntention::GetChunks(keys) => keys.get(path).cloned(),
+            _ => None,
         }
     }
 }
@@ -124,6 +373,8 @@ struct NodeQuery {
     node: Node,
     status: QueryStatus,
     distance: u128,
+    // The token this node gave us, allowing us to store values with it
+    token: Option<Token>,
 }
 
 impl NodeQuery {
@@ -132,29 +383,204 @@ impl NodeQuery {
             node,
             status: QueryStatus::Empty,
             distance: node.id.distance(target),
+            token: None,
         }
     }
 }
 
-struct Query {
+// The anti-entropy requests we've sent, along with the range of keys each one covers
+struct SyncRequests {
+    transactions: TransactionTable,
+    ranges: HashMap<TransactionID, (BitKey, BitKey)>,
+}
+
+impl SyncRequests {
+    fn new() -> Self {
+        SyncRequests {
+            transactions: TransactionTable::new(),
+            ranges: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, header: Header, to: Node, range: (BitKey, BitKey)) {
+        self.transactions.insert(header, to);
+        self.ranges.insert(header.transaction_id, range);
+    }
+
+    // Returns the range a response covers, if it answers one of our requests
+    fn take(&mut self, header: Header, src: SocketAddr) -> Option<(BitKey, BitKey)> {
+        if self.transactions.check(header, src) != TransactionCheck::Valid {
+            return None;
+        }
+        self.transactions.remove(header.transaction_id);
+        self.ranges.remove(&header.transaction_id)
+    }
+
+    fn remove_stale(&mut self) {
+        self.transactions.remove_stale(&mut Vec::new());
+        let transactions = &self.transactions.transactions;
+        self.ranges.retain(|id, _| transactions.contains_key(id));
+    }
+}
+
+// How a value came to be sent to us
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StoreKind {
+    // Stored by its publisher, alongside the other values of the key
+    Plain,
+    // Stored by its publisher, if the key is at this version
+    Conditional(u64),
+    // Copied over by some other node, to repair a replica
+    Copy,
+}
+
+// How long we reuse the summary of a range, when the store doesn't change
+const HASH_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// The summaries of the ranges we've compared lately
+struct HashCache {
+    hashes: HashMap<(BitKey, BitKey), Vec<u64>>,
+    // The changes the store had gone through when we started caching
+    changes: u64,
+    created: Instant,
+}
+
+// How many times we send a write to a replica, before giving up on it
+const WRITE_ATTEMPTS: u32 = 3;
+
+// How a replica answered one of our writes
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WriteAnswer {
+    Acked,
+    Refused,
+    // The replica had the key at another version than a conditional store expected
+    Mismatched,
+}
+
+// A write sent to the closest nodes of a query, waiting for each of them to acknowledge it
+struct PendingWrite {
+    transactions: TransactionTable,
+    // The requests yet to be answered, with the replica we sent them to and how many times we've sent them
+    //
+    // A replica may be sent several requests, when it's close to several shards of a value.
+    waiting: HashMap<TransactionID, (Node, RPCPayload, u32)>,
+    acks: usize,
+    // The acknowledgements for each key written, since a write may cover several keys
+    key_acks: HashMap<String, usize>,
+    // How many replicas need to acknowledge a key for it to count
+    quorum: usize,
+    // How many keys need to reach that quorum for the write to succeed
+    keys_needed: usize,
+    mismatched: bool,
+}
+
+// A store of a value we published, for a node that gave us a token
+fn store_payload(key: &str, value: &PublishedValue, token: Token) -> RPCPayload {
+    let val = value.value.clone();
+    RPCPayload::Store(key.into(), val, value.version, value.signature, token)
+}
+
+// The key a write is for, so that acknowledgements can be counted per key
+fn written_key(payload: &RPCPayload) -> String {
+    match payload {
+        RPCPayload::Store(key, _, _, _, _)
+        | RPCPayload::StoreIf(key, _, _, _, _)
+        | RPCPayload::Delete(key, _, _, _) => key.clone(),
+        _ => String::new(),
+    }
+}
+
+impl PendingWrite {
+    fn new(quorum: usize, keys_needed: usize) -> Self {
+        PendingWrite {
+            transactions: TransactionTable::new(),
+            waiting: HashMap::new(),
+            acks: 0,
+            key_acks: HashMap::new(),
+            quorum,
+            keys_needed,
+            mismatched: false,
+        }
+    }
+
+    // Keep track of a request we've just sent to a replica, after some previous attempts
+    fn sent(&mut self, header: Header, node: Node, payload: RPCPayload, attempts: u32) {
+        self.transactions.insert(header, node);
+        self.key_acks.entry(written_key(&payload)).or_default();
+        let entry = (node, payload, attempts + 1);
+        self.waiting.insert(header.transaction_id, entry);
+    }
+
+    // Record the answer of a replica, returning false if this isn't one of our transactions
+    fn answer(&mut self, header: Header, src: SocketAddr, answer: WriteAnswer) -> bool {
+        if self.transactions.check(header, src) != TransactionCheck::Valid {
+            return false;
+        }
+        self.transactions.remove(header.transaction_id);
+        let payload = self.waiting.remove(&header.transaction_id).map(|w| w.1);
+        match answer {
+            WriteAnswer::Acked => {
+                self.acks += 1;
+                if let Some(payload) = payload {
+                    *self.key_acks.entry(written_key(&payload)).or_default() += 1;
+                }
+            }
+            WriteAnswer::Refused => {}
+            WriteAnswer::Mismatched => self.mismatched = true,
+        }
+        true
+    }
+
+    // Give up on the requests that weren't answered in time, returning those worth retrying
+    fn timed_out(&mut self) -> Vec<(Node, RPCPayload, u32)> {
+        self.transactions.remove_stale(&mut Vec::new());
+        let live = &self.transactions.transactions;
+        let stale: Vec<TransactionID> = self
+            .waiting
+            .keys()
+            .filter(|id| !live.contains_key(id))
+            .copied()
+            .collect();
+        stale
+            .into_iter()
+            .filter_map(|id| self.waiting.remove(&id))
+            .filter(|(_, _, attempts)| *attempts < WRITE_ATTEMPTS)
+            .collect()
+    }
+
+    fn is_done(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    fn outcome(&self) -> FromServerMsg {
+        let keys = self
+            .key_acks
+            .values()
+            .filter(|&&acks| acks >= self.quorum)
+            .count();
+        // Some replica saw another version, so the client needs to read the key again
+        if self.mismatched {
+            FromServerMsg::VersionMismatch(None)
+        } else if self.quorum == 0 || keys >= self.keys_needed {
+            FromServerMsg::StoreResp(self.acks)
+        } else {
+            FromServerMsg::WriteQuorumNotMet(self.acks)
+        }
+    }
+}
+
+// A single path through the network, used to look up the target of a query.
+struct Path {
     target: BitKey,
-    intention: QueryIntention,
     closest: Vec<NodeQuery>,
-    transactions: TransactionTable,
     final_k: bool,
 }
 
-impl Query {
-    fn new(intention: QueryIntention) -> Self {
-        let key = match &intention {
-            QueryIntention::Store(key, _) => key,
-            QueryIntention::Get(key) => key,
-        };
-        Query {
-            target: BitKey::from_hash(&key),
-            intention,
+impl Path {
+    fn new(target: BitKey) -> Self {
+        Path {
+            target,
             closest: Vec::with_capacity(K),
-            transactions: TransactionTable::new(),
             final_k: false,
         }
     }
@@ -166,15 +592,15 @@ impl Query {
     }
 
     fn add_node(&mut self, node: Node) -> bool {
-        if let Err(index) = self.find_node(node.id) {
-            self.closest
-                .insert(index, NodeQuery::new(node, self.target));
-            if self.closest.len() > K {
-                self.closest.pop();
+        match self.find_node(node.id) {
+            // Nodes further than the K closest are of no use to us
+            Err(index) if index < K => {
+                self.closest
+                    .insert(index, NodeQuery::new(node, self.target));
+                self.closest.truncate(K);
+                true
             }
-            true
-        } else {
-            false
+            _ => false,
         }
     }
 
@@ -184,6 +610,12 @@ impl Query {
         }
     }
 
+    fn set_token(&mut self, target: BitKey, token: Token) {
+        if let Ok(index) = self.find_node(target) {
+            self.closest[index].token = Some(token);
+        }
+    }
+
     fn remove(&mut self, key: BitKey) {
         if let Ok(index) = self.find_node(key) {
             self.closest.remove(index);
@@ -209,108 +641,1116 @@ impl Query {
     }
 }
 
+// A query runs through one or more disjoint paths, following S/Kademlia.
+// No node is ever allowed to be part of more than one path, so a single
+// malicious node returning bogus nodes can only hijack the path it's part of.
+struct Query {
+    target: BitKey,
+    intention: QueryIntention,
+    paths: Vec<Path>,
+    // Every node that's already been assigned to a path
+    claimed: HashSet<BitKey>,
+    // Whether a node is kept from being part of more than one path
+    disjoint: bool,
+    // How many paths have been given their initial nodes, when paths take turns
+    started: usize,
+    transactions: TransactionTable,
+    // The path each transaction belongs to, since a node may be asked on behalf of several
+    transaction_paths: HashMap<TransactionID, usize>,
+    // The page each FindValue transaction asked for, when it wasn't the first one
+    page_starts: HashMap<TransactionID, u8>,
+    // The newest valid record we've found, when looking for a mutable record
+    record: Option<MutableRecord>,
+    // The providers we've found, when looking for the providers of a key
+    providers: Vec<Node>,
+    // The values we've found, merged from every node that responded
+    values: Vec<PublishedValue>,
+    // For quorum reads, how many replicas need to answer, and how to settle conflicts
+    read_quorum: Option<ReadQuorum>,
+    // For writes, how many replicas need to acknowledge the write
+    write_quorum: usize,
+    // The values each node sent us, kept apart to spot disagreements and stale replicas
+    answers: HashMap<BitKey, Vec<PublishedValue>>,
+}
+
+impl Query {
+    fn new(intention: QueryIntention, disjoint_paths: usize) -> Self {
+        let target = intention.target();
+        let paths = (0..disjoint_paths.max(1))
+            .map(|_| Path::new(target))
+            .collect();
+        Query {
+            target,
+            intention,
+            paths,
+            claimed: HashSet::new(),
+            disjoint: true,
+            started: disjoint_paths.max(1),
+            transactions: TransactionTable::new(),
+            transaction_paths: HashMap::new(),
+            page_starts: HashMap::new(),
+            record: None,
+            providers: Vec::new(),
+            values: Vec::new(),
+            read_quorum: None,
+            write_quorum: 0,
+            answers: HashMap::new(),
+        }
+    }
+
+    // A query with one path for each of several keys, each path looking for its own key
+    //
+    // These paths look for different keys, so a node may well be close to several of them.
+    fn for_keys(intention: QueryIntention, count: usize) -> Self {
+        let mut query = Query::new(intention, 1);
+        query.disjoint = false;
+        query.paths = (0..count)
+            .filter_map(|i| query.intention.path_key(i))
+            .map(|key| Path::new(BitKey::from_hash(&key)))
+            .collect();
+        query.started = 0;
+        query
+    }
+
+    // A query looking for the nodes to send a write to
+    fn write(intention: QueryIntention, options: StoreOptions) -> Self {
+        let mut query = Query::new(intention, options.disjoint_paths);
+        query.write_quorum = options.write_quorum;
+        query
+    }
+
+    // Keep track of a record if it's valid, and newer than what we've seen so far
+    fn offer_record(&mut self, record: MutableRecord) {
+        let newer = self.record.as_ref().is_none_or(|r| record.seq > r.seq);
+        if newer && record.key() == self.target && record.verify() {
+            self.record = Some(record);
+        }
+    }
+
+    // Keep track of providers we haven't seen yet
+    fn offer_providers<I: IntoIterator<Item = Node>>(&mut self, providers: I) {
+        for provider in providers {
+            if !self.providers.iter().any(|p| p.id == provider.id) {
+                self.providers.push(provider);
+            }
+        }
+    }
+
+    // Add values to the ones we've found, keeping the highest version of each
+    fn offer_values<I: IntoIterator<Item = PublishedValue>>(&mut self, values: I) {
+        for value in values {
+            let existing = self
+                .values
+                .iter_mut()
+                .find(|v| v.publisher == value.publisher && v.value == value.value);
+            match existing {
+                Some(existing) => existing.version = existing.version.max(value.version),
+                None => self.values.push(value),
+            }
+        }
+        // Every node can send us a whole key's worth of values, so we only keep the best ones
+        if self.values.len() > MAX_QUERY_VALUES {
+            self.values.sort_by(|a, b| b.precedence(a));
+            self.values.truncate(MAX_QUERY_VALUES);
+        }
+    }
+
+    // The answers of the K closest nodes that responded, including ours if we're among them
+    fn replica_answers(&self, this_id: BitKey) -> Vec<Vec<PublishedValue>> {
+        let mut replicas: Vec<(u128, BitKey)> = self
+            .closest()
+            .iter()
+            .filter(|n| n.status == QueryStatus::Finished)
+            .map(|n| (n.distance, n.node.id))
+            .collect();
+        let queried = replicas.iter().any(|(_, id)| *id == this_id);
+        if self.answers.contains_key(&this_id) && !queried {
+            replicas.push((this_id.distance(self.target), this_id));
+        }
+        replicas.sort_unstable_by_key(|(distance, _)| *distance);
+        replicas.truncate(K);
+        replicas
+            .iter()
+            .map(|(_, id)| self.answers.get(id).cloned().unwrap_or_default())
+            .collect()
+    }
+
+    // The closest nodes missing some of the values we settled on, along with what they're missing
+    fn stale_replicas(&self, values: &[PublishedValue]) -> Vec<(Node, Token, Vec<PublishedValue>)> {
+        let mut stale = Vec::new();
+        for node in self.closest() {
+            let token = match (node.status, node.token) {
+                (QueryStatus::Finished, Some(token)) => token,
+                _ => continue,
+            };
+            let answer = self
+                .answers
+                .get(&node.node.id)
+                .map_or(&[][..], Vec::as_slice);
+            let missing: Vec<PublishedValue> = values
+                .iter()
+                .filter(|v| {
+                    !answer.iter().any(|a| {
+                        a.publisher == v.publisher && a.value == v.value && a.version >= v.version
+                    })
+                })
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                stale.push((node.node, token, missing));
+            }
+        }
+        stale
+    }
+
+    // The version of the key, according to the values we've found
+    fn version(&self) -> u64 {
+        self.values.iter().map(|v| v.version).max().unwrap_or(0)
+    }
+
+    // Split the initial nodes between each path, returning the first node to contact in each
+    fn start(&mut self, nodes: Vec<Node>) -> Vec<(usize, Node)> {
+        let path_count = self.paths.len();
+        for (i, node) in nodes.into_iter().enumerate() {
+            self.add_node(i % path_count, node);
+        }
+        self.next_nodes()
+    }
+
+    // Give the paths waiting their turn the initial nodes closest to their own target,
+    // for paths with different targets, returning the first node to contact in each
+    //
+    // A query can look up any number of keys, so only so many of its paths run at once,
+    // and the others wait for those to finish.
+    fn start_each<F: Fn(BitKey) -> Vec<Node>>(
+        &mut self,
+        max_in_flight: usize,
+        closest_to: F,
+    ) -> Vec<(usize, Node)> {
+        let started = &self.paths[..self.started];
+        let mut in_flight = started.iter().filter(|p| !p.all_done()).count();
+        let mut first = Vec::new();
+        while self.started < self.paths.len() && in_flight < max_in_flight {
+            let i = self.started;
+            self.started += 1;
+            for node in closest_to(self.paths[i].target) {
+                self.add_node(i, node);
+            }
+            // A path nobody can help with is done before it starts
+            if let Some(node) = self.paths[i].get_closest() {
+                first.push((i, node));
+                in_flight += 1;
+            }
+        }
+        first
+    }
+
+    // The next node to contact in each path that isn't done yet, along with its path
+    fn next_nodes(&self) -> Vec<(usize, Node)> {
+        self.paths
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| !path.final_k)
+            .filter_map(|(i, path)| path.get_closest().map(|node| (i, node)))
+            .collect()
+    }
+
+    fn add_node(&mut self, path: usize, node: Node) -> bool {
+        if self.disjoint && self.claimed.contains(&node.id) {
+            return false;
+        }
+        let added = self.paths[path].add_node(node);
+        if added {
+            self.claimed.insert(node.id);
+        }
+        added
+    }
+
+    fn path_of(&self, key: BitKey) -> Option<usize> {
+        self.paths.iter().position(|p| p.find_node(key).is_ok())
+    }
+
+    // The path a response belongs to, forgetting the transaction it answers
+    fn take_path(&mut self, header: Header) -> Option<usize> {
+        match self.transaction_paths.remove(&header.transaction_id) {
+            Some(path) => Some(path),
+            None => self.path_of(header.node_id),
+        }
+    }
+
+    fn remove(&mut self, key: BitKey) {
+        for path in &mut self.paths {
+            path.remove(key);
+        }
+    }
+
+    fn all_done(&self) -> bool {
+        self.started == self.paths.len() && self.paths.iter().all(Path::all_done)
+    }
+
+    // Combine the results of every path, to get the K closest nodes overall
+    fn closest(&self) -> Vec<NodeQuery> {
+        let mut all: Vec<NodeQuery> = self
+            .paths
+            .iter()
+            .flat_map(|p| &p.closest)
+            .cloned()
+            .collect();
+        all.sort_by_key(|x| x.distance);
+        all.truncate(K);
+        all
+    }
+}
+
+// How far ahead of our clock we accept versions, in milliseconds
+const MAX_VERSION_SKEW: u64 = 10 * 60 * 1000;
+
+// Versions past this would pin a key, since nobody could store a newer version
+fn max_version() -> u64 {
+    timestamp_version().saturating_add(MAX_VERSION_SKEW)
+}
+
+// The version we give to plain stores and deletes, in milliseconds since the epoch
+fn timestamp_version() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// Represents the result of reading a datagram from our socket
+enum Datagram {
+    Message(Message, SocketAddr),
+    // We could read the header, but don't know what kind of message this is
+    Unsupported(Header, SocketAddr),
+}
+
 struct ServerHandle {
     sock: UdpSocket,
     receiver: ServerReceiver,
     table: RoutingTable,
-    key_store: HashMap<String, String>,
+    // The key pair our ID comes from, which we sign deletes with
+    publisher: Publisher,
+    values: ValueStore,
+    // Signed records, which only their publisher can modify
+    records: HashMap<BitKey, MutableRecord>,
+    // The nodes that have announced they provide the content for each key
+    providers: ProviderTable,
+    // How many bytes of storage our records take up
+    record_bytes: usize,
     query: Option<Query>,
+    // The writes we sent out for each client request, oldest first, until every replica
+    // has acknowledged them or given up
+    writes: VecDeque<PendingWrite>,
+    // The blob we're reading, one level of chunks at a time
+    blob: Option<BlobReader>,
     keep_alives: TransactionTable,
+    // The copies we sent to stale replicas, kept apart so their timeouts don't evict anyone
+    repairs: TransactionTable,
+    syncs: SyncRequests,
+    hash_cache: HashCache,
+    // When we last compared the values we hold with one of our neighbours
+    synced_at: Instant,
+    tokens: TokenSecrets,
+    // The addresses that sent us mismatched responses lately
+    suspicious: Suspicion,
+    // The extensions supported by each node we've pinged, or been pinged by
+    peers: HashMap<BitKey, Capabilities>,
+    network_key: Option<NetworkKey>,
+    channel: Option<SecureChannel>,
+    // The datagrams we've opened with our channel lately, to drop them if they're sent again
+    replay: ReplayGuard,
+    mtu: Option<usize>,
+    limits: Limits,
+    ip_limiter: RateLimiter<IpAddr>,
+    node_limiter: RateLimiter<BitKey>,
+    // How often we tell each address that it's being rate limited
+    notice_limiter: RateLimiter<IpAddr>,
+    dropped: DropCounters,
     rng: ThreadRng,
     buf: Box<[u8]>,
+    // This holds datagrams as they appear on the wire, when we use a secure channel
+    sealed_buf: Box<[u8]>,
 }
 
 impl ServerHandle {
-    fn send_message(&mut self, message: Message, addr: SocketAddr) -> io::Result<()> {
-        let amt = message.write(&mut *self.buf);
-        self.sock.send_to(&self.buf[..amt], addr)?;
+    fn send_message(&mut self, mut message: Message, addr: SocketAddr) -> io::Result<()> {
+        // Even without an MTU, lists need to fit in our buffer
+        let mut room = self.mtu.unwrap_or(BUF_SIZE);
+        if self.network_key.is_some() {
+            room = room.saturating_sub(NETWORK_TAG_BYTES);
+        }
+        if self.channel.is_some() {
+            room = room.saturating_sub(OVERHEAD);
+        }
+        message.trim_to(room);
+        let written = match &self.network_key {
+            None => message.write(&mut self.buf[..]),
+            Some(key) => message.write_tagged(key, &mut self.buf[..]),
+        };
+        let amt = match written {
+            Ok(amt) => amt,
+            Err(_) => {
+                self.dropped.unencodable += 1;
+                return Ok(());
+            }
+        };
+        match &mut self.channel {
+            None => self.sock.send_to(&self.buf[..amt], addr)?,
+            Some(channel) => {
+                let sealed = channel.seal(&self.buf[..amt], &mut self.sealed_buf);
+                self.sock.send_to(&self.sealed_buf[..sealed], addr)?
+            }
+        };
         Ok(())
     }
 
+    fn receive_message(&mut self) -> Option<Datagram> {
+        let (data, src) = match &self.channel {
+            None => {
+                let (amt, src) = self.sock.recv_from(&mut self.buf).ok()?;
+                if !self.ip_limiter.allow(src.ip(), Instant::now()) {
+                    self.dropped.ip_rate_limited += 1;
+                    return None;
+                }
+                (&self.buf[..amt], src)
+            }
+            Some(channel) => {
+                let (amt, src) = self.sock.recv_from(&mut self.sealed_buf).ok()?;
+                // We check this before opening, to avoid spending time decrypting floods
+                if !self.ip_limiter.allow(src.ip(), Instant::now()) {
+                    self.dropped.ip_rate_limited += 1;
+                    return None;
+                }
+                let (stamp, amt) = match channel.open(&self.sealed_buf[..amt], &mut self.buf) {
+                    None => {
+                        self.dropped.unauthenticated += 1;
+                        return None;
+                    }
+                    Some((stamp, data)) => (stamp, data.len()),
+                };
+                if !self.replay.accept(stamp, now_micros()) {
+                    self.dropped.replayed += 1;
+                    return None;
+                }
+                (&self.buf[..amt], src)
+            }
+        };
+        let message = match MessageRef::parse(data, self.network_key.as_ref()) {
+            Ok(message) => message,
+            Err(_) => {
+                self.dropped.malformed += 1;
+                return None;
+            }
+        };
+        let header = message.header();
+        let payload = match message.payload() {
+            Ok(payload) => payload,
+            Err(ParseError::UnknownMessageType) => return Some(Datagram::Unsupported(header, src)),
+            Err(_) => {
+                self.dropped.malformed += 1;
+                return None;
+            }
+        };
+        let message = Message {
+            header,
+            payload: payload.into(),
+        };
+        Some(Datagram::Message(message, src))
+    }
+
+    fn supports(&self, id: BitKey, capabilities: Capabilities) -> bool {
+        self.peers
+            .get(&id)
+            .is_some_and(|theirs| theirs.supports(capabilities))
+    }
+
+    fn send_error(
+        &mut self,
+        header: Header,
+        code: ErrorCode,
+        reason: &str,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        // Nodes that don't understand errors will just see a timeout instead
+        if !self.supports(header.node_id, Capabilities::ERROR_RESPONSES) {
+            return Ok(());
+        }
+        let payload = RPCPayload::Error(code, reason.into());
+        self.respond(header, payload, src)
+    }
+
+    fn ping(&mut self, node: Node) -> io::Result<()> {
+        let already_pinging = self
+            .keep_alives
+            .transactions
+            .values()
+            .any(|(_, n)| *n == node);
+        if already_pinging {
+            return Ok(());
+        }
+        if self.keep_alives.transactions.len() >= self.limits.max_pings_in_flight {
+            self.dropped.pings_skipped += 1;
+            return Ok(());
+        }
+        let payload = RPCPayload::Ping(Capabilities::ours());
+        let message = Message::create(&mut self.rng, self.table.this_node_id(), payload);
+        self.keep_alives.insert(message.header, node);
+        self.send_message(message, node.udp_addr)
+    }
+
+    fn respond(&mut self, request: Header, payload: RPCPayload, src: SocketAddr) -> io::Result<()> {
+        let message = Message::response(self.table.this_node_id(), request, payload);
+        self.send_message(message, src)
+    }
+
+    // Tell a node we're dropping its requests, unless we've told it so recently
+    //
+    // The source of a flood may well be spoofed, so we answer a flood with a trickle.
+    fn reject_rate_limited(&mut self, header: Header, src: SocketAddr) -> io::Result<()> {
+        self.dropped.node_rate_limited += 1;
+        if !self.notice_limiter.allow(src.ip(), Instant::now()) {
+            return Ok(());
+        }
+        let reason = "too many messages";
+        self.send_error(header, ErrorCode::RateLimited, reason, src)
+    }
+
+    fn reject_unsupported(&mut self, header: Header, src: SocketAddr) -> io::Result<()> {
+        if !self.node_limiter.allow(header.node_id, Instant::now()) {
+            return self.reject_rate_limited(header, src);
+        }
+        let reason = "unknown message type";
+        self.send_error(header, ErrorCode::UnsupportedMessage, reason, src)
+    }
+
     fn handle_message(&mut self, message: Message, src: SocketAddr) -> io::Result<()> {
         use RPCPayload::*;
+        if !self
+            .node_limiter
+            .allow(message.header.node_id, Instant::now())
+        {
+            if message.payload.is_response() {
+                self.dropped.node_rate_limited += 1;
+                return Ok(());
+            }
+            return self.reject_rate_limited(message.header, src);
+        }
+        if message.payload.is_response() {
+            match self.check_response(message.header, src) {
+                TransactionCheck::Mismatched => {
+                    self.dropped.mismatched_responses += 1;
+                    self.suspicious.strike(src.ip(), Instant::now());
+                    return Ok(());
+                }
+                // Only the node we queried could have answered this, so earlier mismatches were forged
+                TransactionCheck::Valid => self.suspicious.clear(src.ip()),
+                TransactionCheck::Unknown => {}
+            }
+        }
         let node = Node {
             id: message.header.node_id,
             udp_addr: src,
         };
-        if let KBucketInsert::Ping(to_ping) = self.table.insert(node) {
-            let message = Message::create(&mut self.rng, self.table.this_node_id(), Ping);
-            self.keep_alives.insert(message.header);
-            self.send_message(message, to_ping.udp_addr)?;
+        // We still answer suspicious peers, but we don't route through them
+        let trusted = self.suspicious.is_trusted(src.ip(), Instant::now());
+        let inserted = if trusted {
+            self.table.insert(node)
+        } else {
+            KBucketInsert::Rejected
+        };
+        // We only remember capabilities for nodes in the table, so that both are evicted together
+        let in_table = trusted && self.table.contains(&node);
+        if let (true, Ping(capabilities) | PingResp(capabilities)) = (in_table, &message.payload) {
+            self.peers.insert(node.id, *capabilities);
+        }
+        if let KBucketInsert::Ping(to_ping) = inserted {
+            self.ping(to_ping)?;
+        } else if in_table && !self.peers.contains_key(&node.id) {
+            // We ping new nodes to learn which extensions they support
+            self.ping(node)?;
         }
         match message.payload {
-            Ping => {
-                let message = Message::response(message.header, PingResp);
-                self.send_message(message, src)
-            }
-            PingResp => {
+            Ping(_) => self.respond(message.header, PingResp(Capabilities::ours()), src),
+            PingResp(_) => {
                 self.keep_alives.remove(message.header.transaction_id);
                 Ok(())
             }
-            FindValue(key) => {
-                let message = match self.key_store.get(&key) {
-                    None => {
-                        let nodes = self.table.k_closest(BitKey::from_hash(&key), K);
-                        Message::response(message.header, FindValueNodes(nodes))
-                    }
-                    Some(val) => Message::response(message.header, FindValueResp(val.clone())),
+            FindValue(key, start) => {
+                let token = self.tokens.token_for(src.ip());
+                let values = self.values.get(&key, Instant::now());
+                let payload = if values.is_empty() {
+                    let nodes = self.table.k_closest(BitKey::from_hash(&key), K);
+                    FindValueNodes(nodes, token)
+                } else {
+                    // The rest of the page gets trimmed off if it doesn't fit
+                    let page = ValuePage {
+                        start,
+                        total: values.len().min(u8::MAX as usize) as u8,
+                        values: values
+                            .into_iter()
+                            .skip(start.into())
+                            .take(u8::MAX as usize)
+                            .collect(),
+                    };
+                    FindValueResp(page, token)
                 };
-                self.send_message(message, src)
+                self.respond(message.header, payload, src)
             }
-            FindValueResp(val) => {
-                if let Some(query) = &mut self.query {
-                    if query.transactions.contains(message.header.transaction_id) {
-                        // We've found the corresponding value
-                        let msg = FromServerMsg::GetResp(Some(val));
-                        self.receiver.to.send(msg).unwrap();
-                        self.query = None;
-                    }
-                }
-                Ok(())
-            }
-            FindValueNodes(nodes) => self.handle_nodes(message.header, &nodes),
+            FindValueResp(page, token) => self.handle_values(message.header, page, token, src),
+            FindValueNodes(nodes, token) => self.handle_nodes(message.header, &nodes, token),
             FindNode(id) => {
                 let nodes = self.table.k_closest(id, K);
-                let message = Message::response(message.header, FindNodeResp(nodes));
-                self.send_message(message, src)
+                let token = self.tokens.token_for(src.ip());
+                self.respond(message.header, FindNodeResp(nodes, token), src)
+            }
+            FindNodeResp(nodes, token) => self.handle_nodes(message.header, &nodes, token),
+            Store(key, val, version, signature, token) => {
+                let value = PublishedValue {
+                    publisher: signature.publisher(),
+                    version,
+                    value: val,
+                    signature,
+                };
+                self.store_value(message.header, key, value, StoreKind::Plain, token, src)
             }
-            FindNodeResp(nodes) => self.handle_nodes(message.header, &nodes),
-            Store(key, val) => {
-                self.key_store.insert(key, val);
-                let message = Message::response(message.header, StoreResp);
-                self.send_message(message, src)
+            StoreIf(key, val, expected, signature, token) => {
+                let value = PublishedValue {
+                    publisher: signature.publisher(),
+                    version: expected.saturating_add(1),
+                    value: val,
+                    signature,
+                };
+                let kind = StoreKind::Conditional(expected);
+                self.store_value(message.header, key, value, kind, token, src)
+            }
+            Delete(key, version, signature, token) => {
+                self.delete_values(message.header, key, version, signature, token, src)
+            }
+            // A copy is held to the same rules as a store from its publisher
+            Replicate(key, value, token) => {
+                self.store_value(message.header, key, value, StoreKind::Copy, token, src)
+            }
+            // Only our neighbours share our keys, and anyone else could use syncs to
+            // have us send far more than they sent us
+            SyncHashes(_, _) | SyncPull(_, _) if !self.is_neighbour(&node) => Ok(()),
+            SyncHashes(lo, hi) => {
+                let hashes = self.range_hashes(lo, hi);
+                self.respond(message.header, SyncHashesResp(hashes), src)
+            }
+            SyncHashesResp(hashes) => self.handle_sync_hashes(message.header, &hashes, src),
+            SyncPull(lo, hi) => {
+                // Anything past what a single response can hold gets pulled separately
+                let limit = usize::from(u8::MAX);
+                let (entries, more) = self.values.entries_in(lo, hi, limit, Instant::now());
+                self.respond(message.header, SyncEntries(entries, more), src)
+            }
+            SyncEntries(entries, more) => {
+                self.handle_sync_entries(message.header, entries, more, src)
             }
             StoreResp => {
+                if self.answer_write(message.header, src, WriteAnswer::Acked) {
+                    return Ok(());
+                }
+                if self.repairs.remove(message.header.transaction_id) {
+                    return Ok(());
+                }
                 self.keep_alives.remove(message.header.transaction_id);
                 Ok(())
             }
-        }
-    }
-
-    fn handle_nodes(&mut self, header: Header, nodes: &[Node]) -> io::Result<()> {
-        let mut contact_nodes = Vec::new();
-        if let Some(query) = &mut self.query {
-            // We simply ignore this transaction if we didn't create it
-            if !query.transactions.remove(header.transaction_id) {
-                return Ok(());
+            Error(code, _) => self.handle_error(message.header, code, src),
+            StoreRecord(record, token) => self.store_record(message.header, record, token, src),
+            FindRecord(key) => {
+                let token = self.tokens.token_for(src.ip());
+                let nodes = self.table.k_closest(key, K);
+                let payload = match self.records.get(&key) {
+                    None => FindValueNodes(nodes, token),
+                    Some(record) => FindRecordResp(record.clone(), nodes, token),
+                };
+                self.respond(message.header, payload, src)
+            }
+            FindRecordResp(record, nodes, token) => {
+                if let Some(query) = &mut self.query {
+                    if query.transactions.check(message.header, src) == TransactionCheck::Valid {
+                        query.offer_record(record);
+                    }
+                }
+                self.handle_nodes(message.header, &nodes, token)
+            }
+            AddProvider(key, token) => {
+                if !self.tokens.verify(src.ip(), token) {
+                    let reason = "invalid write token";
+                    return self.send_error(message.header, ErrorCode::BadToken, reason, src);
+                }
+                // The announcing node is the provider, so nobody can announce on its behalf
+                if !self.providers.add(key, node, Instant::now()) {
+                    self.dropped.storage_full += 1;
+                    let reason = "too many providers";
+                    return self.send_error(message.header, ErrorCode::StorageFull, reason, src);
+                }
+                self.respond(message.header, StoreResp, src)
+            }
+            GetProviders(key) => {
+                let token = self.tokens.token_for(src.ip());
+                let mut providers = self.providers.get(key, Instant::now());
+                providers.truncate(K);
+                let nodes = self.table.k_closest(key, K);
+                self.respond(
+                    message.header,
+                    GetProvidersResp(providers, nodes, token),
+                    src,
+                )
+            }
+            GetProvidersResp(providers, nodes, token) => {
+                if let Some(query) = &mut self.query {
+                    if query.transactions.check(message.header, src) == TransactionCheck::Valid {
+                        query.offer_providers(providers);
+                    }
+                }
+                self.handle_nodes(message.header, &nodes, token)
+            }
+        }
+    }
+
+    fn store_value(
+        &mut self,
+        header: Header,
+        key: String,
+        value: PublishedValue,
+        kind: StoreKind,
+        token: Token,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        if !self.tokens.verify(src.ip(), token) {
+            let reason = "invalid write token";
+            return self.send_error(header, ErrorCode::BadToken, reason, src);
+        }
+        if value.value.len() > self.limits.max_value_bytes {
+            let reason = "value too large";
+            return self.send_error(header, ErrorCode::ValueTooLarge, reason, src);
+        }
+        if value.version > max_version() {
+            let reason = "version too far ahead";
+            return self.send_error(header, ErrorCode::VersionTooHigh, reason, src);
+        }
+        // Node IDs are easy to claim, so a value is only tied to its publisher by a signature
+        // the publisher made, and anyone else sending it to us is only passing along a copy
+        let from_publisher = value.publisher == header.node_id || kind == StoreKind::Copy;
+        if !from_publisher || !value.verify(&key) {
+            let reason = "invalid store signature";
+            return self.send_error(header, ErrorCode::InvalidSignature, reason, src);
+        }
+        let room = self.limits.max_storage_bytes - self.record_bytes;
+        let now = Instant::now();
+        // Plain stores add to the values held by the key, rather than replacing them
+        let inserted = match kind {
+            StoreKind::Plain => self.values.insert(key, value, now, room),
+            StoreKind::Conditional(expected) => self
+                .values
+                .compare_and_swap(key, expected, value, now, room),
+            StoreKind::Copy => self.values.insert_copy(key, value, now, room),
+        };
+        let (code, reason) = match inserted {
+            Ok(()) => return self.respond(header, RPCPayload::StoreResp, src),
+            Err(InsertError::KeyFull) => (ErrorCode::StorageFull, "too many values for this key"),
+            Err(InsertError::StorageFull) => (ErrorCode::StorageFull, "out of storage space"),
+            Err(InsertError::VersionMismatch(current)) => {
+                let reason = format!("the key is at version {}", current);
+                return self.send_error(header, ErrorCode::VersionMismatch, &reason, src);
+            }
+            Err(InsertError::Deleted(version)) => {
+                let reason = format!("deleted up to version {}", version);
+                return self.send_error(header, ErrorCode::Deleted, &reason, src);
+            }
+            Err(InsertError::Unsigned) => {
+                let reason = "invalid store signature";
+                return self.send_error(header, ErrorCode::InvalidSignature, reason, src);
+            }
+        };
+        self.dropped.storage_full += 1;
+        self.send_error(header, code, reason, src)
+    }
+
+    fn delete_values(
+        &mut self,
+        header: Header,
+        key: String,
+        version: u64,
+        signature: DeleteSignature,
+        token: Token,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        if !self.tokens.verify(src.ip(), token) {
+            let reason = "invalid write token";
+            return self.send_error(header, ErrorCode::BadToken, reason, src);
+        }
+        // Node IDs are easy to claim, so the sender proves it holds the key pair behind its ID
+        let publisher = header.node_id;
+        if signature.publisher() != publisher || !signature.verify(&key, version) {
+            let reason = "invalid delete signature";
+            return self.send_error(header, ErrorCode::InvalidSignature, reason, src);
+        }
+        if version > max_version() {
+            let reason = "version too far ahead";
+            return self.send_error(header, ErrorCode::VersionTooHigh, reason, src);
+        }
+        let room = self.limits.max_storage_bytes - self.record_bytes;
+        // The sender can only ever delete its own values, since it signed for its own ID
+        let reason = match self
+            .values
+            .delete(key, version, signature, Instant::now(), room)
+        {
+            Ok(()) => return self.respond(header, RPCPayload::StoreResp, src),
+            Err(InsertError::KeyFull) => "too many tombstones for this key",
+            Err(_) => "out of storage space",
+        };
+        self.dropped.storage_full += 1;
+        self.send_error(header, ErrorCode::StorageFull, reason, src)
+    }
+
+    fn store_record(
+        &mut self,
+        header: Header,
+        record: MutableRecord,
+        token: Token,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        if !self.tokens.verify(src.ip(), token) {
+            let reason = "invalid write token";
+            return self.send_error(header, ErrorCode::BadToken, reason, src);
+        }
+        if !record.verify() {
+            let reason = "invalid signature";
+            return self.send_error(header, ErrorCode::InvalidSignature, reason, src);
+        }
+        let key = record.key();
+        let existing = self.records.get(&key);
+        if existing.is_some_and(|r| r.seq >= record.seq) {
+            let reason = "a newer version of this record exists";
+            return self.send_error(header, ErrorCode::StaleRecord, reason, src);
+        }
+        let existing = existing.map_or(0, MutableRecord::size);
+        let record_bytes = self.record_bytes - existing + record.size();
+        if record_bytes + self.values.stored_bytes() > self.limits.max_storage_bytes {
+            self.dropped.storage_full += 1;
+            let reason = "out of storage space";
+            return self.send_error(header, ErrorCode::StorageFull, reason, src);
+        }
+        self.record_bytes = record_bytes;
+        self.records.insert(key, record);
+        self.respond(header, RPCPayload::StoreResp, src)
+    }
+
+    fn handle_error(&mut self, header: Header, code: ErrorCode, src: SocketAddr) -> io::Result<()> {
+        self.dropped.errors_received += 1;
+        // A replica refusing a write won't change its mind, so we don't retry it
+        let answer = match code {
+            ErrorCode::VersionMismatch => WriteAnswer::Mismatched,
+            _ => WriteAnswer::Refused,
+        };
+        if self.answer_write(header, src, answer) {
+            return Ok(());
+        }
+        if let Some(query) = &mut self.query {
+            // We treat the node as having failed, rather than waiting for a timeout
+            if query.transactions.remove(header.transaction_id) {
+                query.transaction_paths.remove(&header.transaction_id);
+                query.remove(header.node_id);
+                return self.advance_query();
+            }
+        }
+        if self.repairs.remove(header.transaction_id) {
+            return Ok(());
+        }
+        // The node is clearly still alive, even if it refused our ping
+        self.keep_alives.remove(header.transaction_id);
+        Ok(())
+    }
+
+    // Check whether a response comes from the node we sent the request to
+    fn check_response(&self, header: Header, src: SocketAddr) -> TransactionCheck {
+        let in_query = match &self.query {
+            None => TransactionCheck::Unknown,
+            Some(query) => query.transactions.check(header, src),
+        };
+        let in_write = match in_query {
+            TransactionCheck::Unknown => self
+                .writes
+                .iter()
+                .map(|write| write.transactions.check(header, src))
+                .find(|checked| *checked != TransactionCheck::Unknown)
+                .unwrap_or(TransactionCheck::Unknown),
+            checked => checked,
+        };
+        let in_sync = match in_write {
+            TransactionCheck::Unknown => self.syncs.transactions.check(header, src),
+            checked => checked,
+        };
+        let in_repair = match in_sync {
+            TransactionCheck::Unknown => self.repairs.check(header, src),
+            checked => checked,
+        };
+        match in_repair {
+            TransactionCheck::Unknown => self.keep_alives.check(header, src),
+            checked => checked,
+        }
+    }
+
+    // Start comparing the values we hold with one of our closest neighbours
+    fn start_sync(&mut self) -> io::Result<()> {
+        let this_id = self.table.this_node_id();
+        let neighbours: Vec<Node> = self
+            .table
+            .k_closest(this_id, K)
+            .into_iter()
+            .filter(|node| node.id != this_id)
+            .collect();
+        let furthest = match neighbours.last() {
+            Some(furthest) => furthest.id,
+            None => return Ok(()),
+        };
+        let candidates: Vec<Node> = neighbours
+            .into_iter()
+            .filter(|node| self.supports(node.id, Capabilities::SYNC))
+            .collect();
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        // Spreading syncs over every neighbour heals the whole neighbourhood over time
+        let peer = candidates[self.rng.gen_range(0, candidates.len())];
+        let (lo, hi) = responsibility_range(this_id, furthest);
+        self.send_sync(peer, RPCPayload::SyncHashes(lo, hi), (lo, hi))
+    }
+
+    fn send_sync(
+        &mut self,
+        node: Node,
+        payload: RPCPayload,
+        range: (BitKey, BitKey),
+    ) -> io::Result<()> {
+        let message = Message::create(&mut self.rng, self.table.this_node_id(), payload);
+        self.syncs.insert(message.header, node, range);
+        self.send_message(message, node.udp_addr)
+    }
+
+    // Pull the slices of the range where our neighbour holds different values than we do
+    fn handle_sync_hashes(
+        &mut self,
+        header: Header,
+        hashes: &[u64],
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        let (lo, hi) = match self.syncs.take(header, src) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        let slices = split_range(lo, hi);
+        let ours = self.range_hashes(lo, hi);
+        let node = Node {
+            id: header.node_id,
+            udp_addr: src,
+        };
+        for ((slice, ours), theirs) in slices.into_iter().zip(ours).zip(hashes) {
+            if ours != *theirs {
+                self.send_sync(node, RPCPayload::SyncPull(slice.0, slice.1), slice)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Keep the values and tombstones we're missing, or hold older versions of
+    fn handle_sync_entries(
+        &mut self,
+        header: Header,
+        entries: Vec<(String, SyncEntry)>,
+        more: bool,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        let (lo, hi) = match self.syncs.take(header, src) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let max_version = max_version();
+        let mut last = None;
+        for (key, entry) in entries {
+            let position = BitKey::from_hash(&key);
+            if position.0 < lo.0 || position.0 > hi.0 {
+                continue;
+            }
+            last = Some(position);
+            let room = self.limits.max_storage_bytes - self.record_bytes;
+            match entry {
+                // Values we already hold are left alone, and tombstones still apply, but
+                // like any copy, a value is only as good as the signature of its publisher
+                SyncEntry::Value(value) if value.version <= max_version && value.verify(&key) => {
+                    let _ = self.values.insert_copy(key, value, now, room);
+                }
+                // A tombstone is only as good as the signed delete behind it
+                SyncEntry::Tombstone(version, signature)
+                    if version <= max_version && signature.verify(&key, version) =>
+                {
+                    let _ = self.values.delete(key, version, signature, now, room);
+                }
+                _ => {}
+            }
+        }
+        // Entries are sorted, so we continue from the last key we received, unless
+        // that key filled the whole response, in which case we move past it
+        let next = match last {
+            Some(last) if last.0 > lo.0 => Some(last),
+            Some(last) => last.0.checked_add(1).map(BitKey),
+            None => None,
+        };
+        match next {
+            Some(next) if more && next.0 <= hi.0 => {
+                let node = Node {
+                    id: header.node_id,
+                    udp_addr: src,
+                };
+                self.send_sync(node, RPCPayload::SyncPull(next, hi), (next, hi))
             }
+            _ => Ok(()),
+        }
+    }
+
+    // Whether a node is one of our K closest neighbours, at the address we know it by
+    fn is_neighbour(&self, node: &Node) -> bool {
+        let this_id = self.table.this_node_id();
+        // We're always the closest node to ourselves, so we look one node further
+        let neighbours = self.table.k_closest(this_id, K + 1);
+        node.id != this_id && self.table.contains(node) && neighbours.contains(node)
+    }
+
+    // Summarize the values we hold in each slice of a range, reusing our last summary
+    // of the range until the store changes
+    fn range_hashes(&mut self, lo: BitKey, hi: BitKey) -> Vec<u64> {
+        let now = Instant::now();
+        let changes = self.values.changes();
+        let cache = &mut self.hash_cache;
+        // Values expire without changing the store, so summaries don't last forever
+        let stale = now.duration_since(cache.created) >= HASH_CACHE_TTL;
+        if cache.changes != changes || stale || cache.hashes.len() >= K {
+            cache.hashes.clear();
+            cache.changes = changes;
+            cache.created = now;
+        }
+        let values = &self.values;
+        cache
+            .hashes
+            .entry((lo, hi))
+            .or_insert_with(|| values.range_hashes(&split_range(lo, hi), now))
+            .clone()
+    }
+
+    fn handle_values(
+        &mut self,
+        header: Header,
+        page: ValuePage,
+        token: Token,
+        src: SocketAddr,
+    ) -> io::Result<()> {
+        let query = match &mut self.query {
+            Some(query) if query.transactions.check(header, src) == TransactionCheck::Valid => {
+                query
+            }
+            _ => return Ok(()),
+        };
+        // A page we didn't ask for would put values in the wrong place in the answer
+        let expected = query.page_starts.remove(&header.transaction_id);
+        if page.start != expected.unwrap_or(0) {
+            self.dropped.unexpected_pages += 1;
+            return Ok(());
+        }
+        let next_start = page.next_start();
+        // We keep each answer apart, and start it afresh in case we asked this node twice
+        let answer = query.answers.entry(header.node_id).or_default();
+        if page.start == 0 {
+            answer.clear();
+        }
+        answer.extend(page.values.iter().cloned());
+        let path = query.transaction_paths.get(&header.transaction_id);
+        let path = path.copied().or_else(|| query.path_of(header.node_id));
+        let path_key = query.intention.path_key(path.unwrap_or(0));
+        if let (QueryIntention::GetChunks(_), Some(key), Some(blob)) =
+            (&query.intention, &path_key, &mut self.blob)
+        {
+            for value in &page.values {
+                blob.offer(key, &value.value);
+            }
+            // Data is handed out as soon as everything before it has been
+            let ready = blob.take_ready();
+            if !ready.is_empty() {
+                self.receiver
+                    .to
+                    .send(FromServerMsg::BlobData(ready))
+                    .unwrap();
+            }
+            if blob.is_complete() {
+                return self.finalize_query();
+            }
+        }
+        query.offer_values(page.values);
+        let key = match &query.intention {
+            QueryIntention::Get(key)
+            | QueryIntention::StoreIf(key, _, _)
+            | QueryIntention::GetManifest(key) => Some(key.clone()),
+            QueryIntention::GetChunks(_) => path_key,
+            _ => None,
+        };
+        if let (Some(start), Some(key)) = (next_start, key) {
+            // We ask the same node for the next page, keeping its place in the query
+            let payload = RPCPayload::FindValue(key, start);
+            let message = Message::create(&mut self.rng, self.table.this_node_id(), payload);
+            let node = Node {
+                id: header.node_id,
+                udp_addr: src,
+            };
+            query.transactions.remove(header.transaction_id);
+            query.transactions.insert(message.header, node);
+            query
+                .page_starts
+                .insert(message.header.transaction_id, start);
+            if let Some(path) = query.transaction_paths.remove(&header.transaction_id) {
+                query
+                    .transaction_paths
+                    .insert(message.header.transaction_id, path);
+            }
+            return self.send_message(message, src);
+        }
+        // Nodes holding values are close to the key, so we only look for more values
+        self.handle_nodes(header, &[], token)
+    }
+
+    fn handle_nodes(&mut self, header: Header, nodes: &[Node], token: Token) -> io::Result<()> {
+        let mut contact_nodes = Vec::new();
+        if let Some(query) = &mut self.query {
+            // We simply ignore this transaction if we didn't create it
+            if !query.transactions.remove(header.transaction_id) {
+                return Ok(());
+            }
+            let index = match query.take_path(header) {
+                Some(index) => index,
+                None => return Ok(()),
+            };
             let mut added = false;
             for node in nodes {
-                added = query.add_node(*node) || added;
+                added = query.add_node(index, *node) || added;
             }
-            query.update_status(header.node_id, QueryStatus::Finished);
+            let path = &mut query.paths[index];
+            path.update_status(header.node_id, QueryStatus::Finished);
+            path.set_token(header.node_id, token);
             if added {
-                if let Some(next) = query.get_closest() {
-                    contact_nodes.push(next);
-                } else {
+                if let Some(next) = path.get_closest() {
+                    contact_nodes.push((index, next));
+                } else if query.all_done() {
                     // There are no nodes left to contact, and no further work can be done
                     self.finalize_query()?;
                 }
-            } else if !query.final_k {
-                query.final_k = true;
-                for node in &query.closest {
+            } else if !path.final_k {
+                path.final_k = true;
+                for node in &path.closest {
                     if node.status == QueryStatus::Empty {
-                        contact_nodes.push(node.node);
+                        contact_nodes.push((index, node.node));
                     }
                 }
             } else if query.all_done() {
@@ -318,46 +1758,318 @@ impl ServerHandle {
                 self.finalize_query()?;
             }
         }
-        for node in contact_nodes {
-            self.continue_query(node)?;
+        for (path, node) in contact_nodes {
+            self.continue_query(path, node)?;
+        }
+        self.start_waiting_paths()
+    }
+
+    // Start the paths of the query that are waiting for others to finish
+    fn start_waiting_paths(&mut self) -> io::Result<()> {
+        let query = match &mut self.query {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        let table = &self.table;
+        let first = query.start_each(self.limits.max_paths_in_flight, |target| {
+            table.k_closest(target, K)
+        });
+        if first.is_empty() && query.all_done() {
+            return self.finalize_query();
+        }
+        for (path, node) in first {
+            self.continue_query(path, node)?;
         }
         Ok(())
     }
 
-    fn continue_query(&mut self, node: Node) -> io::Result<()> {
+    fn continue_query(&mut self, path: usize, node: Node) -> io::Result<()> {
         let query = self.query.as_mut().unwrap();
-        query.update_status(node.id, QueryStatus::Started);
+        query.paths[path].update_status(node.id, QueryStatus::Started);
         let target = query.target;
-        let payload = if let Some(key) = query.intention.key_to_find() {
-            RPCPayload::FindValue(key)
-        } else {
-            RPCPayload::FindNode(target)
+        // Each path of a query for shards looks for a different shard
+        let payload = match &query.intention {
+            // Conditional stores need to know the version of the key, not just where it lives
+            QueryIntention::Get(key)
+            | QueryIntention::StoreIf(key, _, _)
+            | QueryIntention::GetManifest(key) => RPCPayload::FindValue(key.clone(), 0),
+            QueryIntention::GetChunks(_) => {
+                RPCPayload::FindValue(query.intention.path_key(path).unwrap_or_default(), 0)
+            }
+            QueryIntention::StoreEach(_, _, _) => RPCPayload::FindNode(query.paths[path].target),
+            QueryIntention::GetRecord(key) => RPCPayload::FindRecord(*key),
+            QueryIntention::FindProviders(key) => RPCPayload::GetProviders(*key),
+            QueryIntention::Store(_, _)
+            | QueryIntention::Delete(_, _, _)
+            | QueryIntention::StoreRecord(_)
+            | QueryIntention::Provide(_) => RPCPayload::FindNode(target),
+        };
+        // Nodes that don't understand an extension can still point us towards the target
+        let payload = match payload {
+            RPCPayload::FindRecord(key) if !self.supports(node.id, Capabilities::SIGNATURES) => {
+                RPCPayload::FindNode(key)
+            }
+            RPCPayload::GetProviders(key) if !self.supports(node.id, Capabilities::PROVIDERS) => {
+                RPCPayload::FindNode(key)
+            }
+            payload => payload,
         };
+        let query = self.query.as_mut().unwrap();
         let message = Message::create(&mut self.rng, self.table.this_node_id(), payload);
-        query.transactions.insert(message.header);
+        query.transactions.insert(message.header, node);
+        query
+            .transaction_paths
+            .insert(message.header.transaction_id, path);
         self.send_message(message, node.udp_addr)
     }
 
+    // Send a write to each of the closest nodes in a query that gave us a token
+    //
+    // The client hears back once every one of these nodes has acknowledged the write, or failed to.
+    fn send_to_closest<F>(
+        &mut self,
+        query: &Query,
+        required: Capabilities,
+        mut make_payload: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(Token) -> RPCPayload,
+    {
+        let write = self.start_write(query.write_quorum, 1);
+        for node in query.closest() {
+            let supported = required == Capabilities::NONE || self.supports(node.node.id, required);
+            if let (Some(token), true) = (node.token, supported) {
+                self.send_write(write, node.node, make_payload(token), 0)?;
+            }
+        }
+        self.report_writes();
+        Ok(())
+    }
+
+    // Send the values a quorum read settled on to the closest nodes that didn't have them
+    //
+    // These copies aren't tracked like writes are, since the client isn't waiting on them,
+    // and a replica not answering says nothing about whether it's still alive.
+    fn repair_replicas(
+        &mut self,
+        query: &Query,
+        key: &str,
+        values: &[PublishedValue],
+    ) -> io::Result<()> {
+        for (node, token, missing) in query.stale_replicas(values) {
+            if !self.supports(node.id, Capabilities::REPLICATION) {
+                continue;
+            }
+            for value in missing {
+                if self.repairs.transactions.len() >= self.limits.max_repairs_in_flight {
+                    self.dropped.repairs_skipped += 1;
+                    continue;
+                }
+                let payload = RPCPayload::Replicate(key.into(), value, token);
+                let msg = Message::create(&mut self.rng, self.table.this_node_id(), payload);
+                self.repairs.insert(msg.header, node);
+                self.send_message(msg, node.udp_addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Start tracking the write for a client request, returning its index among our writes
+    fn start_write(&mut self, quorum: usize, keys_needed: usize) -> usize {
+        self.writes
+            .push_back(PendingWrite::new(quorum, keys_needed));
+        self.writes.len() - 1
+    }
+
+    fn send_write(
+        &mut self,
+        write: usize,
+        node: Node,
+        payload: RPCPayload,
+        attempts: u32,
+    ) -> io::Result<()> {
+        let this_id = self.table.this_node_id();
+        let msg = Message::create(&mut self.rng, this_id, payload.clone());
+        self.writes[write].sent(msg.header, node, payload, attempts);
+        self.send_message(msg, node.udp_addr)
+    }
+
+    // Returns true if the response belongs to one of the writes we're tracking
+    fn answer_write(&mut self, header: Header, src: SocketAddr, answer: WriteAnswer) -> bool {
+        let answered = self
+            .writes
+            .iter_mut()
+            .any(|write| write.answer(header, src, answer));
+        if answered {
+            self.report_writes();
+        }
+        answered
+    }
+
+    // Tell the client how its writes went, once no replica is left to answer
+    //
+    // The client can't tell outcomes apart, so they're reported in the order it asked for them.
+    fn report_writes(&mut self) {
+        while self.writes.front().is_some_and(PendingWrite::is_done) {
+            let write = self.writes.pop_front().unwrap();
+            self.receiver.to.send(write.outcome()).unwrap();
+        }
+    }
+
+    // Hand out what we can of the blob we're reading, looking for the chunks we're missing
+    //
+    // Once we've looked for the chunks of a level, any chunk still missing can't be found.
+    fn read_blob(&mut self, mut looked_up: bool) -> io::Result<()> {
+        let now = Instant::now();
+        while let Some(blob) = &mut self.blob {
+            // We may hold some of the chunks ourselves
+            for key in blob.missing() {
+                for value in self.values.get(&key, now) {
+                    blob.offer(&key, &value.value);
+                }
+            }
+            let ready = blob.take_ready();
+            if !ready.is_empty() {
+                self.receiver
+                    .to
+                    .send(FromServerMsg::BlobData(ready))
+                    .unwrap();
+            }
+            if !blob.is_complete() {
+                if looked_up {
+                    break;
+                }
+                let keys = blob.missing();
+                let count = keys.len();
+                return self.start_query(Query::for_keys(QueryIntention::GetChunks(keys), count));
+            }
+            // Each level lists the chunks of the next one, until we reach the data itself
+            if blob.depth() == 0 || !blob.descend() {
+                break;
+            }
+            looked_up = false;
+        }
+        let len = self.blob.take().filter(BlobReader::is_finished);
+        let msg = FromServerMsg::GetBlobResp(len.as_ref().map(BlobReader::len));
+        self.receiver.to.send(msg).unwrap();
+        Ok(())
+    }
+
     fn finalize_query(&mut self) -> io::Result<()> {
-        if let Some(query) = &self.query {
-            match &query.intention {
-                QueryIntention::Get(_) => {
-                    let msg = FromServerMsg::GetResp(None);
+        let query = match self.query.take() {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        match &query.intention {
+            QueryIntention::Get(key) => match query.read_quorum {
+                Some(quorum) => {
+                    let answers = query.replica_answers(self.table.this_node_id());
+                    if answers.len() < quorum.replicas {
+                        let msg = FromServerMsg::QuorumNotMet(answers.len());
+                        self.receiver.to.send(msg).unwrap();
+                        return Ok(());
+                    }
+                    let read = resolve(answers, quorum.resolver);
+                    self.repair_replicas(&query, key, &read.values)?;
+                    let msg = FromServerMsg::QuorumGetResp(read);
                     self.receiver.to.send(msg).unwrap();
                 }
-                QueryIntention::Store(key, val) => {
-                    let msg = FromServerMsg::StoreResp;
-                    for node in &query.closest {
-                        let payload = RPCPayload::Store(key.clone(), val.clone());
-                        let msg = Message::create(&mut self.rng, node.node.id, payload);
-                        let amt = msg.write(&mut *self.buf);
-                        self.sock.send_to(&self.buf[..amt], node.node.udp_addr)?;
-                    }
+                // Without a quorum, we can't tell which values replicas should hold
+                None => {
+                    let mut values = query.values.clone();
+                    values.sort_by(|a, b| b.precedence(a));
+                    self.receiver
+                        .to
+                        .send(FromServerMsg::GetResp(values))
+                        .unwrap();
+                }
+            },
+            QueryIntention::Store(key, value) => {
+                self.send_to_closest(&query, Capabilities::NONE, |token| {
+                    store_payload(key, value, token)
+                })?;
+            }
+            QueryIntention::StoreIf(key, expected, value) => {
+                // We check the version ourselves first, to avoid storing a value bound to fail
+                let current = query.version();
+                if current != *expected {
+                    let msg = FromServerMsg::VersionMismatch(Some(current));
                     self.receiver.to.send(msg).unwrap();
+                    return Ok(());
                 }
+                self.send_to_closest(&query, Capabilities::NONE, |token| {
+                    let val = value.value.clone();
+                    RPCPayload::StoreIf(key.clone(), val, *expected, value.signature, token)
+                })?;
+            }
+            QueryIntention::Delete(key, version, signature) => {
+                self.send_to_closest(&query, Capabilities::DELETES, |token| {
+                    RPCPayload::Delete(key.clone(), *version, *signature, token)
+                })?;
+            }
+            QueryIntention::GetManifest(_) => {
+                // Values arrive in any order, so we pick the manifest with the highest precedence
+                let manifest = query
+                    .values
+                    .iter()
+                    .filter_map(|value| Manifest::from_value(&value.value).map(|m| (value, m)))
+                    .max_by(|a, b| a.0.precedence(b.0))
+                    .map(|(_, manifest)| manifest);
+                self.blob = manifest.and_then(BlobReader::new);
+                self.read_blob(false)?;
+            }
+            // Any chunk still missing is nowhere to be found
+            QueryIntention::GetChunks(_) => self.read_blob(true)?,
+            QueryIntention::StoreEach(entries, replicas, needed) => {
+                // Each value only goes to the few nodes closest to its own key,
+                // so no key can be acknowledged by more nodes than that
+                let quorum = query.write_quorum.min(*replicas);
+                let write = self.start_write(quorum, *needed);
+                for (path, (key, value)) in query.paths.iter().zip(entries) {
+                    let holders = path
+                        .closest
+                        .iter()
+                        .filter_map(|n| n.token.map(|token| (n.node, token)))
+                        .take(*replicas);
+                    for (node, token) in holders {
+                        self.send_write(write, node, store_payload(key, value, token), 0)?;
+                    }
+                }
+                self.report_writes();
+            }
+            QueryIntention::GetRecord(_) => {
+                let msg = FromServerMsg::GetRecordResp(query.record.clone());
+                self.receiver.to.send(msg).unwrap();
+            }
+            QueryIntention::StoreRecord(record) => {
+                self.send_to_closest(&query, Capabilities::SIGNATURES, |token| {
+                    RPCPayload::StoreRecord(record.clone(), token)
+                })?;
+            }
+            QueryIntention::FindProviders(_) => {
+                let msg = FromServerMsg::FindProvidersResp(query.providers.clone());
+                self.receiver.to.send(msg).unwrap();
+            }
+            QueryIntention::Provide(key) => {
+                self.send_to_closest(&query, Capabilities::PROVIDERS, |token| {
+                    RPCPayload::AddProvider(*key, token)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    // Finish the query if every node has been queried, or contact the next nodes
+    fn advance_query(&mut self) -> io::Result<()> {
+        self.start_waiting_paths()?;
+        if let Some(query) = &self.query {
+            if query.all_done() {
+                return self.finalize_query();
+            }
+            for (path, node) in query.next_nodes() {
+                self.continue_query(path, node)?;
             }
         }
-        self.query = None;
         Ok(())
     }
 
@@ -365,80 +2077,852 @@ impl ServerHandle {
         let mut buf = Vec::new();
         if let Some(query) = &mut self.query {
             query.transactions.remove_stale(&mut buf);
+            let live = &query.transactions.transactions;
+            query
+                .transaction_paths
+                .retain(|id, _| live.contains_key(id));
             for &key in &buf {
                 query.remove(key);
             }
-            if query.all_done() {
-                self.finalize_query()?;
-            } else if !query.final_k {
-                if let Some(node) = query.get_closest() {
-                    self.continue_query(node)?;
-                }
+            self.advance_query()?;
+        }
+        for write in 0..self.writes.len() {
+            for (node, payload, attempts) in self.writes[write].timed_out() {
+                self.send_write(write, node, payload, attempts)?;
             }
         }
+        self.report_writes();
+        self.syncs.remove_stale();
+        self.repairs.remove_stale(&mut Vec::new());
+        if self.synced_at.elapsed() >= SYNC_PERIOD {
+            self.synced_at = Instant::now();
+            self.start_sync()?;
+        }
         self.keep_alives.remove_stale(&mut buf);
         for &key in &buf {
             self.table.remove(key);
+            self.peers.remove(&key);
+        }
+        let now = Instant::now();
+        self.tokens.rotate(&mut self.rng, now);
+        self.values.prune(now);
+        self.providers.prune(now);
+        self.ip_limiter.prune(now);
+        self.node_limiter.prune(now);
+        self.notice_limiter.prune(now);
+        self.replay.prune(now_micros());
+        self.suspicious.prune(now);
+        Ok(())
+    }
+
+    fn start_query(&mut self, mut query: Query) -> io::Result<()> {
+        let first = match query.intention {
+            QueryIntention::StoreEach(_, _, _) | QueryIntention::GetChunks(_) => {
+                let table = &self.table;
+                let max_in_flight = self.limits.max_paths_in_flight;
+                query.start_each(max_in_flight, |target| table.k_closest(target, K))
+            }
+            _ => query.start(self.table.k_closest(query.target, K)),
+        };
+        self.query = Some(query);
+        // With nobody to ask, we can only answer from what we have ourselves
+        if first.is_empty() {
+            return self.finalize_query();
+        }
+        for (path, node) in first {
+            self.continue_query(path, node)?;
         }
         Ok(())
     }
 
+    // Sign a value we're about to store, telling the client if it can't fit in a message
+    fn publish(&mut self, key: &str, version: u64, value: Vec<u8>) -> Option<PublishedValue> {
+        let published = self.publisher.publish(key, version, value).ok();
+        if published.is_none() {
+            let msg = FromServerMsg::WriteQuorumNotMet(0);
+            self.receiver.to.send(msg).unwrap();
+        }
+        published
+    }
+
+    // Sign each of the values we're about to store at once, all at the same version
+    fn publish_each<I>(&mut self, entries: I, version: u64) -> Option<Vec<(String, PublishedValue)>>
+    where
+        I: Iterator<Item = (String, Vec<u8>)>,
+    {
+        let mut published = Vec::new();
+        for (key, value) in entries {
+            let value = self.publish(&key, version, value)?;
+            published.push((key, value));
+        }
+        Some(published)
+    }
+
     fn handle_client(&mut self) -> io::Result<()> {
         match self.receiver.from.try_recv() {
-            Ok(ToServerMsg::Get(key)) => match self.key_store.get(&key).cloned() {
-                Some(val) => {
-                    let msg = FromServerMsg::GetResp(Some(val));
-                    self.receiver.to.send(msg).unwrap();
-                    Ok(())
+            Ok(ToServerMsg::Get(key, options)) => {
+                let values = self.values.get(&key, Instant::now());
+                let mut query = Query::new(QueryIntention::Get(key), options.disjoint_paths);
+                // We count as a replica too, if we turn out to be one of the closest nodes
+                if options.quorum.is_some() {
+                    query.read_quorum = options.quorum;
+                    query
+                        .answers
+                        .insert(self.table.this_node_id(), values.clone());
                 }
-                None => {
-                    let mut query = Query::new(QueryIntention::Get(key));
-                    let nodes = self.table.k_closest(query.target, K);
-                    query.add_node(nodes[0]);
-                    self.query = Some(query);
-                    self.continue_query(nodes[0])
+                // Other nodes may hold values we don't, so we merge ours with theirs
+                query.offer_values(values);
+                self.start_query(query)
+            }
+            Ok(ToServerMsg::Store(key, val, options)) => {
+                // Without an expected version, we use the time, so newer stores win conflicts
+                let value = match self.publish(&key, timestamp_version(), val) {
+                    Some(value) => value,
+                    None => return Ok(()),
+                };
+                let intention = QueryIntention::Store(key, value);
+                self.start_query(Query::write(intention, options))
+            }
+            Ok(ToServerMsg::StoreIf(key, val, expected, options)) => {
+                let value = match self.publish(&key, expected.saturating_add(1), val) {
+                    Some(value) => value,
+                    None => return Ok(()),
+                };
+                let values = self.values.get(&key, Instant::now());
+                let intention = QueryIntention::StoreIf(key, expected, value);
+                let mut query = Query::write(intention, options);
+                query.offer_values(values);
+                self.start_query(query)
+            }
+            Ok(ToServerMsg::Delete(key, options)) => {
+                // This covers every value we've stored so far, since stores use the time too
+                let version = timestamp_version();
+                let signature = match self.publisher.sign_delete(&key, version) {
+                    Ok(signature) => signature,
+                    // The key is too long to fit in a message, so no replica could delete it
+                    Err(_) => {
+                        let msg = FromServerMsg::WriteQuorumNotMet(0);
+                        self.receiver.to.send(msg).unwrap();
+                        return Ok(());
+                    }
+                };
+                let intention = QueryIntention::Delete(key, version, signature);
+                self.start_query(Query::write(intention, options))
+            }
+            Ok(ToServerMsg::StoreCoded(name, data, erasure, options)) => {
+                let (manifest, mut entries) = match blob::split_coded(&data, erasure) {
+                    Ok(split) => split,
+                    Err(e) => {
+                        self.receiver
+                            .to
+                            .send(FromServerMsg::CodingFailed(e))
+                            .unwrap();
+                        return Ok(());
+                    }
+                };
+                entries.push((name, manifest.to_value()));
+                let count = entries.len();
+                let entries = match self.publish_each(entries.into_iter(), timestamp_version()) {
+                    Some(entries) => entries,
+                    None => return Ok(()),
+                };
+                // Chunks are shared by whichever stripes have the same content, so we can't
+                // tell how many of them may be lost, and wait for all of them like any blob
+                let intention = QueryIntention::StoreEach(entries, erasure.replicas, count);
+                let mut query = Query::for_keys(intention, count);
+                query.write_quorum = options.write_quorum;
+                self.start_query(query)
+            }
+            Ok(ToServerMsg::PutBlob(name, data, options)) => {
+                let (manifest, mut entries) = match blob::split(&data) {
+                    Ok(split) => split,
+                    Err(e) => {
+                        self.receiver.to.send(FromServerMsg::BlobFailed(e)).unwrap();
+                        return Ok(());
+                    }
+                };
+                // The manifest is stored along with the chunks, like any other value
+                entries.push((name, manifest.to_value()));
+                let count = entries.len();
+                let entries = match self.publish_each(entries.into_iter(), timestamp_version()) {
+                    Some(entries) => entries,
+                    None => return Ok(()),
+                };
+                // The blob can't be read back if any of its chunks is missing
+                let intention = QueryIntention::StoreEach(entries, K, count);
+                let mut query = Query::for_keys(intention, count);
+                query.write_quorum = options.write_quorum;
+                self.start_query(query)
+            }
+            Ok(ToServerMsg::GetBlob(name, options)) => {
+                self.blob = None;
+                let intention = QueryIntention::GetManifest(name.clone());
+                let mut query = Query::new(intention, options.disjoint_paths);
+                query.offer_values(self.values.get(&name, Instant::now()));
+                self.start_query(query)
+            }
+            Ok(ToServerMsg::StoreRecord(record, options)) => {
+                let intention = QueryIntention::StoreRecord(*record);
+                self.start_query(Query::write(intention, options))
+            }
+            Ok(ToServerMsg::GetRecord(key, options)) => {
+                let mut query = Query::new(QueryIntention::GetRecord(key), options.disjoint_paths);
+                // We still look for newer versions, even if we have the record ourselves
+                if let Some(record) = self.records.get(&key) {
+                    query.offer_record(record.clone());
                 }
-            },
-            Ok(ToServerMsg::Store(key, val)) => {
-                let mut query = Query::new(QueryIntention::Store(key, val));
-                let nodes = self.table.k_closest(query.target, K);
-                query.add_node(nodes[0]);
-                self.query = Some(query);
-                self.continue_query(nodes[0])
+                self.start_query(query)
+            }
+            Ok(ToServerMsg::Provide(key, options)) => {
+                // We remember our own announcements, so lookups passing through us find them
+                let this_node = self.table.this_node();
+                self.providers.add(key, this_node, Instant::now());
+                let intention = QueryIntention::Provide(key);
+                self.start_query(Query::write(intention, options))
+            }
+            Ok(ToServerMsg::FindProviders(key, options)) => {
+                let intention = QueryIntention::FindProviders(key);
+                let mut query = Query::new(intention, options.disjoint_paths);
+                query.offer_providers(self.providers.get(key, Instant::now()));
+                self.start_query(query)
+            }
+            Ok(ToServerMsg::Stats) => {
+                let msg = FromServerMsg::StatsResp(self.dropped);
+                self.receiver.to.send(msg).unwrap();
+                Ok(())
+            }
+            Ok(ToServerMsg::GetPeers(_)) | Ok(ToServerMsg::AnnouncePeer(..)) => {
+                self.receiver.to.send(FromServerMsg::Unsupported).unwrap();
+                Ok(())
             }
             _ => Ok(()),
         }
     }
 }
 
-pub fn run_server<S: ToSocketAddrs>(receiver: ServerReceiver, address: S) -> io::Result<()> {
-    let mut rng = thread_rng();
+pub fn run_server<S: ToSocketAddrs>(
+    receiver: ServerReceiver,
+    address: S,
+    config: ServerConfig,
+) -> io::Result<()> {
     let sock = UdpSocket::bind(address)?;
-    let this_addr = sock.local_addr()?;
-    let this_node = Node::create(&mut rng, this_addr);
-    let table = RoutingTable::new(this_node, K);
-    let buf = Box::new([0; BUF_SIZE]);
-    let mut handle = ServerHandle {
-        table,
-        receiver,
-        sock,
-        key_store: HashMap::new(),
-        query: None,
-        keep_alives: TransactionTable::new(),
-        rng,
-        buf,
-    };
+    if let Some(mainline) = config.mainline {
+        return mainline::run(receiver, sock, mainline, config.limits, config.ip_limits);
+    }
+    let mut handle = ServerHandle::new(receiver, sock, config)?;
     let timeout = Duration::from_millis(400);
     handle.sock.set_read_timeout(Some(timeout))?;
     loop {
-        if let Ok((amt, src)) = handle.sock.recv_from(&mut *handle.buf) {
-            let try_message = Message::try_from(&handle.buf[..amt]);
-            match try_message {
-                Err(e) => println!("Error parsing message from {} error: {:?}", src, e),
-                Ok(message) => handle.handle_message(message, src)?,
-            }
+        match handle.receive_message() {
+            None => {}
+            Some(Datagram::Message(message, src)) => handle.handle_message(message, src)?,
+            Some(Datagram::Unsupported(header, src)) => handle.reject_unsupported(header, src)?,
         }
         handle.remove_stale()?;
         handle.handle_client()?;
     }
 }
+
+impl ServerHandle {
+    fn new(receiver: ServerReceiver, sock: UdpSocket, config: ServerConfig) -> io::Result<Self> {
+        let mut rng = thread_rng();
+        let this_addr = sock.local_addr()?;
+        let publisher = Publisher::generate(&mut rng);
+        let this_node = Node {
+            id: publisher.id(),
+            udp_addr: this_addr,
+        };
+        let table = RoutingTable::with_ip_limits(this_node, K, config.ip_limits);
+        let mut peers = HashMap::new();
+        peers.insert(this_node.id, Capabilities::ours());
+        let buf = Box::new([0; BUF_SIZE]);
+        let sealed_buf = Box::new([0; BUF_SIZE + OVERHEAD]);
+        Ok(ServerHandle {
+            table,
+            publisher,
+            receiver,
+            sock,
+            values: ValueStore::new(config.limits.max_values_per_key, Instant::now()),
+            records: HashMap::new(),
+            providers: ProviderTable::new(
+                config.limits.max_providers_per_key,
+                config.limits.max_providers,
+                Instant::now(),
+            ),
+            record_bytes: 0,
+            query: None,
+            writes: VecDeque::new(),
+            blob: None,
+            keep_alives: TransactionTable::new(),
+            repairs: TransactionTable::new(),
+            syncs: SyncRequests::new(),
+            hash_cache: HashCache {
+                hashes: HashMap::new(),
+                changes: 0,
+                created: Instant::now(),
+            },
+            synced_at: Instant::now(),
+            tokens: TokenSecrets::new(&mut rng, Instant::now()),
+            suspicious: Suspicion::new(SUSPICIOUS_THRESHOLD, MAX_SUSPICIOUS, Instant::now()),
+            peers,
+            network_key: config.network_key,
+            channel: config.channel,
+            replay: ReplayGuard::new(),
+            mtu: config.mtu,
+            limits: config.limits,
+            ip_limiter: RateLimiter::new(config.limits.per_ip, Instant::now()),
+            node_limiter: RateLimiter::new(config.limits.per_node, Instant::now()),
+            notice_limiter: RateLimiter::new(RATE_LIMIT_NOTICES, Instant::now()),
+            dropped: DropCounters::default(),
+            rng,
+            buf,
+            sealed_buf,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::PROTOCOL_VERSION;
+    use crate::rand::Rng;
+    use crate::record::{Publisher, StoreSignature, PUBLIC_KEY_BYTES, SIGNATURE_BYTES};
+
+    // Most tests don't look at signatures, so their values go unsigned
+    const UNSIGNED: StoreSignature = StoreSignature {
+        public_key: [0; PUBLIC_KEY_BYTES],
+        signature: [0; SIGNATURE_BYTES],
+    };
+
+    // A server bound to a local port, along with the client side of its channels
+    fn local_server(limits: Limits) -> (ServerHandle, ServerSender) {
+        let (sender, receiver) = make_server_comms();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = ServerConfig {
+            limits,
+            ..ServerConfig::default()
+        };
+        let handle = ServerHandle::new(receiver, sock, config).unwrap();
+        let timeout = Duration::from_millis(20);
+        handle.sock.set_read_timeout(Some(timeout)).unwrap();
+        (handle, sender)
+    }
+
+    // Handle the messages the server has received, including the ones it sent itself
+    fn pump(handle: &mut ServerHandle) {
+        while let Some(Datagram::Message(message, src)) = handle.receive_message() {
+            handle.handle_message(message, src).unwrap();
+        }
+    }
+
+    // A peer of the server we play ourselves, supporting every extension
+    fn local_peer(handle: &mut ServerHandle, id: u128) -> (Node, UdpSocket) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let node = Node {
+            id: BitKey(id),
+            udp_addr: sock.local_addr().unwrap(),
+        };
+        handle.table.insert(node);
+        handle.peers.insert(node.id, Capabilities::ours());
+        (node, sock)
+    }
+
+    fn receive(sock: &UdpSocket) -> Option<Message> {
+        let mut buf = [0; BUF_SIZE];
+        let (amt, _) = sock.recv_from(&mut buf).ok()?;
+        Message::parse(&buf[..amt], None).ok()
+    }
+
+    fn make_node(id: u128) -> Node {
+        Node {
+            id: BitKey(id),
+            udp_addr: "0.0.0.0:10".parse().unwrap(),
+        }
+    }
+
+    fn get_query(disjoint_paths: usize) -> Query {
+        Query::new(QueryIntention::Get(String::from("A")), disjoint_paths)
+    }
+
+    #[test]
+    fn transactions_check_responder() {
+        let mut table = TransactionTable::new();
+        let node = make_node(1);
+        let header = Header {
+            node_id: BitKey(0),
+            transaction_id: thread_rng().gen(),
+            version: PROTOCOL_VERSION,
+        };
+        table.insert(header, node);
+        let response = Header {
+            node_id: node.id,
+            ..header
+        };
+        let src = node.udp_addr;
+        let other_src = "10.0.0.1:10".parse().unwrap();
+        assert_eq!(TransactionCheck::Valid, table.check(response, src));
+        assert_eq!(TransactionCheck::Mismatched, table.check(header, src));
+        assert_eq!(
+            TransactionCheck::Mismatched,
+            table.check(response, other_src)
+        );
+        let unknown = Header {
+            transaction_id: thread_rng().gen(),
+            ..response
+        };
+        assert_eq!(TransactionCheck::Unknown, table.check(unknown, src));
+    }
+
+    #[test]
+    fn queries_fail_right_away_on_errors() {
+        let codes = [
+            ErrorCode::ValueTooLarge,
+            ErrorCode::RateLimited,
+            ErrorCode::BadToken,
+            ErrorCode::UnsupportedMessage,
+            ErrorCode::StorageFull,
+            ErrorCode::InvalidSignature,
+            ErrorCode::StaleRecord,
+            ErrorCode::VersionMismatch,
+            ErrorCode::Deleted,
+            ErrorCode::VersionTooHigh,
+            ErrorCode::Other(0xFF),
+        ];
+        for &code in &codes {
+            let (mut handle, sender) = local_server(Limits::default());
+            let (peer, sock) = local_peer(&mut handle, 1);
+            let request = ToServerMsg::Get("A".into(), GetOptions::default());
+            sender.to.send(request).unwrap();
+            handle.handle_client().unwrap();
+            // We may be closer to the key than the peer, and ask ourselves first
+            pump(&mut handle);
+            let lookup = receive(&sock).unwrap();
+            let payload = RPCPayload::Error(code, "no".into());
+            let error = Message::response(peer.id, lookup.header, payload);
+            handle.handle_message(error, peer.udp_addr).unwrap();
+
+            // We may not have asked ourselves yet, but there's nothing else left to wait for
+            pump(&mut handle);
+            assert!(matches!(
+                sender.from.try_recv(),
+                Ok(FromServerMsg::GetResp(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rate_limited_requests_get_a_notice() {
+        let limits = Limits {
+            per_node: Rate {
+                burst: 1,
+                per_second: 1,
+            },
+            ..Limits::default()
+        };
+        let (mut handle, _sender) = local_server(limits);
+        let (peer, sock) = local_peer(&mut handle, 1);
+        for _ in 0..3 {
+            let ping = RPCPayload::Ping(Capabilities::ours());
+            let message = Message::create(&mut thread_rng(), peer.id, ping);
+            handle.handle_message(message, peer.udp_addr).unwrap();
+        }
+        let payloads: Vec<RPCPayload> = (0..3)
+            .map_while(|_| receive(&sock))
+            .map(|m| m.payload)
+            .collect();
+        // Only the first request over the limit hears about it
+        assert!(matches!(payloads[0], RPCPayload::PingResp(_)));
+        assert!(matches!(
+            payloads[1],
+            RPCPayload::Error(ErrorCode::RateLimited, _)
+        ));
+        assert_eq!(2, payloads.len());
+        assert_eq!(2, handle.dropped.node_rate_limited);
+    }
+
+    #[test]
+    fn oversized_values_are_refused() {
+        let limits = Limits {
+            max_value_bytes: 4,
+            ..Limits::default()
+        };
+        let (mut handle, _sender) = local_server(limits);
+        let (peer, sock) = local_peer(&mut handle, 1);
+        let token = handle.tokens.token_for(peer.udp_addr.ip());
+        let store = RPCPayload::Store("A".into(), "too long".into(), 1, UNSIGNED, token);
+        let message = Message::create(&mut thread_rng(), peer.id, store);
+        handle.handle_message(message, peer.udp_addr).unwrap();
+        let response = receive(&sock).unwrap();
+        assert!(matches!(
+            response.payload,
+            RPCPayload::Error(ErrorCode::ValueTooLarge, _)
+        ));
+    }
+
+    #[test]
+    fn stores_need_the_signature_of_their_publisher() {
+        let (mut handle, _sender) = local_server(Limits::default());
+        let publisher = Publisher::from_seed([1; 32]);
+        let (peer, sock) = local_peer(&mut handle, publisher.id().0);
+        let token = handle.tokens.token_for(peer.udp_addr.ip());
+        let mut store = |sender: BitKey, version, val: &str, signature| {
+            let payload = RPCPayload::Store("A".into(), val.into(), version, signature, token);
+            let message = Message::create(&mut thread_rng(), sender, payload);
+            handle.handle_message(message, peer.udp_addr).unwrap();
+            receive(&sock).unwrap().payload
+        };
+        let signed = publisher.sign_store("A", 1, b"mine").unwrap();
+        assert_eq!(RPCPayload::StoreResp, store(peer.id, 1, "mine", signed));
+        // Claiming the ID of the publisher isn't enough to store in its name
+        let other = Publisher::from_seed([2; 32]);
+        let forged = other.sign_store("A", 5, b"forged").unwrap();
+        let response = store(peer.id, 5, "forged", forged);
+        assert!(matches!(
+            response,
+            RPCPayload::Error(ErrorCode::InvalidSignature, _)
+        ));
+        // Nor is reusing a signature the publisher made for another value
+        let response = store(peer.id, 5, "forged", signed);
+        assert!(matches!(
+            response,
+            RPCPayload::Error(ErrorCode::InvalidSignature, _)
+        ));
+        let values = handle.values.get("A", Instant::now());
+        assert_eq!(
+            vec![publisher.publish("A", 1, "mine".into()).unwrap()],
+            values
+        );
+    }
+
+    #[test]
+    fn forged_stores_cant_bring_back_deleted_values() {
+        let (mut handle, _sender) = local_server(Limits::default());
+        let publisher = Publisher::from_seed([1; 32]);
+        let (peer, sock) = local_peer(&mut handle, publisher.id().0);
+        let token = handle.tokens.token_for(peer.udp_addr.ip());
+        let mut send = |payload| {
+            let message = Message::create(&mut thread_rng(), peer.id, payload);
+            handle.handle_message(message, peer.udp_addr).unwrap();
+            receive(&sock).unwrap().payload
+        };
+        let signed = publisher.sign_store("A", 1, b"mine").unwrap();
+        let payload = RPCPayload::Store("A".into(), "mine".into(), 1, signed, token);
+        assert_eq!(RPCPayload::StoreResp, send(payload));
+        let signature = publisher.sign_delete("A", 1).unwrap();
+        let payload = RPCPayload::Delete("A".into(), 1, signature, token);
+        assert_eq!(RPCPayload::StoreResp, send(payload));
+        // A higher version would get past the tombstone, but only the publisher can sign one
+        let forged = Publisher::from_seed([2; 32])
+            .sign_store("A", 5, b"mine")
+            .unwrap();
+        let payload = RPCPayload::Store("A".into(), "mine".into(), 5, forged, token);
+        let response = send(payload);
+        assert!(matches!(
+            response,
+            RPCPayload::Error(ErrorCode::InvalidSignature, _)
+        ));
+        let copy = PublishedValue {
+            publisher: publisher.id(),
+            version: 5,
+            value: "mine".into(),
+            signature: signed,
+        };
+        let response = send(RPCPayload::Replicate("A".into(), copy, token));
+        assert!(matches!(
+            response,
+            RPCPayload::Error(ErrorCode::InvalidSignature, _)
+        ));
+        assert!(handle.values.get("A", Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn synced_values_need_the_signature_of_their_publisher() {
+        let (mut handle, _sender) = local_server(Limits::default());
+        let (peer, sock) = local_peer(&mut handle, 1);
+        let everything = (BitKey(0), BitKey(u128::MAX));
+        let pull = RPCPayload::SyncPull(everything.0, everything.1);
+        handle.send_sync(peer, pull, everything).unwrap();
+        let request = receive(&sock).unwrap();
+        let publisher = Publisher::from_seed([1; 32]);
+        let signed = publisher.publish("B", 1, "mine".into()).unwrap();
+        // The signature of the publisher only covers the value it signed
+        let forged = PublishedValue {
+            publisher: publisher.id(),
+            version: 2,
+            value: "forged".into(),
+            signature: signed.signature,
+        };
+        let entries = vec![
+            ("A".into(), SyncEntry::Value(forged)),
+            ("B".into(), SyncEntry::Value(signed.clone())),
+        ];
+        let payload = RPCPayload::SyncEntries(entries, false);
+        let message = Message::response(peer.id, request.header, payload);
+        handle.handle_message(message, peer.udp_addr).unwrap();
+        assert!(handle.values.get("A", Instant::now()).is_empty());
+        assert_eq!(vec![signed], handle.values.get("B", Instant::now()));
+    }
+
+    #[test]
+    fn writes_count_acknowledgements() {
+        let mut write = PendingWrite::new(2, 1);
+        let header = |id: u128, transaction_id| Header {
+            node_id: BitKey(id),
+            transaction_id,
+            version: PROTOCOL_VERSION,
+        };
+        let sent: Vec<Header> = (1..=3).map(|id| header(id, thread_rng().gen())).collect();
+        for h in &sent {
+            write.sent(*h, make_node(h.node_id.0), RPCPayload::StoreResp, 0);
+        }
+        let src = make_node(1).udp_addr;
+        assert!(write.answer(sent[0], src, WriteAnswer::Acked));
+        // Answering twice, or answering someone else's request, doesn't count
+        assert!(!write.answer(sent[0], src, WriteAnswer::Acked));
+        let forged = header(3, sent[1].transaction_id);
+        assert!(!write.answer(forged, src, WriteAnswer::Acked));
+        assert!(write.answer(sent[1], src, WriteAnswer::Refused));
+        // The last replica gets retried until we run out of attempts
+        for attempt in 1..=WRITE_ATTEMPTS {
+            let stale = Instant::now() - Duration::from_secs(10);
+            for (then, _) in write.transactions.transactions.values_mut() {
+                *then = stale;
+            }
+            let retries = write.timed_out();
+            if attempt == WRITE_ATTEMPTS {
+                assert!(retries.is_empty());
+            } else {
+                let expected = vec![(make_node(3), RPCPayload::StoreResp, attempt)];
+                assert_eq!(expected, retries);
+                write.sent(
+                    header(3, thread_rng().gen()),
+                    make_node(3),
+                    RPCPayload::StoreResp,
+                    attempt,
+                );
+            }
+        }
+        assert!(write.is_done());
+        assert!(matches!(
+            write.outcome(),
+            FromServerMsg::WriteQuorumNotMet(1)
+        ));
+    }
+
+    #[test]
+    fn writes_count_distinct_keys() {
+        let mut write = PendingWrite::new(1, 2);
+        let token = Token(0);
+        let store = |key: &str| RPCPayload::Store(key.into(), "value".into(), 1, UNSIGNED, token);
+        let sent: Vec<(Header, RPCPayload)> = [(1, "a"), (2, "a"), (3, "b")]
+            .iter()
+            .map(|&(id, key)| {
+                let header = Header {
+                    node_id: BitKey(id),
+                    transaction_id: thread_rng().gen(),
+                    version: PROTOCOL_VERSION,
+                };
+                (header, store(key))
+            })
+            .collect();
+        for (h, payload) in &sent {
+            write.sent(*h, make_node(h.node_id.0), payload.clone(), 0);
+        }
+        let src = make_node(1).udp_addr;
+        // Two copies of the same key make for a single key
+        assert!(write.answer(sent[0].0, src, WriteAnswer::Acked));
+        assert!(write.answer(sent[1].0, src, WriteAnswer::Acked));
+        assert!(write.answer(sent[2].0, src, WriteAnswer::Refused));
+        assert!(matches!(
+            write.outcome(),
+            FromServerMsg::WriteQuorumNotMet(2)
+        ));
+    }
+
+    #[test]
+    fn writes_report_version_mismatches() {
+        let mut write = PendingWrite::new(1, 1);
+        let sent: Vec<Header> = (1..=2)
+            .map(|id| Header {
+                node_id: BitKey(id),
+                transaction_id: thread_rng().gen(),
+                version: PROTOCOL_VERSION,
+            })
+            .collect();
+        for h in &sent {
+            write.sent(*h, make_node(h.node_id.0), RPCPayload::StoreResp, 0);
+        }
+        let src = make_node(1).udp_addr;
+        assert!(write.answer(sent[0], src, WriteAnswer::Acked));
+        assert!(write.answer(sent[1], src, WriteAnswer::Mismatched));
+        // Even with the quorum met, the client learns the key moved on
+        assert!(matches!(
+            write.outcome(),
+            FromServerMsg::VersionMismatch(None)
+        ));
+    }
+
+    #[test]
+    fn query_splits_nodes_between_paths() {
+        let mut query = get_query(3);
+        let nodes: Vec<Node> = (1..=6).map(make_node).collect();
+        let first = query.start(nodes);
+        assert_eq!(3, first.len());
+        for path in &query.paths {
+            assert_eq!(2, path.closest.len());
+        }
+        assert_eq!(6, query.closest().len());
+    }
+
+    #[test]
+    fn query_takes_turns_between_paths() {
+        let keys = (0..5).map(|i| format!("{}", i)).collect();
+        let mut query = Query::for_keys(QueryIntention::GetChunks(keys), 5);
+        let first = query.start_each(2, |_| vec![make_node(1), make_node(2)]);
+        assert_eq!(
+            vec![0, 1],
+            first.iter().map(|(path, _)| *path).collect::<Vec<_>>()
+        );
+        // Nothing more starts until a path is done
+        assert!(query.start_each(2, |_| vec![make_node(1)]).is_empty());
+        for node in [1, 2] {
+            query.paths[0].update_status(BitKey(node), QueryStatus::Finished);
+        }
+        assert!(!query.all_done());
+        let next = query.start_each(2, |_| vec![make_node(3)]);
+        assert_eq!(vec![(2, make_node(3))], next);
+        // Paths nobody can help with don't hold up the others
+        for node in [1, 2] {
+            query.paths[1].update_status(BitKey(node), QueryStatus::Finished);
+        }
+        query.paths[2].update_status(BitKey(3), QueryStatus::Finished);
+        assert!(query.start_each(2, |_| Vec::new()).is_empty());
+        assert!(query.all_done());
+    }
+
+    #[test]
+    fn query_paths_are_disjoint() {
+        let mut query = get_query(2);
+        query.start(vec![make_node(1), make_node(2)]);
+        let first_path = query.path_of(BitKey(1)).unwrap();
+        let second_path = query.path_of(BitKey(2)).unwrap();
+        assert_ne!(first_path, second_path);
+        // A node already in one path can't be added to another
+        assert!(!query.add_node(first_path, make_node(2)));
+        assert!(query.add_node(first_path, make_node(3)));
+        assert!(!query.add_node(second_path, make_node(3)));
+    }
+
+    #[test]
+    fn query_merges_values() {
+        let value = |publisher: u128, version: u64, value: &str| PublishedValue {
+            publisher: BitKey(publisher),
+            version,
+            value: value.into(),
+            signature: UNSIGNED,
+        };
+        let mut query = get_query(1);
+        query.offer_values(vec![value(1, 1, "a"), value(2, 3, "b")]);
+        query.offer_values(vec![value(2, 1, "b"), value(2, 2, "c"), value(3, 4, "a")]);
+        let expected = vec![
+            value(1, 1, "a"),
+            value(2, 3, "b"),
+            value(2, 2, "c"),
+            value(3, 4, "a"),
+        ];
+        assert_eq!(expected, query.values);
+        assert_eq!(4, query.version());
+    }
+
+    #[test]
+    fn query_finds_stale_replicas() {
+        let value = |version: u64, value: &str| PublishedValue {
+            publisher: BitKey(1),
+            version,
+            value: value.into(),
+            signature: UNSIGNED,
+        };
+        let mut query = get_query(1);
+        query.start((1..=4).map(make_node).collect());
+        for id in 1..=3 {
+            query.paths[0].update_status(BitKey(id), QueryStatus::Finished);
+            query.paths[0].set_token(BitKey(id), Token(id as u64));
+        }
+        query.answers.insert(BitKey(1), vec![value(2, "a")]);
+        query.answers.insert(BitKey(2), vec![value(1, "a")]);
+        // Node 3 answered without values, and node 4 never answered at all
+        let stale = query.stale_replicas(&[value(2, "a")]);
+        // Replicas come closest first, and node 3 happens to be closer to the key
+        let expected = vec![
+            (make_node(3), Token(3), vec![value(2, "a")]),
+            (make_node(2), Token(2), vec![value(2, "a")]),
+        ];
+        assert_eq!(expected, stale);
+    }
+
+    #[test]
+    fn query_collects_replica_answers() {
+        let value = |version: u64| PublishedValue {
+            publisher: BitKey(1),
+            version,
+            value: b"a".to_vec(),
+            signature: UNSIGNED,
+        };
+        let mut query = get_query(1);
+        query.start((1..=4).map(make_node).collect());
+        for id in 1..=3 {
+            query.paths[0].update_status(BitKey(id), QueryStatus::Finished);
+        }
+        query.answers.insert(BitKey(1), vec![value(2)]);
+        query.answers.insert(BitKey(2), vec![value(1)]);
+        // Node 4 never answered, so it isn't a replica, and neither is an unknown node
+        query.answers.insert(BitKey(9), vec![value(9)]);
+        let answers = query.replica_answers(BitKey(0));
+        // Node 3 answered without values, and comes first, being closest to the key
+        assert_eq!(vec![vec![], vec![value(1)], vec![value(2)]], answers);
+        // Our own answer counts, even though we never queried ourselves
+        query.answers.insert(BitKey(5), vec![value(3)]);
+        assert_eq!(4, query.replica_answers(BitKey(5)).len());
+        assert!(query.replica_answers(BitKey(5)).contains(&vec![value(3)]));
+    }
+
+    #[test]
+    fn query_values_are_bounded() {
+        let mut query = get_query(1);
+        let values = (0..2 * MAX_QUERY_VALUES).map(|i| PublishedValue {
+            publisher: BitKey(i as u128),
+            version: i as u64,
+            value: b"a".to_vec(),
+            signature: UNSIGNED,
+        });
+        query.offer_values(values);
+        assert_eq!(MAX_QUERY_VALUES, query.values.len());
+        // We keep the values that would win a conflict
+        let newest = 2 * MAX_QUERY_VALUES as u64 - 1;
+        assert_eq!(newest, query.values[0].version);
+    }
+
+    #[test]
+    fn query_keeps_newest_valid_record() {
+        let publisher = Publisher::generate(&mut thread_rng());
+        let first = publisher.sign("A", 1, "first").unwrap();
+        let intention = QueryIntention::GetRecord(first.key());
+        let mut query = Query::new(intention, 1);
+        query.offer_record(first);
+        let forged = MutableRecord {
+            seq: 3,
+            ..publisher.sign("A", 2, "forged").unwrap()
+        };
+        query.offer_record(forged);
+        query.offer_record(publisher.sign("B", 4, "other key").unwrap());
+        assert_eq!(Some(1), query.record.as_ref().map(|r| r.seq));
+        query.offer_record(publisher.sign("A", 2, "second").unwrap());
+        query.offer_record(publisher.sign("A", 2, "same version").unwrap());
+        assert_eq!(
+            Some("second"),
+            query.record.as_ref().map(|r| r.value.as_str())
+        );
+    }
+}
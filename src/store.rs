@@ -0,0 +1,917 @@
+use crate::base::BitKey;
+use crate::messages::{PublishedValue, SyncEntry};
+use crate::record::{DeleteSignature, PUBLIC_KEY_BYTES, SIGNATURE_BYTES};
+use crate::sha1::Sha1;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+/// How long a value stays in the store, unless its publisher stores it again.
+pub const VALUE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a tombstone stays in the store.
+///
+/// Deleted values would have expired by the time their tombstone does, so
+/// there's nothing left for a tombstone to hide after this.
+pub const TOMBSTONE_TTL: Duration = VALUE_TTL;
+/// How often we sweep the whole store for expired values.
+const PRUNE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Represents the reasons a value can be refused by the store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InsertError {
+    /// The key already holds as many values as it can
+    KeyFull,
+    /// Storing the value would go over our storage limit
+    StorageFull,
+    /// The key wasn't at the version a conditional store expected, holding this version instead
+    VersionMismatch(u64),
+    /// The publisher deleted its values under this key, up to and including this version
+    Deleted(u64),
+    /// A copy would replace a value stored by its publisher, without the publisher's signature
+    Unsigned,
+}
+
+struct StoredValue {
+    value: PublishedValue,
+    size: usize,
+    expiration: Instant,
+    // Copies come from other nodes, which can claim any publisher they like
+    copy: bool,
+}
+
+// This marks the values of a publisher as deleted, up to some version
+struct Tombstone {
+    publisher: BitKey,
+    version: u64,
+    // We keep the signed delete, so neighbours can check the tombstone for themselves
+    signature: DeleteSignature,
+    expiration: Instant,
+}
+
+// Summarize a value held under a key, for comparing stores with each other
+fn entry_hash(key: &str, value: &PublishedValue) -> u64 {
+    let mut hasher = Sha1::new();
+    hasher.update(&[key.len() as u8]);
+    hasher.update(key.as_bytes());
+    hasher.update(&value.publisher.0.to_be_bytes());
+    hasher.update(&value.version.to_be_bytes());
+    hasher.update(&value.value);
+    let bytes = hasher.digest().bytes()[..8].try_into().unwrap();
+    u64::from_be_bytes(bytes)
+}
+
+// Tombstones are summarized like values, with a marker no value hash starts with
+fn tombstone_hash(key: &str, tombstone: &Tombstone) -> u64 {
+    let mut hasher = Sha1::new();
+    hasher.update(b"tombstone");
+    hasher.update(&[key.len() as u8]);
+    hasher.update(key.as_bytes());
+    hasher.update(&tombstone.publisher.0.to_be_bytes());
+    hasher.update(&tombstone.version.to_be_bytes());
+    let bytes = hasher.digest().bytes()[..8].try_into().unwrap();
+    u64::from_be_bytes(bytes)
+}
+
+// Values are charged for their key as well, since it's kept alongside them, and for their signature
+fn value_size(key: &str, value: &PublishedValue) -> usize {
+    key.len() + value.value.len() + PUBLIC_KEY_BYTES + SIGNATURE_BYTES
+}
+
+// Tombstones are charged for their key, publisher, version, and signed delete
+fn tombstone_size(key: &str) -> usize {
+    key.len() + 16 + 8 + PUBLIC_KEY_BYTES + SIGNATURE_BYTES
+}
+
+/// Represents the values other nodes have stored with us.
+///
+/// Each key holds a bounded set of values, one for each publisher, so publishers
+/// storing under the same key don't overwrite each other. Every value is tagged
+/// with the node that published it, and expires unless that node stores it again.
+///
+/// Values also carry a version, and the version of a key is the highest version
+/// among its values. Conditional stores only go through if the key is still at
+/// the version the publisher expects, superseding the values the key held.
+///
+/// Publishers can delete their values, leaving behind a tombstone, so that
+/// storing an old version of a value again doesn't bring it back.
+pub struct ValueStore {
+    values: HashMap<String, Vec<StoredValue>>,
+    tombstones: HashMap<String, Vec<Tombstone>>,
+    max_per_key: usize,
+    stored_bytes: usize,
+    pruned_at: Instant,
+    changes: u64,
+}
+
+impl ValueStore {
+    /// Create an empty store, holding at most `max_per_key` values for each key.
+    pub fn new(max_per_key: usize, now: Instant) -> Self {
+        ValueStore {
+            values: HashMap::new(),
+            tombstones: HashMap::new(),
+            max_per_key,
+            stored_bytes: 0,
+            pruned_at: now,
+            changes: 0,
+        }
+    }
+
+    /// Add a value to the set held by a key.
+    ///
+    /// Each publisher holds a single value under a key, so storing a new value
+    /// replaces the one the publisher stored before, unless that one takes
+    /// precedence over it. Storing the same value again pushes back its expiration.
+    /// `room` is how many bytes the store as a whole is allowed to take up.
+    ///
+    /// If the key is full, the value takes the place of a copy, if the key holds any.
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: PublishedValue,
+        now: Instant,
+        room: usize,
+    ) -> Result<(), InsertError> {
+        self.insert_value(key, value, now, room, false)
+    }
+
+    /// Add a copy of a value, sent to us by some node other than its publisher.
+    ///
+    /// Copies follow the same rules as values stored by their publisher, but
+    /// nothing backs the publisher they claim, so they never push out other
+    /// values, and values stored by their publisher push them out of full keys.
+    /// A copy only replaces a value its publisher stored if it carries the
+    /// publisher's signature.
+    pub fn insert_copy(
+        &mut self,
+        key: String,
+        value: PublishedValue,
+        now: Instant,
+        room: usize,
+    ) -> Result<(), InsertError> {
+        self.insert_value(key, value, now, room, true)
+    }
+
+    fn insert_value(
+        &mut self,
+        key: String,
+        value: PublishedValue,
+        now: Instant,
+        room: usize,
+        copy: bool,
+    ) -> Result<(), InsertError> {
+        if let Some(deleted) = self.deleted_version(&key, value.publisher, now) {
+            if deleted >= value.version {
+                return Err(InsertError::Deleted(deleted));
+            }
+        }
+        let size = value_size(&key, &value);
+        let stored = StoredValue {
+            value,
+            size,
+            expiration: now + VALUE_TTL,
+            copy,
+        };
+        let stored_bytes = &mut self.stored_bytes;
+        let changes = &mut self.changes;
+        let entry = match self.values.get_mut(&key) {
+            Some(entry) => entry,
+            // We only make room for a new key once we know the value fits
+            None if self.max_per_key == 0 => return Err(InsertError::KeyFull),
+            None if *stored_bytes + size > room => return Err(InsertError::StorageFull),
+            None => {
+                *stored_bytes += size;
+                *changes += 1;
+                self.values.insert(key, vec![stored]);
+                return Ok(());
+            }
+        };
+        remove_expired(entry, stored_bytes, now);
+        let publisher = stored.value.publisher;
+        if let Some(existing) = entry.iter_mut().find(|s| s.value.publisher == publisher) {
+            match stored.value.precedence(&existing.value) {
+                // An older store arriving late doesn't undo a newer one
+                Ordering::Less => {}
+                Ordering::Equal => {
+                    existing.expiration = stored.expiration;
+                    // The publisher vouches for a copy by storing it itself
+                    existing.copy &= copy;
+                }
+                Ordering::Greater => {
+                    if copy && !existing.copy && !stored.value.verify(&key) {
+                        return Err(InsertError::Unsigned);
+                    }
+                    if *stored_bytes - existing.size + size > room {
+                        return Err(InsertError::StorageFull);
+                    }
+                    *stored_bytes = *stored_bytes - existing.size + size;
+                    *changes += 1;
+                    *existing = stored;
+                }
+            }
+            return Ok(());
+        }
+        if entry.len() < self.max_per_key {
+            if *stored_bytes + size > room {
+                return Err(InsertError::StorageFull);
+            }
+            *stored_bytes += size;
+            *changes += 1;
+            entry.push(stored);
+            return Ok(());
+        }
+        // Forged copies could otherwise keep publishers out of a key
+        let evicted = entry
+            .iter_mut()
+            .filter(|s| s.copy && !copy)
+            .min_by(|a, b| a.value.precedence(&b.value));
+        match evicted {
+            Some(evicted) if *stored_bytes - evicted.size + size <= room => {
+                *stored_bytes = *stored_bytes - evicted.size + size;
+                *changes += 1;
+                *evicted = stored;
+                Ok(())
+            }
+            Some(_) => Err(InsertError::StorageFull),
+            None => Err(InsertError::KeyFull),
+        }
+    }
+
+    /// Store a value, if the key is at the version we expect.
+    ///
+    /// Keys without any values are at version 0. The new value should have a
+    /// higher version than the one expected, so that it supersedes the other values
+    /// held by the key, including on nodes that missed this store. Only the value
+    /// the publisher held before is replaced, and tombstones still apply.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: u64,
+        value: PublishedValue,
+        now: Instant,
+        room: usize,
+    ) -> Result<(), InsertError> {
+        let current = self.version(&key, now);
+        if current != expected {
+            return Err(InsertError::VersionMismatch(current));
+        }
+        self.insert(key, value, now, room)
+    }
+
+    /// Delete the values a publisher stored under a key, up to and including some version.
+    ///
+    /// The publisher is the one that signed the delete, which the caller should
+    /// have checked. This leaves a tombstone behind, so the publisher can't store
+    /// those versions again until the tombstone expires. Deleting again keeps the
+    /// higher of the two versions, and pushes back the expiration of the tombstone.
+    pub fn delete(
+        &mut self,
+        key: String,
+        version: u64,
+        signature: DeleteSignature,
+        now: Instant,
+        room: usize,
+    ) -> Result<(), InsertError> {
+        let publisher = signature.publisher();
+        let size = tombstone_size(&key);
+        let expiration = now + TOMBSTONE_TTL;
+        // The tombstone has to fit before any values go, or a full store could
+        // be used to delete values without leaving anything to keep them deleted
+        match self.tombstones.get_mut(&key) {
+            Some(tombstones) => {
+                let stored_bytes = &mut self.stored_bytes;
+                tombstones.retain(|tombstone| {
+                    let alive = tombstone.expiration > now;
+                    if !alive {
+                        *stored_bytes -= size;
+                    }
+                    alive
+                });
+                if let Some(existing) = tombstones.iter_mut().find(|t| t.publisher == publisher) {
+                    if version > existing.version {
+                        existing.version = version;
+                        existing.signature = signature;
+                        self.changes += 1;
+                    }
+                    existing.expiration = expiration;
+                } else if tombstones.len() >= self.max_per_key {
+                    return Err(InsertError::KeyFull);
+                } else if self.stored_bytes + size > room {
+                    return Err(InsertError::StorageFull);
+                } else {
+                    self.stored_bytes += size;
+                    self.changes += 1;
+                    tombstones.push(Tombstone {
+                        publisher,
+                        version,
+                        signature,
+                        expiration,
+                    });
+                }
+            }
+            None => {
+                if self.max_per_key == 0 {
+                    return Err(InsertError::KeyFull);
+                }
+                if self.stored_bytes + size > room {
+                    return Err(InsertError::StorageFull);
+                }
+                self.stored_bytes += size;
+                self.changes += 1;
+                let tombstone = Tombstone {
+                    publisher,
+                    version,
+                    signature,
+                    expiration,
+                };
+                self.tombstones.insert(key.clone(), vec![tombstone]);
+            }
+        }
+        let stored_bytes = &mut self.stored_bytes;
+        let changes = &mut self.changes;
+        if let Some(entry) = self.values.get_mut(&key) {
+            entry.retain(|stored| {
+                let deleted =
+                    stored.value.publisher == publisher && stored.value.version <= version;
+                if deleted {
+                    *stored_bytes -= stored.size;
+                    *changes += 1;
+                }
+                !deleted
+            });
+        }
+        Ok(())
+    }
+
+    // The version a publisher deleted its values under a key up to, if it did
+    fn deleted_version(&self, key: &str, publisher: BitKey, now: Instant) -> Option<u64> {
+        self.tombstones
+            .get(key)?
+            .iter()
+            .find(|t| t.publisher == publisher && t.expiration > now)
+            .map(|t| t.version)
+    }
+
+    /// The highest version among the values held by a key, or 0 if it holds none.
+    pub fn version(&self, key: &str, now: Instant) -> u64 {
+        self.values.get(key).map_or(0, |entry| {
+            entry
+                .iter()
+                .filter(|stored| stored.expiration > now)
+                .map(|stored| stored.value.version)
+                .max()
+                .unwrap_or(0)
+        })
+    }
+
+    /// Get the values held by a key that haven't expired yet.
+    ///
+    /// Values are returned in the order they were first stored.
+    pub fn get(&self, key: &str, now: Instant) -> Vec<PublishedValue> {
+        self.values.get(key).map_or_else(Vec::new, |entry| {
+            entry
+                .iter()
+                .filter(|stored| stored.expiration > now)
+                .map(|stored| stored.value.clone())
+                .collect()
+        })
+    }
+
+    /// Get the values and tombstones held under keys in an inclusive range, sorted by key.
+    ///
+    /// At most `limit` entries are returned, along with whether any were left out.
+    /// Values come before tombstones under each key, with the value that wins a
+    /// conflict first, so the entries of a key always come out in the same order.
+    pub fn entries_in(
+        &self,
+        lo: BitKey,
+        hi: BitKey,
+        limit: usize,
+        now: Instant,
+    ) -> (Vec<(String, SyncEntry)>, bool) {
+        let in_range = |key: &String| {
+            let position = BitKey::from_hash(key).0;
+            Some(position).filter(|p| lo.0 <= *p && *p <= hi.0)
+        };
+        let mut values = Vec::new();
+        for (key, entry) in &self.values {
+            if let Some(position) = in_range(key) {
+                let alive = entry.iter().filter(|stored| stored.expiration > now);
+                values.extend(alive.map(|stored| (position, key, &stored.value)));
+            }
+        }
+        let mut tombstones = Vec::new();
+        for (key, entry) in &self.tombstones {
+            if let Some(position) = in_range(key) {
+                let alive = entry.iter().filter(|tombstone| tombstone.expiration > now);
+                tombstones.extend(alive.map(|tombstone| (position, key, tombstone)));
+            }
+        }
+        values.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| b.2.precedence(a.2)));
+        tombstones.sort_by_key(|(position, _, tombstone)| (*position, tombstone.publisher.0));
+        // Only the entries we send get cloned, however many the range holds
+        let mut entries = Vec::with_capacity(limit.min(values.len() + tombstones.len()));
+        let (mut values, mut tombstones) = (values.iter().peekable(), tombstones.iter().peekable());
+        while entries.len() < limit {
+            let value_first = match (values.peek(), tombstones.peek()) {
+                (Some(value), Some(tombstone)) => value.0 <= tombstone.0,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if value_first {
+                let (_, key, value) = values.next().unwrap();
+                entries.push((key.to_string(), SyncEntry::Value((*value).clone())));
+            } else {
+                let (_, key, tombstone) = tombstones.next().unwrap();
+                let entry = SyncEntry::Tombstone(tombstone.version, tombstone.signature);
+                entries.push((key.to_string(), entry));
+            }
+        }
+        let more = values.peek().is_some() || tombstones.peek().is_some();
+        (entries, more)
+    }
+
+    /// Summarize the values and tombstones held under the keys in each of several inclusive ranges.
+    ///
+    /// Two stores holding the same values and tombstones in a range have the same
+    /// summary for it, whatever order they stored them in.
+    pub fn range_hashes(&self, ranges: &[(BitKey, BitKey)], now: Instant) -> Vec<u64> {
+        let mut hashes = vec![0; ranges.len()];
+        let range_of = |key: &str| {
+            let position = BitKey::from_hash(key).0;
+            ranges
+                .iter()
+                .position(|(lo, hi)| lo.0 <= position && position <= hi.0)
+        };
+        for (key, entry) in &self.values {
+            if let Some(i) = range_of(key) {
+                for stored in entry.iter().filter(|stored| stored.expiration > now) {
+                    hashes[i] ^= entry_hash(key, &stored.value);
+                }
+            }
+        }
+        for (key, entry) in &self.tombstones {
+            if let Some(i) = range_of(key) {
+                for tombstone in entry.iter().filter(|tombstone| tombstone.expiration > now) {
+                    hashes[i] ^= tombstone_hash(key, tombstone);
+                }
+            }
+        }
+        hashes
+    }
+
+    /// Remove every expired value and tombstone, if we haven't done so recently.
+    pub fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned_at) < PRUNE_PERIOD {
+            return;
+        }
+        self.pruned_at = now;
+        let bytes_before = self.stored_bytes;
+        let stored_bytes = &mut self.stored_bytes;
+        self.values.retain(|_, entry| {
+            remove_expired(entry, stored_bytes, now);
+            !entry.is_empty()
+        });
+        self.tombstones.retain(|key, tombstones| {
+            let before = tombstones.len();
+            tombstones.retain(|tombstone| tombstone.expiration > now);
+            *stored_bytes -= (before - tombstones.len()) * tombstone_size(key);
+            !tombstones.is_empty()
+        });
+        // Everything we hold takes up some bytes, so anything removed shows up here
+        if self.stored_bytes != bytes_before {
+            self.changes += 1;
+        }
+    }
+
+    /// A counter bumped whenever a value or tombstone is added, replaced, or removed.
+    ///
+    /// Summaries of the store stay the same while this does, unless values expire.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// How many bytes of keys, values, and tombstones we hold.
+    pub fn stored_bytes(&self) -> usize {
+        self.stored_bytes
+    }
+}
+
+fn remove_expired(entry: &mut Vec<StoredValue>, stored_bytes: &mut usize, now: Instant) {
+    entry.retain(|stored| {
+        let alive = stored.expiration > now;
+        if !alive {
+            *stored_bytes -= stored.size;
+        }
+        alive
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::BitKey;
+    use crate::record::{Publisher, StoreSignature};
+
+    // How many bytes each value takes up for its signature
+    const SIGNED: usize = PUBLIC_KEY_BYTES + SIGNATURE_BYTES;
+    // The store leaves checking signatures to the server, so most values go unsigned
+    const UNSIGNED: StoreSignature = StoreSignature {
+        public_key: [0; PUBLIC_KEY_BYTES],
+        signature: [0; SIGNATURE_BYTES],
+    };
+
+    fn published(publisher: u128, value: &str) -> PublishedValue {
+        versioned(publisher, 1, value)
+    }
+
+    // Sign a delete with the key pair the seed gives, returning the ID of the publisher too
+    fn signed_delete(seed: u8, key: &str, version: u64) -> (u128, DeleteSignature) {
+        let publisher = Publisher::from_seed([seed; 32]);
+        (
+            publisher.id().0,
+            publisher.sign_delete(key, version).unwrap(),
+        )
+    }
+
+    fn versioned(publisher: u128, version: u64, value: &str) -> PublishedValue {
+        PublishedValue {
+            publisher: BitKey(publisher),
+            version,
+            value: value.into(),
+            signature: UNSIGNED,
+        }
+    }
+
+    #[test]
+    fn publishers_dont_overwrite_each_other() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        assert_eq!(
+            Ok(()),
+            store.insert(key.clone(), published(1, "a"), now, 1000)
+        );
+        assert_eq!(
+            Ok(()),
+            store.insert(key.clone(), published(2, "b"), now, 1000)
+        );
+        // Storing the same value again doesn't take up more room
+        assert_eq!(
+            Ok(()),
+            store.insert(key.clone(), published(2, "b"), now, 1000)
+        );
+        assert_eq!(2 * SIGNED + 4, store.stored_bytes());
+        assert_eq!(
+            Err(InsertError::KeyFull),
+            store.insert(key.clone(), published(3, "c"), now, 1000)
+        );
+        assert_eq!(
+            Err(InsertError::StorageFull),
+            store.insert(String::from("B"), published(3, "c"), now, 5)
+        );
+        assert_eq!(
+            vec![published(1, "a"), published(2, "b")],
+            store.get(&key, now)
+        );
+    }
+
+    #[test]
+    fn publishers_hold_one_value_each() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        store
+            .insert(key.clone(), versioned(1, 1, "a"), now, 1000)
+            .unwrap();
+        // A second value from the same publisher replaces the first, rather than taking a slot
+        store
+            .insert(key.clone(), versioned(1, 2, "bb"), now, 1000)
+            .unwrap();
+        store
+            .insert(key.clone(), versioned(2, 1, "c"), now, 1000)
+            .unwrap();
+        assert_eq!(
+            vec![versioned(1, 2, "bb"), versioned(2, 1, "c")],
+            store.get(&key, now)
+        );
+        assert_eq!(2 * SIGNED + 5, store.stored_bytes());
+        // Older values arriving late don't replace newer ones
+        store
+            .insert(key.clone(), versioned(1, 1, "a"), now, 1000)
+            .unwrap();
+        assert_eq!(versioned(1, 2, "bb"), store.get(&key, now)[0]);
+        // Rejected values don't leave an empty key behind
+        assert_eq!(
+            Err(InsertError::StorageFull),
+            store.insert(String::from("B"), versioned(1, 1, "a"), now, 5)
+        );
+        assert!(!store.values.contains_key("B"));
+    }
+
+    #[test]
+    fn values_expire() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        store
+            .insert(key.clone(), published(1, "a"), now, 1000)
+            .unwrap();
+        let later = now + VALUE_TTL / 2;
+        store
+            .insert(key.clone(), published(2, "b"), later, 1000)
+            .unwrap();
+        let expired = now + VALUE_TTL;
+        assert_eq!(vec![published(2, "b")], store.get(&key, expired));
+        // Expired values make room for new ones
+        assert_eq!(
+            Ok(()),
+            store.insert(key.clone(), published(3, "c"), expired, 1000)
+        );
+        assert_eq!(2 * SIGNED + 4, store.stored_bytes());
+        store.prune(expired + VALUE_TTL);
+        assert_eq!(0, store.stored_bytes());
+        assert!(store.get(&key, now).is_empty());
+    }
+
+    #[test]
+    fn compare_and_swap_checks_version() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        let first = versioned(1, 1, "a");
+        assert_eq!(
+            Ok(()),
+            store.compare_and_swap(key.clone(), 0, first, now, 1000)
+        );
+        store
+            .insert(key.clone(), versioned(2, 3, "b"), now, 1000)
+            .unwrap();
+        assert_eq!(3, store.version(&key, now));
+        assert_eq!(
+            Err(InsertError::VersionMismatch(3)),
+            store.compare_and_swap(key.clone(), 1, versioned(1, 2, "c"), now, 1000)
+        );
+        let swapped = versioned(1, 4, "c");
+        assert_eq!(
+            Ok(()),
+            store.compare_and_swap(key.clone(), 3, swapped.clone(), now, 1000)
+        );
+        // Only the value of the publisher is replaced, and the new one takes precedence
+        assert_eq!(vec![swapped, versioned(2, 3, "b")], store.get(&key, now));
+        assert_eq!(2 * SIGNED + 4, store.stored_bytes());
+        assert_eq!(4, store.version(&key, now));
+    }
+
+    #[test]
+    fn compare_and_swap_keeps_tombstones() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        let (one, first) = signed_delete(1, &key, 5);
+        let (two, second) = signed_delete(2, &key, 5);
+        store.delete(key.clone(), 5, first, now, 1000).unwrap();
+        store.delete(key.clone(), 5, second, now, 1000).unwrap();
+        assert_eq!(
+            Ok(()),
+            store.compare_and_swap(key.clone(), 0, versioned(one, 6, "a"), now, 1000)
+        );
+        // Other publishers' tombstones still hold
+        assert_eq!(
+            Err(InsertError::Deleted(5)),
+            store.insert(key.clone(), versioned(two, 5, "b"), now, 1000)
+        );
+    }
+
+    #[test]
+    fn publishers_push_out_copies() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        store
+            .insert_copy(key.clone(), versioned(1, 1, "a"), now, 1000)
+            .unwrap();
+        store
+            .insert_copy(key.clone(), versioned(2, 2, "b"), now, 1000)
+            .unwrap();
+        // A copy can't push out another copy
+        assert_eq!(
+            Err(InsertError::KeyFull),
+            store.insert_copy(key.clone(), versioned(3, 3, "c"), now, 1000)
+        );
+        // Publishers push out the copy that would lose a conflict first
+        store
+            .insert(key.clone(), versioned(3, 3, "c"), now, 1000)
+            .unwrap();
+        assert_eq!(
+            vec![versioned(3, 3, "c"), versioned(2, 2, "b")],
+            store.get(&key, now)
+        );
+        assert_eq!(2 * SIGNED + 4, store.stored_bytes());
+        // A copy vouched for by its publisher stays
+        store
+            .insert(key.clone(), versioned(2, 2, "b"), now, 1000)
+            .unwrap();
+        assert_eq!(
+            Err(InsertError::KeyFull),
+            store.insert(key.clone(), versioned(4, 4, "d"), now, 1000)
+        );
+    }
+
+    #[test]
+    fn copies_need_a_signature_to_replace_stored_values() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        let publisher = Publisher::from_seed([1; 32]);
+        let mine = publisher.publish(&key, 1, "mine".into()).unwrap();
+        store.insert(key.clone(), mine.clone(), now, 1000).unwrap();
+        // A copy claiming a newer version never replaces what the publisher stored
+        let forged = versioned(publisher.id().0, 2, "forged");
+        assert_eq!(
+            Err(InsertError::Unsigned),
+            store.insert_copy(key.clone(), forged, now, 1000)
+        );
+        assert_eq!(vec![mine], store.get(&key, now));
+        // Unless the publisher signed it
+        let newer = publisher.publish(&key, 2, "newer".into()).unwrap();
+        store
+            .insert_copy(key.clone(), newer.clone(), now, 1000)
+            .unwrap();
+        assert_eq!(vec![newer], store.get(&key, now));
+    }
+
+    #[test]
+    fn deletes_need_room_for_their_tombstone() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(1, now);
+        let key = String::from("A");
+        let (one, delete) = signed_delete(1, &key, 1);
+        store
+            .insert(key.clone(), versioned(one, 1, "a"), now, 1000)
+            .unwrap();
+        assert_eq!(
+            Err(InsertError::StorageFull),
+            store.delete(key.clone(), 1, delete, now, 2)
+        );
+        assert_eq!(vec![versioned(one, 1, "a")], store.get(&key, now));
+        let (_, other) = signed_delete(2, &key, 1);
+        store.delete(key.clone(), 1, other, now, 1000).unwrap();
+        assert_eq!(
+            Err(InsertError::KeyFull),
+            store.delete(key.clone(), 1, delete, now, 1000)
+        );
+        assert_eq!(vec![versioned(one, 1, "a")], store.get(&key, now));
+        // A key without tombstones isn't left behind by a refused delete
+        let (_, delete) = signed_delete(1, "B", 1);
+        assert_eq!(
+            Err(InsertError::StorageFull),
+            store.delete("B".into(), 1, delete, now, 0)
+        );
+        assert!(!store.tombstones.contains_key("B"));
+    }
+
+    #[test]
+    fn range_hashes_ignore_order() {
+        let now = Instant::now();
+        let mut first = ValueStore::new(2, now);
+        let mut second = ValueStore::new(2, now);
+        let keys = ["A", "B", "C"];
+        for key in &keys {
+            first
+                .insert(key.to_string(), versioned(1, 1, key), now, 1000)
+                .unwrap();
+        }
+        for key in keys.iter().rev() {
+            second
+                .insert(key.to_string(), versioned(1, 1, key), now, 1000)
+                .unwrap();
+        }
+        let everything = [(BitKey(0), BitKey(u128::MAX))];
+        assert_eq!(
+            first.range_hashes(&everything, now),
+            second.range_hashes(&everything, now)
+        );
+        let (entries, more) = first.entries_in(BitKey(0), BitKey(u128::MAX), 3, now);
+        assert_eq!((3, false), (entries.len(), more));
+        second
+            .insert("A".into(), versioned(1, 2, "A"), now, 1000)
+            .unwrap();
+        assert_ne!(
+            first.range_hashes(&everything, now),
+            second.range_hashes(&everything, now)
+        );
+        // Only the range holding the changed key differs
+        let a = BitKey::from_hash("A");
+        let ranges = [(a, a), (BitKey(a.0 ^ 1 << 127), BitKey(a.0 ^ 1 << 127))];
+        let (ours, theirs) = (
+            first.range_hashes(&ranges, now),
+            second.range_hashes(&ranges, now),
+        );
+        assert_ne!(ours[0], theirs[0]);
+        assert_eq!(ours[1], theirs[1]);
+        let entry = SyncEntry::Value(versioned(1, 1, "A"));
+        assert_eq!(
+            (vec![(String::from("A"), entry)], false),
+            first.entries_in(a, a, 10, now)
+        );
+    }
+
+    #[test]
+    fn refused_writes_dont_count_as_changes() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(1, now);
+        let key = String::from("A");
+        let (one, delete) = signed_delete(1, &key, 1);
+        store
+            .insert(key.clone(), versioned(one, 2, "a"), now, 1000)
+            .unwrap();
+        let changes = store.changes();
+        // Refused, stale, and repeated writes leave the store as it was
+        let _ = store.insert(key.clone(), versioned(2, 1, "b"), now, 1000);
+        let _ = store.insert(String::from("B"), versioned(2, 1, "b"), now, 0);
+        let _ = store.insert(key.clone(), versioned(one, 1, "a"), now, 1000);
+        let _ = store.insert(key.clone(), versioned(one, 2, "a"), now, 1000);
+        let _ = store.delete(key.clone(), 1, delete, now, 0);
+        store.prune(now + PRUNE_PERIOD);
+        assert_eq!(changes, store.changes());
+        store.delete(key.clone(), 1, delete, now, 1000).unwrap();
+        assert_eq!(changes + 1, store.changes());
+        store.prune(now + TOMBSTONE_TTL + PRUNE_PERIOD);
+        assert_eq!(changes + 2, store.changes());
+    }
+
+    #[test]
+    fn tombstones_are_synced() {
+        let now = Instant::now();
+        let mut first = ValueStore::new(2, now);
+        let mut second = ValueStore::new(2, now);
+        let key = String::from("A");
+        let (one, delete) = signed_delete(1, &key, 1);
+        for store in [&mut first, &mut second] {
+            store
+                .insert(key.clone(), versioned(2, 1, "b"), now, 1000)
+                .unwrap();
+        }
+        first
+            .insert(key.clone(), versioned(one, 1, "a"), now, 1000)
+            .unwrap();
+        first.delete(key.clone(), 1, delete, now, 1000).unwrap();
+        // Both hold the same values, but only one holds the tombstone
+        assert_eq!(first.get(&key, now), second.get(&key, now));
+        let everything = [(BitKey(0), BitKey(u128::MAX))];
+        assert_ne!(
+            first.range_hashes(&everything, now),
+            second.range_hashes(&everything, now)
+        );
+        let (entries, more) = first.entries_in(BitKey(0), BitKey(u128::MAX), 10, now);
+        let expected = vec![
+            (key.clone(), SyncEntry::Value(versioned(2, 1, "b"))),
+            (key.clone(), SyncEntry::Tombstone(1, delete)),
+        ];
+        assert_eq!((expected, false), (entries, more));
+        // Entries past the limit are left for the next pull
+        let (entries, more) = first.entries_in(BitKey(0), BitKey(u128::MAX), 1, now);
+        assert_eq!((1, true), (entries.len(), more));
+        second.delete(key.clone(), 1, delete, now, 1000).unwrap();
+        assert_eq!(
+            first.range_hashes(&everything, now),
+            second.range_hashes(&everything, now)
+        );
+    }
+
+    #[test]
+    fn deleted_values_stay_deleted() {
+        let now = Instant::now();
+        let mut store = ValueStore::new(2, now);
+        let key = String::from("A");
+        let (one, delete) = signed_delete(1, &key, 2);
+        store
+            .insert(key.clone(), versioned(one, 1, "a"), now, 1000)
+            .unwrap();
+        store
+            .insert(key.clone(), versioned(2, 1, "b"), now, 1000)
+            .unwrap();
+        assert_eq!(Ok(()), store.delete(key.clone(), 2, delete, now, 1000));
+        assert_eq!(vec![versioned(2, 1, "b")], store.get(&key, now));
+        assert_eq!(SIGNED + 2 + tombstone_size("A"), store.stored_bytes());
+        // Republishing an old version doesn't bring the value back
+        assert_eq!(
+            Err(InsertError::Deleted(2)),
+            store.insert(key.clone(), versioned(one, 1, "a"), now, 1000)
+        );
+        assert_eq!(
+            Ok(()),
+            store.insert(key.clone(), versioned(one, 3, "c"), now, 1000)
+        );
+        // Once the tombstone expires, old versions can be stored again
+        let expired = now + TOMBSTONE_TTL;
+        assert_eq!(
+            Ok(()),
+            store.insert(key.clone(), versioned(one, 1, "a"), expired, 1000)
+        );
+        store.prune(expired + VALUE_TTL);
+        assert_eq!(0, store.stored_bytes());
+    }
+}
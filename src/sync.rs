@@ -0,0 +1,69 @@
+use crate::base::BitKey;
+use std::time::Duration;
+
+/// How often we compare the values we hold with one of our neighbours.
+pub const SYNC_PERIOD: Duration = Duration::from_secs(10 * 60);
+/// How many slices a range of keys is split into, when summarizing it.
+pub const SYNC_BUCKETS: usize = 16;
+
+/// The range of keys a node shares responsibility for with its neighbours.
+///
+/// This is the range of keys sharing as long a prefix with our id as the
+/// furthest of our K closest neighbours does. Any key in this range is at
+/// least as close to us as that neighbour, so we're likely to be one of
+/// the K closest nodes to it, as are our neighbours. Both bounds are inclusive.
+pub fn responsibility_range(this_id: BitKey, furthest: BitKey) -> (BitKey, BitKey) {
+    let prefix_len = this_id.distance(furthest).leading_zeros();
+    let mask = u128::MAX.checked_shr(prefix_len).unwrap_or(0);
+    (BitKey(this_id.0 & !mask), BitKey(this_id.0 | mask))
+}
+
+/// Split an inclusive range of keys into at most `SYNC_BUCKETS` equal slices.
+///
+/// Two nodes splitting the same range get the same slices, which lets them
+/// compare their summaries of each slice.
+pub fn split_range(lo: BitKey, hi: BitKey) -> Vec<(BitKey, BitKey)> {
+    let span = hi.0.saturating_sub(lo.0);
+    let step = span / SYNC_BUCKETS as u128 + 1;
+    let mut slices = Vec::with_capacity(SYNC_BUCKETS);
+    let mut start = Some(lo.0);
+    while let Some(s) = start.filter(|s| *s <= hi.0) {
+        let end = s.saturating_add(step - 1).min(hi.0);
+        slices.push((BitKey(s), BitKey(end)));
+        start = end.checked_add(1);
+    }
+    slices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responsibility_covers_shared_prefix() {
+        let this_id = BitKey(0b1011 << 124);
+        let furthest = BitKey(0b1001 << 124);
+        let (lo, hi) = responsibility_range(this_id, furthest);
+        assert_eq!(BitKey(0b1000 << 124), lo);
+        assert_eq!(BitKey((0b1100 << 124) - 1), hi);
+        // Being our own furthest neighbour leaves us with our own id
+        assert_eq!((this_id, this_id), responsibility_range(this_id, this_id));
+    }
+
+    #[test]
+    fn split_range_covers_everything() {
+        let slices = split_range(BitKey(0), BitKey(u128::MAX));
+        assert_eq!(SYNC_BUCKETS, slices.len());
+        assert_eq!(BitKey(u128::MAX), slices[SYNC_BUCKETS - 1].1);
+        for pair in slices.windows(2) {
+            assert_eq!(pair[0].1 .0 + 1, pair[1].0 .0);
+        }
+        let small = split_range(BitKey(10), BitKey(12));
+        let expected = vec![
+            (BitKey(10), BitKey(10)),
+            (BitKey(11), BitKey(11)),
+            (BitKey(12), BitKey(12)),
+        ];
+        assert_eq!(expected, small);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::messages::Token;
+use crate::rand::Rng;
+use crate::sha1::Sha1;
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How often we change the secret used to generate tokens.
+const ROTATION_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// Represents the secrets used to hand out write tokens.
+///
+/// Before a node can store a value with us, it needs to find us through a FindNode
+/// or FindValue call, and we give it a token in our response. The token is
+/// derived from the IP address of the requester, so a node can't use a token
+/// to store values on behalf of a different IP address.
+///
+/// We rotate the secret every few minutes, but keep accepting tokens generated
+/// with the previous secret, so a token stays valid for between 5 and 10 minutes.
+pub struct TokenSecrets {
+    current: u64,
+    previous: u64,
+    rotated_at: Instant,
+}
+
+impl TokenSecrets {
+    /// Create new secrets, generated at random.
+    pub fn new<R: Rng + ?Sized>(rng: &mut R, now: Instant) -> Self {
+        TokenSecrets {
+            current: rng.gen(),
+            previous: rng.gen(),
+            rotated_at: now,
+        }
+    }
+
+    /// Rotate the secret, if enough time has passed since the last rotation.
+    pub fn rotate<R: Rng + ?Sized>(&mut self, rng: &mut R, now: Instant) {
+        if now.duration_since(self.rotated_at) >= ROTATION_PERIOD {
+            self.previous = self.current;
+            self.current = rng.gen();
+            self.rotated_at = now;
+        }
+    }
+
+    /// Generate the token we should give to a given IP address.
+    pub fn token_for(&self, ip: IpAddr) -> Token {
+        make_token(self.current, ip)
+    }
+
+    /// Check whether or not a token was generated by us for a given IP address.
+    pub fn verify(&self, ip: IpAddr, token: Token) -> bool {
+        token == make_token(self.current, ip) || token == make_token(self.previous, ip)
+    }
+}
+
+fn make_token(secret: u64, ip: IpAddr) -> Token {
+    let mut hasher = Sha1::new();
+    hasher.update(&secret.to_be_bytes());
+    match ip {
+        IpAddr::V4(v4) => hasher.update(&v4.octets()),
+        IpAddr::V6(v6) => hasher.update(&v6.octets()),
+    }
+    let bytes = hasher.digest().bytes()[..8].try_into().unwrap();
+    Token(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::thread_rng;
+
+    #[test]
+    fn tokens_depend_on_ip() {
+        let secrets = TokenSecrets::new(&mut thread_rng(), Instant::now());
+        let a = "10.0.0.1".parse().unwrap();
+        let b = "10.0.0.2".parse().unwrap();
+        let token = secrets.token_for(a);
+        assert!(secrets.verify(a, token));
+        assert!(!secrets.verify(b, token));
+    }
+
+    #[test]
+    fn tokens_survive_one_rotation() {
+        let mut rng = thread_rng();
+        let now = Instant::now();
+        let mut secrets = TokenSecrets::new(&mut rng, now);
+        let ip = "10.0.0.1".parse().unwrap();
+        let token = secrets.token_for(ip);
+        secrets.rotate(&mut rng, now + Duration::from_secs(1));
+        assert_eq!(token, secrets.token_for(ip));
+        secrets.rotate(&mut rng, now + ROTATION_PERIOD);
+        assert!(secrets.verify(ip, token));
+        secrets.rotate(&mut rng, now + ROTATION_PERIOD * 2);
+        assert!(!secrets.verify(ip, token));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::rand::random;
+use argon2::Argon2;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many bytes are in a key used to encrypt traffic between nodes.
+pub const KEY_BYTES: usize = 32;
+const SENDER_BYTES: usize = 4;
+const NONCE_BYTES: usize = SENDER_BYTES + 8;
+const TAG_BYTES: usize = 16;
+// Keeps keys derived from a passphrase apart from anything else derived from it
+const KEY_SALT: &[u8] = b"kadht transport key";
+
+/// How far the clock of a sender can be from ours, before its datagrams are dropped.
+///
+/// Datagrams older than this are dropped too, so this bounds how long we need to
+/// remember the datagrams we've seen to catch replays.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+/// How long a datagram can arrive after a newer one from the same sender, and still be accepted.
+pub const MAX_REORDER: Duration = Duration::from_secs(1);
+// How often we forget about the senders whose datagrams are all too old to be replayed
+const PRUNE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The current time, in microseconds since the Unix epoch.
+pub fn now_micros() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_micros() as u64
+}
+
+/// How many bytes sealing a message adds on top of the message itself.
+pub const OVERHEAD: usize = NONCE_BYTES + TAG_BYTES;
+
+/// Represents an encrypted and authenticated channel between nodes.
+///
+/// Every node on a private network shares the same key, and each datagram
+/// we send gets sealed with that key, using ChaCha20-Poly1305. This wraps
+/// around the existing message format: we first write out a message as usual,
+/// and then seal the resulting bytes before sending them.
+///
+/// A sealed datagram is laid out as the nonce used to encrypt it, followed
+/// by the ciphertext, followed by the authentication tag. The nonce is made of
+/// a random identifier for the sender, picked when the channel is created, and
+/// a counter, starting from the current time in microseconds. This lets a
+/// `ReplayGuard` drop datagrams that were captured and sent again.
+pub struct SecureChannel {
+    cipher: ChaCha20Poly1305,
+    sender: [u8; SENDER_BYTES],
+    counter: u64,
+}
+
+/// Represents where a datagram came from, and where it falls among the datagrams of its sender.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stamp {
+    sender: [u8; SENDER_BYTES],
+    counter: u64,
+}
+
+impl SecureChannel {
+    /// Create a new channel from a raw key.
+    pub fn new(key: [u8; KEY_BYTES]) -> Self {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        SecureChannel {
+            cipher,
+            sender: random(),
+            counter: 0,
+        }
+    }
+
+    /// Create a new channel from a passphrase shared by every node on the network.
+    ///
+    /// The key is derived from the passphrase with Argon2id, which makes guessing
+    /// the passphrase expensive, but the passphrase should still be chosen with care.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut key = [0; KEY_BYTES];
+        // This only fails for salts or outputs of the wrong size, and ours are fixed
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), KEY_SALT, &mut key)
+            .unwrap();
+        Self::new(key)
+    }
+
+    /// Seal some plaintext into a buffer, returning the number of bytes written.
+    ///
+    /// The buffer needs to have room for `OVERHEAD` more bytes than the plaintext.
+    pub fn seal(&mut self, plaintext: &[u8], buf: &mut [u8]) -> usize {
+        // The counter never goes back, even if our clock does
+        self.counter = (self.counter + 1).max(now_micros());
+        let mut nonce = [0; NONCE_BYTES];
+        nonce[..SENDER_BYTES].copy_from_slice(&self.sender);
+        nonce[SENDER_BYTES..].copy_from_slice(&self.counter.to_be_bytes());
+        let end = NONCE_BYTES + plaintext.len();
+        buf[..NONCE_BYTES].copy_from_slice(&nonce);
+        buf[NONCE_BYTES..end].copy_from_slice(plaintext);
+        // Encryption can only fail for messages far larger than any datagram
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[], &mut buf[NONCE_BYTES..end])
+            .unwrap();
+        buf[end..end + TAG_BYTES].copy_from_slice(&tag);
+        end + TAG_BYTES
+    }
+
+    /// Open a sealed datagram into a buffer, returning the plaintext, and the stamp of the datagram.
+    ///
+    /// This returns `None` if the datagram is too short, or if it wasn't sealed
+    /// with the same key as this channel, or if it was tampered with. The stamp
+    /// should be checked against a `ReplayGuard` before using the plaintext.
+    pub fn open<'a>(&self, data: &[u8], buf: &'a mut [u8]) -> Option<(Stamp, &'a [u8])> {
+        if data.len() < OVERHEAD || buf.len() < data.len() - OVERHEAD {
+            return None;
+        }
+        let (nonce, rest) = data.split_at(NONCE_BYTES);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_BYTES);
+        let plaintext = &mut buf[..ciphertext.len()];
+        plaintext.copy_from_slice(ciphertext);
+        self.cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &[],
+                plaintext,
+                Tag::from_slice(tag),
+            )
+            .ok()?;
+        let stamp = Stamp {
+            sender: nonce[..SENDER_BYTES].try_into().unwrap(),
+            counter: u64::from_be_bytes(nonce[SENDER_BYTES..].try_into().unwrap()),
+        };
+        Some((stamp, plaintext))
+    }
+}
+
+// The datagrams we've seen from a single sender, in a sliding window below its newest one
+struct Window {
+    newest: u64,
+    // The counters we've seen within `MAX_REORDER` of the newest one
+    seen: HashSet<u64>,
+}
+
+/// Keeps track of the datagrams we've opened, in order to drop replayed ones.
+///
+/// Each sender numbers its datagrams with an increasing counter, close to the
+/// current time. A datagram is accepted once, as long as it's recent enough,
+/// and isn't too far behind the newest datagram of its sender.
+///
+/// A sender uses the same counter for all of its peers, so this only stops
+/// a datagram from being accepted twice by the same node.
+pub struct ReplayGuard {
+    windows: HashMap<[u8; SENDER_BYTES], Window>,
+    pruned_at: u64,
+}
+
+impl ReplayGuard {
+    /// Create a guard which has yet to see any datagrams.
+    pub fn new() -> Self {
+        ReplayGuard {
+            windows: HashMap::new(),
+            pruned_at: 0,
+        }
+    }
+
+    /// Check whether or not a datagram is fresh, remembering it if so.
+    ///
+    /// `now` is the current time, in microseconds since the Unix epoch.
+    pub fn accept(&mut self, stamp: Stamp, now: u64) -> bool {
+        let skew = MAX_CLOCK_SKEW.as_micros() as u64;
+        if stamp.counter.saturating_add(skew) < now || stamp.counter > now.saturating_add(skew) {
+            return false;
+        }
+        let window = self.windows.entry(stamp.sender).or_insert_with(|| Window {
+            newest: stamp.counter,
+            seen: HashSet::new(),
+        });
+        let reorder = MAX_REORDER.as_micros() as u64;
+        if stamp.counter > window.newest {
+            window.newest = stamp.counter;
+            let oldest = stamp.counter.saturating_sub(reorder);
+            window.seen.retain(|&counter| counter >= oldest);
+        } else if window.newest - stamp.counter > reorder {
+            return false;
+        }
+        window.seen.insert(stamp.counter)
+    }
+
+    /// Forget about senders we haven't heard from in a while.
+    ///
+    /// Their datagrams would be too old to accept anyway. This only does
+    /// work once in a while, so it's cheap to call often.
+    pub fn prune(&mut self, now: u64) {
+        if now.saturating_sub(self.pruned_at) < PRUNE_PERIOD.as_micros() as u64 {
+            return;
+        }
+        self.pruned_at = now;
+        let skew = MAX_CLOCK_SKEW.as_micros() as u64;
+        self.windows
+            .retain(|_, window| window.newest.saturating_add(skew) >= now);
+    }
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_round_trip() {
+        let mut channel = SecureChannel::from_passphrase("hunter2");
+        let mut sealed = [0; 0x100];
+        let mut opened = [0; 0x100];
+        let count = channel.seal(b"Hello World", &mut sealed);
+        assert_eq!(11 + OVERHEAD, count);
+        let (_, plaintext) = channel.open(&sealed[..count], &mut opened).unwrap();
+        assert_eq!(&b"Hello World"[..], plaintext);
+    }
+
+    #[test]
+    fn channel_rejects_other_keys() {
+        let mut channel = SecureChannel::new([1; KEY_BYTES]);
+        let other = SecureChannel::new([2; KEY_BYTES]);
+        let mut sealed = [0; 0x100];
+        let mut opened = [0; 0x100];
+        let count = channel.seal(b"Hello World", &mut sealed);
+        assert_eq!(None, other.open(&sealed[..count], &mut opened));
+    }
+
+    #[test]
+    fn channel_rejects_tampering() {
+        let mut channel = SecureChannel::new([1; KEY_BYTES]);
+        let mut sealed = [0; 0x100];
+        let mut opened = [0; 0x100];
+        let count = channel.seal(b"Hello World", &mut sealed);
+        sealed[NONCE_BYTES] ^= 1;
+        assert_eq!(None, channel.open(&sealed[..count], &mut opened));
+        assert_eq!(None, channel.open(&sealed[..OVERHEAD - 1], &mut opened));
+    }
+
+    #[test]
+    fn replays_are_dropped() {
+        let mut channel = SecureChannel::new([1; KEY_BYTES]);
+        let mut guard = ReplayGuard::new();
+        let mut opened = [0; 0x100];
+        let mut stamps = Vec::new();
+        for _ in 0..3 {
+            let mut sealed = [0; 0x100];
+            let count = channel.seal(b"Hello World", &mut sealed);
+            stamps.push(channel.open(&sealed[..count], &mut opened).unwrap().0);
+        }
+        let now = now_micros();
+        // Datagrams can arrive out of order, but only once
+        assert!(guard.accept(stamps[0], now));
+        assert!(guard.accept(stamps[2], now));
+        assert!(guard.accept(stamps[1], now));
+        for &stamp in &stamps {
+            assert!(!guard.accept(stamp, now));
+        }
+        // Forgetting a sender doesn't let its old datagrams back in
+        let later = now + 2 * MAX_CLOCK_SKEW.as_micros() as u64;
+        guard.prune(later);
+        assert!(guard.windows.is_empty());
+        assert!(!guard.accept(stamps[0], later));
+        // Datagrams too far behind the newest one from their sender are dropped too
+        let old = Stamp {
+            counter: stamps[2].counter - MAX_REORDER.as_micros() as u64 - 1,
+            ..stamps[2]
+        };
+        let mut guard = ReplayGuard::new();
+        assert!(guard.accept(stamps[2], now));
+        assert!(!guard.accept(old, now));
+    }
+}